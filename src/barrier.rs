@@ -0,0 +1,24 @@
+// Memory barriers (see cpu.S)
+
+extern "C" {
+    fn cpu_dmb();
+    fn cpu_dsb();
+    fn cpu_isb();
+}
+
+// Data memory barrier: memory accesses before it are observed before those after it
+pub fn dmb() {
+    unsafe { cpu_dmb(); }
+}
+
+// Data synchronisation barrier: waits for all earlier memory accesses and cache/TLB maintenance
+// to complete
+pub fn dsb() {
+    unsafe { cpu_dsb(); }
+}
+
+// Instruction synchronisation barrier: flushes the pipeline, so later instructions see the effect
+// of earlier context-changing operations (e.g. CP15 writes)
+pub fn isb() {
+    unsafe { cpu_isb(); }
+}
@@ -0,0 +1,46 @@
+// Safe wrappers around the cache maintenance routines in cache.S. Ranges are virtual addresses.
+
+extern "C" {
+    fn cache_clean_range(start: usize, end: usize);
+    fn cache_invalidate_range(start: usize, end: usize);
+    fn cache_clean_invalidate_range(start: usize, end: usize);
+    fn cache_clean_invalidate_all();
+    fn cache_invalidate_icache();
+}
+
+// Data cache line size of the Cortex-A7. Buffers that are invalidated should be aligned to this
+// (and a multiple of it in size), so that no unrelated data shares their lines.
+pub const CACHE_LINE: usize = 64;
+
+// Makes CPU writes to [start, start + len) visible to other bus masters (GPU, DMA)
+pub fn clean_range(start: usize, len: usize) {
+    if len > 0 {
+        unsafe { cache_clean_range(start, start + len); }
+    }
+}
+
+// Discards cached copies of [start, start + len), so the CPU sees what other bus masters wrote.
+// Any dirty data in lines only partly covered by the range is lost too.
+pub fn invalidate_range(start: usize, len: usize) {
+    debug_assert!(start % CACHE_LINE == 0 && len % CACHE_LINE == 0,
+                  "invalidating a range that is not cache line aligned: {:#010x} + {:#x}", start, len);
+    if len > 0 {
+        unsafe { cache_invalidate_range(start, start + len); }
+    }
+}
+
+pub fn clean_invalidate_range(start: usize, len: usize) {
+    if len > 0 {
+        unsafe { cache_clean_invalidate_range(start, start + len); }
+    }
+}
+
+// Writes back and discards the whole data cache
+pub fn flush_all() {
+    unsafe { cache_clean_invalidate_all(); }
+}
+
+// Needed after writing instructions to memory, once the data cache has been cleaned
+pub fn invalidate_icache() {
+    unsafe { cache_invalidate_icache(); }
+}
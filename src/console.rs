@@ -0,0 +1,117 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::framebuffer::{FrameBuffer24, TextStyle};
+use crate::sync::{IrqSpinLock, Once};
+use crate::uart::Uart;
+
+/*
+ * Kernel console: kprint!() and kprintln!() format straight into each enabled sink without
+ * allocating. The framebuffer sink only exists once a framebuffer has been attached.
+ *
+ * Any core, and interrupt handlers, may print: OUTPUT is held for the whole of each kprint!() so
+ * that lines from different cores do not interleave, and SCREEN guards the framebuffer itself.
+ * The lock order is OUTPUT, then SCREEN, so nothing may print while it holds the screen.
+ */
+
+// Output sinks (bitmask)
+pub const SINK_SERIAL: u32 = 1 << 0;
+pub const SINK_SCREEN: u32 = 1 << 1;
+pub const SINK_ALL:    u32 = SINK_SERIAL | SINK_SCREEN;
+
+static SINKS:  AtomicUsize = AtomicUsize::new(SINK_SERIAL as usize);
+static CURSOR: AtomicBool = AtomicBool::new(false);
+static OUTPUT: IrqSpinLock<()> = IrqSpinLock::new(());
+static SCREEN: Once<IrqSpinLock<FrameBuffer24>> = Once::new();
+
+#[macro_export]
+macro_rules! kprint {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! kprintln {
+    ()                          => (kprint!("\n"));
+    ($fmt:expr)                 => (kprint!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*)    => (kprint!(concat!($fmt, "\n"), $($arg)*));
+}
+
+// Selects which sinks kprint!() writes to
+pub fn set_sinks(sinks: u32) {
+    SINKS.store(sinks as usize, Ordering::Release);
+}
+
+pub fn sinks() -> u32 {
+    SINKS.load(Ordering::Acquire) as u32
+}
+
+// Hands a framebuffer over to the console; it becomes the SINK_SCREEN output. Only the first
+// framebuffer attached is kept.
+pub fn attach_screen(fb: FrameBuffer24) {
+    SCREEN.call_once(move || IrqSpinLock::new(fb));
+}
+
+// Runs "f" on the console framebuffer, if one has been attached, and returns its result. "f" must
+// not print to the console.
+pub fn with_screen<R, F: FnOnce(&mut FrameBuffer24) -> R>(f: F) -> Option<R> {
+    SCREEN.get().map(|screen| f(&mut screen.lock()))
+}
+
+// Sets the style used for subsequent text written to the screen sink
+pub fn set_screen_style(style: TextStyle) {
+    with_screen(|fb| fb.set_style(style));
+}
+
+// Releases the console locks, whoever holds them, so that a panic can be reported even if it
+// interrupted a core in the middle of printing. Output from other cores may be garbled after this.
+pub unsafe fn break_locks() {
+    OUTPUT.force_unlock();
+    if let Some(screen) = SCREEN.get() {
+        screen.force_unlock();
+    }
+}
+
+// Writer for the console sinks, for code that takes a core::fmt::Write
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        _print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+// Shows or hides the text cursor on the screen sink. While shown, the cursor is hidden around
+// every write so that it always sits after the last character written.
+pub fn show_cursor(show: bool) {
+    let _output = OUTPUT.lock();
+    with_screen(|fb| {
+        if CURSOR.load(Ordering::Relaxed) != show {
+            fb.toggle_cursor();
+        }
+    });
+    CURSOR.store(show, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _output = OUTPUT.lock();
+    let sinks = sinks();
+
+    if sinks & SINK_SERIAL > 0 {
+        let _ = Uart {}.write_fmt(args);
+    }
+
+    if sinks & SINK_SCREEN > 0 {
+        with_screen(|fb| {
+            let cursor = CURSOR.load(Ordering::Relaxed);
+            if cursor {
+                fb.toggle_cursor();
+            }
+            let _ = fb.write_fmt(args);
+            if cursor {
+                fb.toggle_cursor();
+            }
+        });
+    }
+}
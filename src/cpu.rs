@@ -0,0 +1,84 @@
+// Safe wrappers around the CPU helper routines in cpu.S
+
+extern "C" {
+    fn cpu_get_fp() -> usize;
+    fn cpu_core_id() -> u32;
+    fn cpu_disable_interrupts();
+    fn cpu_enable_interrupts();
+    fn cpu_irq_save() -> u32;
+    fn cpu_irq_restore(cpsr: u32);
+    fn cpu_get_cpsr() -> u32;
+    fn cpu_wait_for_event();
+    fn cpu_wait_for_interrupt();
+    fn cpu_send_event();
+    fn cpu_get_tpidrprw() -> usize;
+    fn cpu_set_tpidrprw(value: usize);
+}
+
+// Number of cores on the BCM2836
+pub const CORES: usize = 4;
+
+// CPSR I bit: IRQs masked
+const CPSR_IRQ_MASK: u32 = 1 << 7;
+
+// Returns the frame pointer (r11) of the function calling this one
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    unsafe { cpu_get_fp() }
+}
+
+pub fn core_id() -> usize {
+    unsafe { cpu_core_id() as usize }
+}
+
+pub fn disable_interrupts() {
+    unsafe { cpu_disable_interrupts(); }
+}
+
+pub fn enable_interrupts() {
+    unsafe { cpu_enable_interrupts(); }
+}
+
+pub fn interrupts_enabled() -> bool {
+    unsafe { cpu_get_cpsr() & CPSR_IRQ_MASK == 0 }
+}
+
+// Masks IRQs, returning a value for irq_restore() that records whether they were masked before.
+// Pairs may nest.
+pub fn irq_save() -> u32 {
+    unsafe { cpu_irq_save() }
+}
+
+pub fn irq_restore(saved: u32) {
+    unsafe { cpu_irq_restore(saved); }
+}
+
+pub fn wait_for_event() {
+    unsafe { cpu_wait_for_event(); }
+}
+
+pub fn send_event() {
+    unsafe { cpu_send_event(); }
+}
+
+// Address of the current core's per-CPU data (see smp.rs)
+pub fn per_cpu() -> usize {
+    unsafe { cpu_get_tpidrprw() }
+}
+
+pub fn set_per_cpu(addr: usize) {
+    unsafe { cpu_set_tpidrprw(addr); }
+}
+
+// Sleeps until an interrupt is pending, whether or not IRQs are masked
+pub fn wait_for_interrupt() {
+    unsafe { cpu_wait_for_interrupt(); }
+}
+
+// Stops the current core for good
+pub fn halt() -> ! {
+    disable_interrupts();
+    loop {
+        wait_for_event();
+    }
+}
@@ -0,0 +1,575 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::cpu;
+use crate::dmabuf::{Coherency, DmaBuffer};
+use crate::irq;
+use crate::mmio::{self, Mmio};
+use crate::shell;
+use crate::spi;
+use crate::sync::IrqSpinLock;
+use crate::timer::Timer;
+use crate::uart::Uart;
+use crate::vm::VmError;
+
+/*
+ * BCM2835 DMA controller. Channels 0 - 6 are full channels; 7 - 14 are "lite" channels, which have
+ * no 2D mode, take at most 64 KiB per control block and have half the bandwidth. The firmware
+ * keeps some channels for itself, so only those in CHANNEL_MASK (the mask it gives Linux) are used.
+ *
+ * A transfer is a chain of 32-byte aligned control blocks, each pointing to the next by bus
+ * address. Every allocated Channel owns a Chain in uncached memory, so the engine always sees what
+ * the CPU wrote there; data buffers are the caller's business (see dmabuf.rs) and are given to the
+ * engine as bus addresses (mmu::virt_to_bus(), DmaBuffer::bus_addr(), mmio::bus_addr()).
+ */
+
+pub const CHANNELS:     usize = 15;
+const CHANNEL_MASK:     u32   = 0x7F35;
+const FIRST_LITE:       usize = 7;
+const LITE_MAX_LENGTH:  usize = 0xFFFF;
+const FULL_MAX_LENGTH:  usize = 0x3FFFFFFF;
+pub const MAX_2D_ROWS:  usize = 0x4000;
+
+// Control blocks per chain (one page holds 128)
+pub const CHAIN_MAX: usize = 64;
+
+// Channel register offsets
+const CS:        usize = 0x00; // Control and status
+const CONBLK_AD: usize = 0x04; // Control block address
+const DEBUG:     usize = 0x20;
+
+// CS bits
+const CS_ACTIVE:      u32 = 1 << 0;
+const CS_END:         u32 = 1 << 1;  // Write 1 to clear
+const CS_INT:         u32 = 1 << 2;  // Write 1 to clear
+const CS_ERROR:       u32 = 1 << 8;
+const CS_WAIT_WRITES: u32 = 1 << 28; // Wait for outstanding writes before signalling the end
+const CS_ABORT:       u32 = 1 << 30;
+const CS_RESET:       u32 = 1 << 31;
+
+// DEBUG error bits (write 1 to clear)
+const DEBUG_ERRORS: u32 = 0x7;
+
+// Transfer information (TI) bits
+const TI_INTEN:      u32 = 1 << 0;  // Interrupt when this block is done
+const TI_TDMODE:     u32 = 1 << 1;  // 2D mode
+const TI_WAIT_RESP:  u32 = 1 << 3;  // Wait for each write to be acknowledged
+const TI_DEST_INC:   u32 = 1 << 4;
+const TI_DEST_WIDTH: u32 = 1 << 5;  // 128-bit writes
+const TI_DEST_DREQ:  u32 = 1 << 6;  // Pace writes by the peripheral's DREQ
+const TI_SRC_INC:    u32 = 1 << 8;
+const TI_SRC_WIDTH:  u32 = 1 << 9;  // 128-bit reads
+const TI_SRC_DREQ:   u32 = 1 << 10; // Pace reads by the peripheral's DREQ
+const TI_PERMAP_SHIFT: u32 = 16;
+const TI_BURST_SHIFT:  u32 = 12;
+
+// Burst length for memory-to-memory transfers
+const MEM_BURST: u32 = 4;
+
+// Peripherals that can pace a transfer (TI PERMAP)
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Dreq {
+    None   = 0,
+    PcmTx  = 2,
+    PcmRx  = 3,
+    Pwm    = 5,
+    SpiTx  = 6,
+    SpiRx  = 7,
+    Emmc   = 11,
+    UartTx = 12,
+    UartRx = 14,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DmaError {
+    NoChannel,    // All usable channels are allocated
+    Unsupported,  // A block needs a full channel (2D mode or a long transfer)
+    ChainFull,
+    OutOfMemory,
+    Failed(u32),  // The engine stopped with an error: the DEBUG register
+}
+
+impl From<VmError> for DmaError {
+    fn from(_: VmError) -> DmaError {
+        DmaError::OutOfMemory
+    }
+}
+
+// What alloc_channel() must find
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ChannelKind {
+    Any,  // Lite channels are preferred, leaving full ones to those that need them
+    Full,
+}
+
+// One control block, as read by the engine
+#[repr(C)]
+#[repr(align(32))]
+#[derive(Copy, Clone, Debug)]
+pub struct ControlBlock {
+    ti:        u32, // Transfer information
+    source:    u32, // Source bus address
+    dest:      u32, // Destination bus address
+    length:    u32, // Bytes, or rows (bits 16-29) and bytes per row (bits 0-15) in 2D mode
+    stride:    u32, // 2D mode: added to the destination (bits 16-31) and source after each row
+    next:      u32, // Bus address of the next block, 0 to stop
+    _reserved: [u32; 2],
+}
+
+impl ControlBlock {
+    fn new(ti: u32, source: u32, dest: u32, length: u32, stride: u32) -> ControlBlock {
+        ControlBlock { ti: ti, source: source, dest: dest, length: length, stride: stride, next: 0, _reserved: [0; 2] }
+    }
+
+    // Copies "len" bytes from "src" to "dst". Overlapping copies are only safe when dst < src.
+    pub fn copy(dst: u32, src: u32, len: usize) -> ControlBlock {
+        let ti = TI_SRC_INC | TI_SRC_WIDTH | TI_DEST_INC | TI_DEST_WIDTH | (MEM_BURST << TI_BURST_SHIFT);
+        ControlBlock::new(ti, src, dst, len as u32, 0)
+    }
+
+    // Fills "len" bytes (a multiple of 4) at "dst" with the word at "src"
+    pub fn fill(dst: u32, src: u32, len: usize) -> ControlBlock {
+        let ti = TI_DEST_INC | (MEM_BURST << TI_BURST_SHIFT);
+        ControlBlock::new(ti, src, dst, len as u32, 0)
+    }
+
+    // Copies "rows" rows of "row_len" bytes. After each row "src_stride" bytes are added to the
+    // source address and "dst_stride" to the destination: a stride of 0 gives contiguous rows,
+    // and a source stride of -row_len repeats the same row. Needs a full channel.
+    pub fn copy_2d(dst: u32, src: u32, row_len: u16, rows: usize, dst_stride: i16, src_stride: i16) -> ControlBlock {
+        debug_assert!(rows > 0 && rows <= MAX_2D_ROWS);
+        let ti = TI_TDMODE | TI_SRC_INC | TI_DEST_INC | (MEM_BURST << TI_BURST_SHIFT);
+        let length = ((rows.saturating_sub(1) as u32) << 16) | row_len as u32;
+        let stride = ((dst_stride as u16 as u32) << 16) | src_stride as u16 as u32;
+        ControlBlock::new(ti, src, dst, length, stride)
+    }
+
+    // Writes "len" bytes from "src" to the peripheral register "reg", as fast as "dreq" allows
+    pub fn to_peripheral(reg: u32, src: u32, len: usize, dreq: Dreq) -> ControlBlock {
+        let ti = TI_SRC_INC | TI_DEST_DREQ | TI_WAIT_RESP | ((dreq as u32) << TI_PERMAP_SHIFT);
+        ControlBlock::new(ti, src, reg, len as u32, 0)
+    }
+
+    // Reads "len" bytes from the peripheral register "reg" into "dst", as "dreq" allows
+    pub fn from_peripheral(dst: u32, reg: u32, len: usize, dreq: Dreq) -> ControlBlock {
+        let ti = TI_DEST_INC | TI_SRC_DREQ | ((dreq as u32) << TI_PERMAP_SHIFT);
+        ControlBlock::new(ti, reg, dst, len as u32, 0)
+    }
+
+    // Raises the completion interrupt when this block is done, not just at the end of the chain
+    pub fn with_interrupt(mut self) -> ControlBlock {
+        self.ti |= TI_INTEN;
+        self
+    }
+
+    fn needs_full_channel(&self) -> bool {
+        self.ti & TI_TDMODE != 0 || self.length as usize > LITE_MAX_LENGTH
+    }
+}
+
+// A list of control blocks run one after the other
+pub struct Chain {
+    blocks: DmaBuffer<[ControlBlock; CHAIN_MAX]>,
+    len:    usize,
+}
+
+impl Chain {
+    fn new() -> Result<Chain, DmaError> {
+        let empty  = ControlBlock::new(0, 0, 0, 0, 0);
+        let blocks = DmaBuffer::new([empty; CHAIN_MAX], Coherency::Uncached)?;
+        Ok(Chain { blocks: blocks, len: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // Appends a block
+    pub fn push(&mut self, block: ControlBlock) -> Result<(), DmaError> {
+        if self.len == CHAIN_MAX {
+            return Err(DmaError::ChainFull);
+        }
+        if block.length as usize > FULL_MAX_LENGTH {
+            return Err(DmaError::Unsupported);
+        }
+
+        let bus = self.block_bus_addr(self.len);
+        if self.len > 0 {
+            self.blocks[self.len - 1].next = bus;
+        }
+        self.blocks[self.len] = ControlBlock { next: 0, ..block };
+        self.len += 1;
+        Ok(())
+    }
+
+    fn block_bus_addr(&self, index: usize) -> u32 {
+        self.blocks.bus_addr() + (index * 32) as u32
+    }
+}
+
+// An allocated channel and its chain. Dropping it stops any transfer still running.
+pub struct Channel {
+    id:      usize,
+    chain:   Chain,
+    running: bool,
+}
+
+// Channel bookkeeping, shared with the completion interrupt
+struct State {
+    allocated: u32,
+    callbacks: [Option<fn(usize)>; CHANNELS],
+    completed: [u32; CHANNELS],
+}
+
+static STATE: IrqSpinLock<State> = IrqSpinLock::new(State {
+    allocated: 0,
+    callbacks: [None; CHANNELS],
+    completed: [0; CHANNELS],
+});
+
+fn reg(id: usize, offset: usize) -> usize {
+    mmio::DMA_BASE + id * mmio::DMA_CHANNEL_STRIDE + offset
+}
+
+// Interrupt line of a channel: channels 11 - 14 share one
+fn irq_number(id: usize) -> usize {
+    if id < 11 { 16 + id } else { 27 }
+}
+
+pub fn init() {
+    {
+        let _access = Mmio::enter();
+        let enabled = Mmio::read(mmio::DMA_ENABLE);
+        Mmio::write(mmio::DMA_ENABLE, enabled | CHANNEL_MASK);
+        for id in 0..CHANNELS {
+            if CHANNEL_MASK & (1 << id) != 0 {
+                Mmio::write(reg(id, CS), CS_RESET);
+            }
+        }
+    }
+
+    for id in 0..CHANNELS {
+        if CHANNEL_MASK & (1 << id) != 0 {
+            irq::register(irq_number(id), handle_irq);
+            irq::enable(irq_number(id));
+        }
+    }
+}
+
+// Allocates a free channel of the given kind
+pub fn alloc_channel(kind: ChannelKind) -> Result<Channel, DmaError> {
+    let found = {
+        let mut state = STATE.lock();
+        let free = CHANNEL_MASK & !state.allocated;
+        let lite = (FIRST_LITE..CHANNELS).find(|id| free & (1 << id) != 0);
+        let full = (0..FIRST_LITE).find(|id| free & (1 << id) != 0);
+        let found = match kind {
+            ChannelKind::Any  => lite.or(full),
+            ChannelKind::Full => full,
+        };
+        if let Some(id) = found {
+            state.allocated |= 1 << id;
+        }
+        found
+    };
+
+    let id = found.ok_or(DmaError::NoChannel)?;
+    match Chain::new() {
+        Ok(chain) => Ok(Channel { id: id, chain: chain, running: false }),
+        Err(e)    => {
+            release(id);
+            Err(e)
+        },
+    }
+}
+
+fn release(id: usize) {
+    let mut state = STATE.lock();
+    state.allocated &= !(1 << id);
+    state.callbacks[id] = None;
+}
+
+impl Channel {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn is_lite(&self) -> bool {
+        self.id >= FIRST_LITE
+    }
+
+    // The channel's chain, to be filled in before start(). Waits for a running transfer first, as
+    // the engine may still be reading the blocks; call wait() first to see how that one ended.
+    pub fn chain(&mut self) -> &mut Chain {
+        let _ = self.wait();
+        &mut self.chain
+    }
+
+    // Calls "callback" with the channel number, in interrupt context, whenever a block with an
+    // interrupt completes (always including the last block of the chain)
+    pub fn set_callback(&mut self, callback: Option<fn(usize)>) {
+        STATE.lock().callbacks[self.id] = callback;
+    }
+
+    // Starts running the chain
+    pub fn start(&mut self) -> Result<(), DmaError> {
+        let _ = self.wait();
+
+        let len = self.chain.len;
+        if len == 0 {
+            return Ok(());
+        }
+        if self.is_lite() && self.chain.blocks[..len].iter().any(|b| b.needs_full_channel()) {
+            return Err(DmaError::Unsupported);
+        }
+        self.chain.blocks[len - 1].ti |= TI_INTEN;
+        self.chain.blocks.sync_for_device();
+
+        let _access = Mmio::enter();
+        Mmio::write(reg(self.id, DEBUG),     DEBUG_ERRORS);
+        Mmio::write(reg(self.id, CONBLK_AD), self.chain.block_bus_addr(0));
+        Mmio::write(reg(self.id, CS),        CS_ACTIVE | CS_END | CS_INT | CS_WAIT_WRITES);
+        self.running = true;
+        Ok(())
+    }
+
+    pub fn is_busy(&self) -> bool {
+        let _access = Mmio::enter();
+        Mmio::read(reg(self.id, CS)) & CS_ACTIVE != 0
+    }
+
+    // Waits for the transfer to finish. Sleeps until the completion interrupt when IRQs are on,
+    // otherwise polls.
+    pub fn wait(&mut self) -> Result<(), DmaError> {
+        if !self.running {
+            return Ok(());
+        }
+
+        let sleep = cpu::interrupts_enabled();
+        let cs = loop {
+            // Check and sleep with IRQs masked, so the interrupt cannot slip in between
+            let saved = cpu::irq_save();
+            let cs = {
+                let _access = Mmio::enter();
+                Mmio::read(reg(self.id, CS))
+            };
+            if cs & CS_ACTIVE == 0 || cs & CS_ERROR != 0 {
+                cpu::irq_restore(saved);
+                break cs;
+            }
+            if sleep {
+                cpu::wait_for_interrupt();
+            }
+            cpu::irq_restore(saved);
+        };
+        self.running = false;
+
+        if cs & CS_ERROR != 0 {
+            let debug = {
+                let _access = Mmio::enter();
+                Mmio::read(reg(self.id, DEBUG))
+            };
+            self.abort();
+            return Err(DmaError::Failed(debug));
+        }
+
+        // The engine's writes are complete (CS_WAIT_WRITES); order later reads after them
+        self.chain.blocks.sync_for_cpu();
+        Ok(())
+    }
+
+    // Starts the chain and waits for it
+    pub fn run(&mut self) -> Result<(), DmaError> {
+        self.start()?;
+        self.wait()
+    }
+
+    // Stops the channel, abandoning the rest of the chain
+    pub fn abort(&mut self) {
+        let _access = Mmio::enter();
+        Mmio::write(reg(self.id, CS), CS_ABORT);
+        Mmio::write(reg(self.id, CS), CS_RESET);
+        Mmio::write(reg(self.id, DEBUG), DEBUG_ERRORS);
+        self.running = false;
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        if self.is_busy() {
+            self.abort();
+        }
+        release(self.id);
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dma::Channel({})", self.id)
+    }
+}
+
+// Completion interrupt, for every channel: acknowledges it and calls the channel's callback
+fn handle_irq(_irq: usize) {
+    let status = {
+        let _access = Mmio::enter();
+        Mmio::read(mmio::DMA_INT_STATUS) & CHANNEL_MASK // Not the firmware's channels
+    };
+
+    for id in 0..CHANNELS {
+        if status & (1 << id) == 0 {
+            continue;
+        }
+        {
+            let _access = Mmio::enter();
+            let cs = Mmio::read(reg(id, CS));
+            Mmio::write(reg(id, CS), (cs & CS_ACTIVE) | CS_INT | CS_WAIT_WRITES);
+        }
+        // The callback runs without the lock, so it may use the channel API
+        let callback = {
+            let mut state = STATE.lock();
+            state.completed[id] = state.completed[id].wrapping_add(1);
+            state.callbacks[id]
+        };
+        if let Some(callback) = callback {
+            callback(id);
+        }
+    }
+}
+
+// Copies "len" bytes between two buffers given by bus address, on any free channel
+pub fn copy(dst: u32, src: u32, len: usize) -> Result<(), DmaError> {
+    let kind = if len > LITE_MAX_LENGTH { ChannelKind::Full } else { ChannelKind::Any };
+    let mut channel = alloc_channel(kind)?;
+    channel.chain().push(ControlBlock::copy(dst, src, len))?;
+    channel.run()
+}
+
+pub fn register_commands() {
+    shell::register("dma", "[test [bytes] | uart <text> | spi <byte>...]", "Show DMA channels or test a transfer", cmd_dma);
+}
+
+fn cmd_dma(args: &[&str]) -> i32 {
+    match args.get(1) {
+        Some(&"test") => {
+            let len = match args.get(2) {
+                Some(s) => match shell::parse_number(s) {
+                    Some(n) if n > 0 && n <= TEST_MAX => n,
+                    _ => return shell::usage(args[0]),
+                },
+                None => TEST_MAX,
+            };
+            return test_copy(len);
+        },
+        Some(&"uart") if args.len() > 2 => {
+            let mut text = String::new();
+            for word in &args[2..] {
+                text.push_str(word);
+                text.push(' ');
+            }
+            text.pop();
+            text.push_str("\r\n");
+
+            if let Err(e) = Uart::write_dma(text.as_bytes()) {
+                shell::error(format_args!("transfer failed: {:?}", e));
+                return shell::STATUS_FAILED;
+            }
+            return shell::STATUS_OK;
+        },
+        Some(&"spi") if args.len() > 2 => {
+            let mut data = Vec::new();
+            for arg in &args[2..] {
+                match shell::parse_number(arg) {
+                    Some(n) if n <= 0xFF => data.push(n as u8),
+                    _ => return shell::usage(args[0]),
+                }
+            }
+
+            let mut received = Vec::new();
+            received.resize(data.len(), 0);
+            if let Err(e) = spi::transfer_dma(&data, &mut received) {
+                shell::error(format_args!("transfer failed: {:?}", e));
+                return shell::STATUS_FAILED;
+            }
+            for b in &received {
+                kprint!("{:02x} ", b);
+            }
+            kprintln!();
+            return shell::STATUS_OK;
+        },
+        Some(_) => return shell::usage(args[0]),
+        None    => {},
+    }
+
+    kprintln!("CH  KIND  STATE      CS          DEBUG       IRQS");
+    let (allocated, completed) = {
+        let state = STATE.lock();
+        (state.allocated, state.completed)
+    };
+    for id in 0..CHANNELS {
+        if CHANNEL_MASK & (1 << id) == 0 {
+            continue;
+        }
+        let (cs, debug) = {
+            let _access = Mmio::enter();
+            (Mmio::read(reg(id, CS)), Mmio::read(reg(id, DEBUG)))
+        };
+        let kind  = if id >= FIRST_LITE { "lite" } else { "full" };
+        let state = if allocated & (1 << id) == 0 {
+            "free"
+        } else if cs & CS_ACTIVE != 0 {
+            "active"
+        } else {
+            "allocated"
+        };
+        kprintln!("{:<3} {:<5} {:<10} {:#010x}  {:#010x}  {}", id, kind, state, cs, debug, completed[id]);
+    }
+    shell::STATUS_OK
+}
+
+const TEST_MAX: usize = 4096;
+
+// Copies a pattern between two cached buffers and checks the result
+fn test_copy(len: usize) -> i32 {
+    let (mut src, dst) = match (DmaBuffer::new([0u8; TEST_MAX], Coherency::Cached),
+                                    DmaBuffer::new([0u8; TEST_MAX], Coherency::Cached)) {
+        (Ok(src), Ok(dst)) => (src, dst),
+        _ => {
+            shell::error(format_args!("out of memory"));
+            return shell::STATUS_FAILED;
+        },
+    };
+
+    for (i, b) in src.iter_mut().enumerate() {
+        *b = (i * 7 + 3) as u8;
+    }
+    src.sync_for_device();
+    dst.sync_for_device();
+
+    let start = Timer::now_us();
+    let result = copy(dst.bus_addr(), src.bus_addr(), len);
+    let elapsed = Timer::now_us() - start;
+    dst.sync_for_cpu();
+
+    if let Err(e) = result {
+        shell::error(format_args!("transfer failed: {:?}", e));
+        return shell::STATUS_FAILED;
+    }
+    if let Some(i) = (0..len).find(|&i| dst[i] != src[i]) {
+        shell::error(format_args!("mismatch at offset {:#x}: {:#04x} != {:#04x}", i, dst[i], src[i]));
+        return shell::STATUS_FAILED;
+    }
+    if dst[len..].iter().any(|&b| b != 0) {
+        shell::error(format_args!("wrote past the end of the transfer"));
+        return shell::STATUS_FAILED;
+    }
+
+    kprintln!("copied {} bytes in {} us", len, elapsed);
+    shell::STATUS_OK
+}
@@ -0,0 +1,122 @@
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::barrier;
+use crate::cache;
+use crate::frame::{self, Frame};
+use crate::mmu;
+use crate::vm::{self, VmError};
+
+/*
+ * Buffers shared with bus masters that do not look in the ARM's caches: the VideoCore (mailbox
+ * property buffers, framebuffer) and the DMA engine. A DmaBuffer<T> holds a T in physically
+ * contiguous, page-aligned memory and gives out its bus address. It is either
+ *  - Uncached: accessed through an uncached mapping, so the CPU and the device always agree, at the
+ *    cost of slow CPU access; best for small structures the device reads often, such as DMA
+ *    control blocks, or
+ *  - Cached: accessed through the cacheable linear map, calling sync_for_device() before the device
+ *    reads it and sync_for_cpu() before the CPU reads what the device wrote; best for bulk data.
+ */
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Coherency {
+    Uncached,
+    Cached,
+}
+
+pub struct DmaBuffer<T> {
+    frames:    Frame,
+    pages:     usize,
+    ptr:       *mut T, // Where the CPU accesses the buffer
+    coherency: Coherency,
+    _owns:     PhantomData<T>,
+}
+
+// The buffer is owned like a Box, so it may move between cores with its contents
+unsafe impl<T: Send> Send for DmaBuffer<T> {}
+
+impl<T> DmaBuffer<T> {
+    // Allocates a buffer holding "value"
+    pub fn new(value: T, coherency: Coherency) -> Result<DmaBuffer<T>, VmError> {
+        let size   = if mem::size_of::<T>() > 0 { mem::size_of::<T>() } else { 1 };
+        let pages  = vm::align_up(size, mmu::PAGE_SIZE) / mmu::PAGE_SIZE;
+        let frames = frame::alloc_contiguous(pages, 1).ok_or(VmError::OutOfMemory)?;
+
+        let addr = match coherency {
+            Coherency::Cached   => mmu::phys_to_virt(frames.addr()),
+            Coherency::Uncached => match vm::map_uncached(frames.addr(), pages) {
+                Ok(addr) => addr,
+                Err(e)   => {
+                    frame::free_contiguous(frames, pages);
+                    return Err(e);
+                },
+            },
+        };
+
+        let ptr = addr as *mut T;
+        unsafe { ptr::write(ptr, value); }
+
+        Ok(DmaBuffer { frames: frames, pages: pages, ptr: ptr, coherency: coherency, _owns: PhantomData })
+    }
+
+    pub fn coherency(&self) -> Coherency {
+        self.coherency
+    }
+
+    pub fn phys_addr(&self) -> usize {
+        self.frames.addr()
+    }
+
+    // The address to give the VideoCore or the DMA engine
+    pub fn bus_addr(&self) -> u32 {
+        mmu::phys_to_bus(self.frames.addr())
+    }
+
+    // Size of the buffer rounded up to whole cache lines (it always starts on a page boundary)
+    fn cache_len(&self) -> usize {
+        vm::align_up(mem::size_of::<T>(), cache::CACHE_LINE)
+    }
+
+    // Makes everything the CPU wrote visible to the device. Call before starting the device.
+    pub fn sync_for_device(&self) {
+        match self.coherency {
+            Coherency::Cached   => cache::clean_range(self.ptr as usize, self.cache_len()),
+            Coherency::Uncached => barrier::dsb(), // Drain the write buffer
+        }
+    }
+
+    // Makes everything the device wrote visible to the CPU. Call once the device has finished,
+    // before reading the buffer; any CPU writes since sync_for_device() are lost.
+    pub fn sync_for_cpu(&self) {
+        match self.coherency {
+            Coherency::Cached   => cache::invalidate_range(self.ptr as usize, self.cache_len()),
+            Coherency::Uncached => barrier::dmb(),
+        }
+    }
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr }
+    }
+}
+
+impl<T> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr); }
+        if self.coherency == Coherency::Uncached {
+            vm::unmap_uncached(self.ptr as usize, self.pages);
+        }
+        frame::free_contiguous(self.frames, self.pages);
+    }
+}
@@ -0,0 +1,131 @@
+use core::fmt;
+
+use crate::cpu;
+use crate::panic;
+use crate::process::{self, ExitStatus};
+
+/*
+ * CPU exceptions other than IRQs and supervisor calls (see vectors.S). None of them are expected,
+ * so each is reported with the faulting address, the fault status and the registers. In the
+ * kernel that is fatal; in user mode, only the faulting process is killed (see process/mod.rs).
+ */
+
+extern "C" {
+    static exception_vectors: u32;
+    fn exceptions_init(abt_stack_top: usize, und_stack_top: usize, vbar: usize);
+    fn exc_get_dfsr() -> u32;
+    fn exc_get_dfar() -> u32;
+    fn exc_get_ifsr() -> u32;
+    fn exc_get_ifar() -> u32;
+}
+
+// Exception numbers (see vectors.S)
+const EXC_RESET:          u32 = 0;
+const EXC_UNDEFINED:      u32 = 1;
+pub const EXC_SVC:        u32 = 2;
+const EXC_PREFETCH_ABORT: u32 = 3;
+const EXC_DATA_ABORT:     u32 = 4;
+const EXC_FIQ:            u32 = 7;
+
+// Size of each core's ABT and UND mode stacks (only used to report the fault)
+const STACK_WORDS: usize = 256;
+
+static mut ABT_STACKS: [[u64; STACK_WORDS]; cpu::CORES] = [[0; STACK_WORDS]; cpu::CORES];
+static mut UND_STACKS: [[u64; STACK_WORDS]; cpu::CORES] = [[0; STACK_WORDS]; cpu::CORES];
+
+// Registers saved by vectors.S
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r:  [usize; 13],
+    pub pc: usize, // The faulting instruction
+}
+
+// Installs the vector table and the exception mode stacks on the current core
+pub fn init() {
+    let core = cpu::core_id();
+    unsafe {
+        let abt_top = ABT_STACKS[core].as_ptr() as usize + STACK_WORDS * 8;
+        let und_top = UND_STACKS[core].as_ptr() as usize + STACK_WORDS * 8;
+        exceptions_init(abt_top, und_top, &exception_vectors as *const u32 as usize);
+    }
+}
+
+// Describes an abort from the fault status register (FS bits [10] and [3:0])
+fn fault_status(fsr: u32) -> &'static str {
+    match (fsr & 0xF) | ((fsr >> 6) & 0x10) {
+        0x01 => "alignment fault",
+        0x02 => "debug event",
+        0x03 => "access flag fault (section)",
+        0x04 => "instruction cache maintenance fault",
+        0x05 => "translation fault (section)",
+        0x06 => "access flag fault (page)",
+        0x07 => "translation fault (page)",
+        0x08 => "synchronous external abort",
+        0x09 => "domain fault (section)",
+        0x0B => "domain fault (page)",
+        0x0C => "external abort on table walk (L1)",
+        0x0D => "permission fault (section)",
+        0x0E => "external abort on table walk (L2)",
+        0x0F => "permission fault (page)",
+        0x16 => "asynchronous external abort",
+        _    => "unknown fault",
+    }
+}
+
+struct Report<'a> {
+    kind:  u32,
+    frame: &'a ExceptionFrame,
+    spsr:  u32,
+}
+
+impl<'a> fmt::Display for Report<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            EXC_DATA_ABORT => {
+                let (dfsr, dfar) = unsafe { (exc_get_dfsr(), exc_get_dfar()) };
+                let access = if dfsr & (1 << 11) != 0 { "writing" } else { "reading" };
+                writeln!(f, "Data abort {} {:#010x}: {} (DFSR {:#x})", access, dfar, fault_status(dfsr), dfsr)?;
+            },
+            EXC_PREFETCH_ABORT => {
+                let (ifsr, ifar) = unsafe { (exc_get_ifsr(), exc_get_ifar()) };
+                writeln!(f, "Prefetch abort at {:#010x}: {} (IFSR {:#x})", ifar, fault_status(ifsr), ifsr)?;
+            },
+            EXC_UNDEFINED => writeln!(f, "Undefined instruction")?,
+            EXC_SVC       => writeln!(f, "Unexpected supervisor call")?,
+            EXC_FIQ       => writeln!(f, "Unexpected FIQ")?,
+            EXC_RESET     => writeln!(f, "Unexpected reset vector")?,
+            _             => writeln!(f, "Unexpected exception {}", self.kind)?,
+        }
+
+        let pc = self.frame.pc;
+        write!(f, "pc {:#010x}", pc)?;
+        if let Some((name, offset)) = panic::symbolicate(pc) {
+            write!(f, " {}+{:#x}", name, offset)?;
+        }
+        writeln!(f, ", cpsr {:#010x}, core {}", self.spsr, cpu::core_id())?;
+
+        for (i, r) in self.frame.r.iter().enumerate() {
+            write!(f, "r{:<2} {:#010x}{}", i, r, if i % 4 == 3 { "\n" } else { "  " })?;
+        }
+        Ok(())
+    }
+}
+
+// Called from vectors.S for every exception taken in the kernel except IRQs and, through
+// process/syscall.rs, for supervisor calls made by the kernel itself
+#[no_mangle]
+pub extern "C" fn exception_fatal(kind: u32, frame: &ExceptionFrame, spsr: u32) -> ! {
+    let report = Report { kind: kind, frame: frame, spsr: spsr };
+    panic::fatal(format_args!("{}", report), frame.r[11])
+}
+
+// Called from vectors.S for an exception taken in user mode, on the faulting thread's kernel stack
+// with IRQs masked
+#[no_mangle]
+pub extern "C" fn exception_user_fault(kind: u32, frame: &ExceptionFrame, spsr: u32) -> ! {
+    let report = Report { kind: kind, frame: frame, spsr: spsr };
+    kprint!("{}", report);
+
+    cpu::enable_interrupts();
+    process::exit_current(ExitStatus::Killed)
+}
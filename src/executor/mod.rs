@@ -0,0 +1,137 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+
+use crate::cpu;
+use crate::sched::{self, ThreadId};
+use crate::shell;
+use crate::timer::Timer;
+use crate::uart::Uart;
+
+/*
+ * A cooperative executor for async kernel code, such as drivers written as async functions rather
+ * than as state machines over polling loops. Interrupt handlers wake the futures waiting for them
+ * through WakerSlots. A future runs either:
+ *  - with block_on(), on the calling thread, which blocks while the future is pending
+ *  - as a task, with spawn(), on the executor thread, which polls the tasks that have been woken
+ * Tasks take turns only where they return Pending: a task that does not, holds up the others.
+ *
+ * The leaf futures are sleep() (see sleep.rs) and the Uart's read_async() and write_async().
+ */
+
+mod sleep;
+mod slot;
+mod task;
+
+pub use self::sleep::sleep;
+pub use self::slot::WakerSlot;
+pub use self::task::spawn;
+
+// Starts the executor thread and the timer futures. Needs the scheduler.
+pub fn init() {
+    sleep::init();
+    task::init();
+}
+
+// Runs "future" to completion on the current thread, which blocks while it is pending. Polls in a
+// loop instead if the caller cannot block.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = future;
+    // Shadowed, so it cannot be moved again
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+
+    let can_block = sched::can_block();
+    let waker = thread_waker(if can_block { sched::current() } else { 0 });
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        if can_block {
+            sched::block();
+        } else {
+            cpu::wait_for_interrupt();
+        }
+    }
+}
+
+/*
+ * Thread wakers, for block_on(): the data pointer is the id of the thread to wake (0: none)
+ */
+
+static THREAD_WAKER: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake, drop_waker);
+
+fn thread_waker(id: ThreadId) -> Waker {
+    unsafe { Waker::from_raw(RawWaker::new(id as *const (), &THREAD_WAKER)) }
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &THREAD_WAKER)
+}
+
+unsafe fn wake(data: *const ()) {
+    if !data.is_null() {
+        sched::wake(data as ThreadId);
+    }
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
+
+pub fn register_commands() {
+    shell::register("async", "[sleep <ms> | echo | count <n>]", "Show executor counts or try async code", cmd_async);
+}
+
+// Echoes what is typed until Ctrl-D, through the async UART calls
+async fn echo() {
+    loop {
+        match Uart::read_async().await {
+            0x04  => break,
+            b'\r' => Uart::write_async(b"\r\n").await,
+            ch    => Uart::write_async(&[ch]).await,
+        }
+    }
+}
+
+// Counts to "n", once a second, as a spawned task
+async fn count(n: usize) {
+    for i in 1..n + 1 {
+        sleep(Duration::from_secs(1)).await;
+        kprintln!("async: {} of {}", i, n);
+    }
+}
+
+fn cmd_async(args: &[&str]) -> i32 {
+    match args.get(1) {
+        Some(&"sleep") => {
+            let ms = match args.get(2).and_then(|ms| shell::parse_number(ms)) {
+                Some(ms) => ms,
+                None     => return shell::usage(args[0]),
+            };
+            let start = Timer::now_us();
+            block_on(sleep(Duration::from_millis(ms as u64)));
+            kprintln!("slept for {} us", Timer::now_us() - start);
+        },
+        Some(&"echo") => {
+            kprintln!("Echoing input, Ctrl-D to stop");
+            block_on(echo());
+        },
+        Some(&"count") => {
+            let n = match args.get(2).and_then(|n| shell::parse_number(n)) {
+                Some(n) => n,
+                None    => return shell::usage(args[0]),
+            };
+            if let Err(e) = spawn(count(n)) {
+                shell::error(format_args!("cannot spawn: {:?}", e));
+                return shell::STATUS_FAILED;
+            }
+        },
+        Some(_) => return shell::usage(args[0]),
+        None    => {
+            let (live, spawned, polls) = task::stats();
+            kprintln!("{} tasks running, {} spawned, {} polls, {} sleeps pending", live, spawned, polls, sleep::pending());
+        },
+    }
+    shell::STATUS_OK
+}
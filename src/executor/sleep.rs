@@ -0,0 +1,132 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use crate::irq;
+use crate::sync::IrqSpinLock;
+use crate::timer::Timer;
+
+/*
+ * Timer futures, on system timer compare channel 3 (channel 1 is the scheduler's tick). Every
+ * pending Sleep has an entry in PENDING; the channel is armed for the earliest deadline and its
+ * interrupt wakes the sleeps that are due. A Sleep removes its entry when dropped.
+ */
+
+const TIMER_CHANNEL: usize = 3;
+const TIMER_IRQ:     usize = 3;
+
+struct Entry {
+    id:       usize,
+    deadline: u64,
+    waker:    Waker,
+}
+
+static PENDING:  IrqSpinLock<Vec<Entry>> = IrqSpinLock::new(Vec::new());
+static NEXT_ID:  AtomicUsize = AtomicUsize::new(1);
+
+pub fn init() {
+    irq::register(TIMER_IRQ, timer_irq);
+    irq::enable(TIMER_IRQ);
+}
+
+// A future that completes once "duration" has passed
+pub fn sleep(duration: Duration) -> Sleep {
+    let us = duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64;
+    Sleep {
+        id:         NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        deadline:   Timer::now_us() + us,
+        registered: false,
+    }
+}
+
+pub struct Sleep {
+    id:         usize,
+    deadline:   u64, // See Timer::now_us()
+    registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Timer::now_us() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        register(self.id, self.deadline, cx.waker());
+        self.registered = true;
+
+        // The deadline may have gone by before the channel was armed for it
+        if Timer::now_us() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            let id = self.id;
+            PENDING.lock().retain(|e| e.id != id);
+        }
+    }
+}
+
+// Adds or updates the entry for sleep "id", arming the channel if its deadline is now the earliest
+fn register(id: usize, deadline: u64, waker: &Waker) {
+    let mut pending = PENDING.lock();
+
+    if let Some(entry) = pending.iter_mut().find(|e| e.id == id) {
+        if !entry.waker.will_wake(waker) {
+            entry.waker = waker.clone();
+        }
+        return;
+    }
+
+    let earliest = pending.iter().all(|e| e.deadline > deadline);
+    pending.push(Entry { id: id, deadline: deadline, waker: waker.clone() });
+    if earliest {
+        Timer::set_match(TIMER_CHANNEL, deadline as u32);
+    }
+}
+
+// Pending sleeps (for the "async" command)
+pub fn pending() -> usize {
+    PENDING.lock().len()
+}
+
+// Wakes the sleeps that are due and arms the channel for the next deadline. The channel only
+// compares the low 32 bits of the counter, so far-off deadlines may bring early interrupts, which
+// just re-arm it.
+fn timer_irq(_irq: usize) {
+    Timer::clear_match(TIMER_CHANNEL);
+
+    let mut pending = PENDING.lock();
+    loop {
+        let now = Timer::now_us();
+        let mut i = 0;
+        while i < pending.len() {
+            if pending[i].deadline <= now {
+                pending.swap_remove(i).waker.wake();
+            } else {
+                i += 1;
+            }
+        }
+
+        let next = match pending.iter().map(|e| e.deadline).min() {
+            Some(next) => next,
+            None       => break,
+        };
+        Timer::set_match(TIMER_CHANNEL, next as u32);
+
+        // Armed too late if it has already gone by: go round again
+        if Timer::now_us() < next {
+            break;
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use core::task::Waker;
+
+use crate::sync::IrqSpinLock;
+
+/*
+ * Where a future waiting for an interrupt leaves its waker for the handler. The future registers
+ * before checking its condition one last time, so an interrupt arriving in between is not missed,
+ * and the handler calls wake(). One waiter at a time: registering replaces the previous waker.
+ */
+
+pub struct WakerSlot {
+    waker: IrqSpinLock<Option<Waker>>,
+}
+
+impl WakerSlot {
+    pub const fn new() -> WakerSlot {
+        WakerSlot { waker: IrqSpinLock::new(None) }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock();
+        let stale = match *slot {
+            Some(ref current) => !current.will_wake(waker),
+            None              => true,
+        };
+        if stale {
+            *slot = Some(waker.clone());
+        }
+    }
+
+    // Wakes the registered waker, if any. Safe from interrupt handlers.
+    pub fn wake(&self) {
+        let waker = self.waker.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
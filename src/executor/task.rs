@@ -0,0 +1,147 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::ManuallyDrop;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+
+use crate::sched;
+use crate::sync::{MpscQueue, Once};
+
+/*
+ * Spawned tasks and the executor thread that polls them. A task's waker holds a reference to the
+ * task and puts it on the run queue when woken (at most once until it is next polled), then wakes
+ * the executor thread, which polls the queued tasks in turn and blocks when there are none.
+ */
+
+// Tasks that may exist at once; the run queue holds each of them at most once
+const MAX_TASKS: usize = 64;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SpawnError {
+    NotRunning,
+    TooManyTasks,
+}
+
+struct Task {
+    // Only ever touched by the executor thread; None once complete
+    future: UnsafeCell<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    queued: AtomicBool,
+}
+
+unsafe impl Sync for Task {}
+
+static RUN_QUEUE: Once<MpscQueue<Arc<Task>>> = Once::new();
+static EXECUTOR:  AtomicUsize = AtomicUsize::new(0); // The executor thread
+
+// Counts for the "async" command
+static LIVE:    AtomicUsize = AtomicUsize::new(0);
+static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+static POLLS:   AtomicUsize = AtomicUsize::new(0);
+
+// Starts the executor thread. Needs the scheduler.
+pub fn init() {
+    RUN_QUEUE.call_once(|| MpscQueue::new(MAX_TASKS));
+    match sched::spawn("executor", sched::PRIORITY_NORMAL, run, 0) {
+        Ok(id) => EXECUTOR.store(id, Ordering::Release),
+        Err(e) => kwarn!("cannot start the executor thread: {:?}", e),
+    }
+}
+
+// Runs "future" as a task on the executor thread
+pub fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) -> Result<(), SpawnError> {
+    if EXECUTOR.load(Ordering::Acquire) == 0 {
+        return Err(SpawnError::NotRunning);
+    }
+    if LIVE.fetch_add(1, Ordering::AcqRel) >= MAX_TASKS {
+        LIVE.fetch_sub(1, Ordering::AcqRel);
+        return Err(SpawnError::TooManyTasks);
+    }
+    SPAWNED.fetch_add(1, Ordering::Relaxed);
+
+    enqueue(Arc::new(Task {
+        future: UnsafeCell::new(Some(Box::pin(future))),
+        queued: AtomicBool::new(false),
+    }));
+    Ok(())
+}
+
+// Tasks not yet complete, tasks ever spawned and polls made
+pub fn stats() -> (usize, usize, usize) {
+    (LIVE.load(Ordering::Relaxed), SPAWNED.load(Ordering::Relaxed), POLLS.load(Ordering::Relaxed))
+}
+
+// Puts "task" on the run queue, unless it is already there, and wakes the executor thread
+fn enqueue(task: Arc<Task>) {
+    if task.queued.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if let Some(queue) = RUN_QUEUE.get() {
+        // Cannot be full: each live task is queued at most once
+        let _ = queue.push(task);
+        sched::wake(EXECUTOR.load(Ordering::Acquire));
+    }
+}
+
+fn run(_arg: usize) {
+    let queue = RUN_QUEUE.get().expect("executor: not initialised");
+
+    loop {
+        while let Some(task) = queue.pop() {
+            // Cleared before polling, so that a wake-up during the poll queues the task again
+            task.queued.store(false, Ordering::Release);
+
+            let waker = task_waker(task.clone());
+            let mut cx = Context::from_waker(&waker);
+            let future = unsafe { &mut *task.future.get() };
+
+            let done = match future.as_mut() {
+                Some(f) => f.as_mut().poll(&mut cx).is_ready(),
+                None    => false,
+            };
+            POLLS.fetch_add(1, Ordering::Relaxed);
+
+            if done {
+                *future = None;
+                LIVE.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        // A task woken since the queue was found empty makes this return at once
+        sched::block();
+    }
+}
+
+/*
+ * Task wakers: the data pointer is an Arc<Task> turned into a raw pointer
+ */
+
+static TASK_WAKER: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+fn task_waker(task: Arc<Task>) -> Waker {
+    unsafe { Waker::from_raw(raw_waker(task)) }
+}
+
+fn raw_waker(task: Arc<Task>) -> RawWaker {
+    RawWaker::new(Arc::into_raw(task) as *const (), &TASK_WAKER)
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    let task = ManuallyDrop::new(Arc::from_raw(data as *const Task));
+    raw_waker((*task).clone())
+}
+
+unsafe fn wake(data: *const ()) {
+    enqueue(Arc::from_raw(data as *const Task));
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    let task = ManuallyDrop::new(Arc::from_raw(data as *const Task));
+    enqueue((*task).clone());
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(Arc::from_raw(data as *const Task));
+}
@@ -0,0 +1,247 @@
+use crate::heap;
+use crate::mailbox;
+use crate::mmio;
+use crate::mmu;
+use crate::shell;
+use crate::sync::SpinLock;
+
+/*
+ * Physical page frame allocator. A bitmap tracks every 4 KiB frame of the low 1 GiB of the
+ * physical address space (all the RAM a Pi 2 can have, plus the peripherals). A set bit means the
+ * frame is free; the bitmap lives in .bss, so everything starts out reserved and init() frees ARM
+ * RAM before reserving the parts of it that are already in use.
+ */
+
+pub const FRAME_SIZE:  usize = 4096;
+pub const FRAME_SHIFT: usize = 12;
+
+const MAX_PHYS:     usize = 0x40000000;
+const FRAME_COUNT:  usize = MAX_PHYS / FRAME_SIZE;
+const BITMAP_WORDS: usize = FRAME_COUNT / 32;
+const MAX_REGIONS:  usize = 16;
+
+// Linker symbols (see linker.ld)
+extern {
+    static __start: u32;
+    static __end:   u32;
+}
+
+// A physical frame, identified by its number (address / FRAME_SIZE)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frame(usize);
+impl Frame {
+    // The frame containing the physical address "addr"
+    pub fn containing(addr: usize) -> Frame {
+        Frame(addr >> FRAME_SHIFT)
+    }
+
+    pub fn number(&self) -> usize {
+        self.0
+    }
+
+    pub fn addr(&self) -> usize {
+        self.0 << FRAME_SHIFT
+    }
+
+    pub fn offset(&self, frames: usize) -> Frame {
+        Frame(self.0 + frames)
+    }
+}
+
+// A named reserved region, kept for reporting
+#[derive(Copy, Clone)]
+struct Region {
+    name:  &'static str,
+    start: usize,
+    end:   usize,
+}
+
+struct State {
+    bitmap:    [u32; BITMAP_WORDS],
+    ram_start: usize,
+    ram_end:   usize,
+    free:      usize, // Number of free frames
+    hint:      usize, // Bitmap word to start searching from
+    regions:   [Option<Region>; MAX_REGIONS],
+}
+
+impl State {
+    fn is_free(&self, n: usize) -> bool {
+        self.bitmap[n / 32] & (1 << (n % 32)) != 0
+    }
+
+    fn set_free(&mut self, n: usize, free: bool) {
+        let bit = 1 << (n % 32);
+        if free && self.bitmap[n / 32] & bit == 0 {
+            self.bitmap[n / 32] |= bit;
+            self.free += 1;
+        } else if !free && self.bitmap[n / 32] & bit != 0 {
+            self.bitmap[n / 32] &= !bit;
+            self.free -= 1;
+        }
+    }
+}
+
+// Shared by all cores. Frames are never allocated from interrupt handlers, so a plain SpinLock
+// does; it is taken after the vm.rs locks, never before.
+static STATE: SpinLock<State> = SpinLock::new(State {
+    bitmap:    [0; BITMAP_WORDS],
+    ram_start: 0,
+    ram_end:   0,
+    free:      0,
+    hint:      0,
+    regions:   [None; MAX_REGIONS],
+});
+
+// Frame numbers covering [start, end), clamped to the tracked range. Partial frames are included.
+fn frame_range(start: usize, end: usize) -> (usize, usize) {
+    let end = if end > MAX_PHYS { MAX_PHYS } else { end };
+    if start >= end {
+        return (0, 0);
+    }
+    (start >> FRAME_SHIFT, (end + FRAME_SIZE - 1) >> FRAME_SHIFT)
+}
+
+// Frees all of ARM RAM, then reserves what the kernel is already using: the boot stack and the
+// ATAGS below the kernel, the kernel image, the kernel heap and the peripherals
+pub fn init() {
+    let (ram_start, ram_end) = match mailbox::get_arm_memory() {
+        Some((base, size)) => (base, base + size),
+        None               => (0, heap::FALLBACK_RAM_END),
+    };
+
+    {
+        let mut s = STATE.lock();
+        s.ram_start = ram_start;
+        s.ram_end   = ram_end;
+
+        let (first, last) = frame_range(ram_start, ram_end);
+        for n in first..last {
+            s.set_free(n, true);
+        }
+    }
+
+    // The kernel image and heap are known by their virtual addresses
+    let (kernel_start, kernel_end) = unsafe {
+        (&__start as *const u32 as usize, &__end as *const u32 as usize)
+    };
+    let (kernel_start, kernel_end) = (mmu::virt_to_phys(kernel_start), mmu::virt_to_phys(kernel_end));
+    let (heap_start, heap_end) = heap::region();
+    let (heap_start, heap_end) = (mmu::virt_to_phys(heap_start), mmu::virt_to_phys(heap_end));
+
+    reserve("boot stack",  0,                     kernel_start);
+    reserve("kernel",      kernel_start,          kernel_end);
+    reserve("heap",        heap_start,            heap_end);
+    reserve("peripherals", mmio::PERIPHERAL_PHYS, MAX_PHYS);
+}
+
+// Marks the physical range [start, end) as in use, recording it under "name"
+pub fn reserve(name: &'static str, start: usize, end: usize) {
+    let mut s = STATE.lock();
+    let (first, last) = frame_range(start, end);
+    for n in first..last {
+        s.set_free(n, false);
+    }
+
+    for slot in s.regions.iter_mut() {
+        if slot.is_none() {
+            *slot = Some(Region { name: name, start: start, end: end });
+            break;
+        }
+    }
+}
+
+// Whether every frame overlapping the physical range [start, end) is free
+pub fn is_free_range(start: usize, end: usize) -> bool {
+    let s = STATE.lock();
+    let (first, last) = frame_range(start, end);
+    last > first && end <= MAX_PHYS && (first..last).all(|n| s.is_free(n))
+}
+
+// Allocates a single frame
+pub fn alloc() -> Option<Frame> {
+    alloc_contiguous(1, 1)
+}
+
+// Allocates "count" physically contiguous frames, the first aligned to "align" frames (a power of
+// two). Used for page tables and DMA buffers.
+pub fn alloc_contiguous(count: usize, align: usize) -> Option<Frame> {
+    if count == 0 {
+        return None;
+    }
+
+    let mut s = STATE.lock();
+    let (first, last) = frame_range(s.ram_start, s.ram_end);
+
+    // Search from the hint first, then wrap around to the start of RAM
+    let hint = s.hint * 32;
+    let starts = [if hint > first && hint < last { hint } else { first }, first];
+
+    for &from in starts.iter() {
+        let mut n = (from + align - 1) & !(align - 1);
+        while n + count <= last {
+            // Skip whole words with no free frames in them
+            if count == 1 && n % 32 == 0 && s.bitmap[n / 32] == 0 {
+                n += 32;
+                continue;
+            }
+
+            let used = (n..(n + count)).find(|&i| !s.is_free(i));
+            match used {
+                None => {
+                    for i in n..(n + count) {
+                        s.set_free(i, false);
+                    }
+                    s.hint = (n + count) / 32;
+                    return Some(Frame(n));
+                },
+                // Restart the search after the frame that is in use
+                Some(used) => n = (used + 1 + align - 1) & !(align - 1),
+            }
+        }
+    }
+
+    None
+}
+
+pub fn free(frame: Frame) {
+    free_contiguous(frame, 1);
+}
+
+pub fn free_contiguous(frame: Frame, count: usize) {
+    let mut s = STATE.lock();
+    for n in frame.0..(frame.0 + count) {
+        debug_assert!(!s.is_free(n), "double free of frame {:#x}", n);
+        s.set_free(n, true);
+    }
+}
+
+// The physical range of ARM RAM
+pub fn ram_range() -> (usize, usize) {
+    let s = STATE.lock();
+    (s.ram_start, s.ram_end)
+}
+
+// Returns (free, total) frame counts for ARM RAM
+pub fn stats() -> (usize, usize) {
+    let s = STATE.lock();
+    (s.free, (s.ram_end - s.ram_start) / FRAME_SIZE)
+}
+
+pub fn register_commands() {
+    shell::register("frames", "", "Show physical memory usage", cmd_frames);
+}
+
+fn cmd_frames(_args: &[&str]) -> i32 {
+    let (free, total) = stats();
+    let (ram_start, ram_end) = ram_range();
+    let regions = STATE.lock().regions;
+
+    kprintln!("ARM RAM: {:#010x} - {:#010x}", ram_start, ram_end);
+    kprintln!("Frames:  {} free of {} ({} KiB free)", free, total, free * FRAME_SIZE / 1024);
+    kprintln!("Reserved regions:");
+    for r in regions.iter().filter_map(|r| r.as_ref()) {
+        kprintln!("  {:<12} {:#010x} - {:#010x}", r.name, r.start, r.end);
+    }
+    shell::STATUS_OK
+}
@@ -1,121 +1,18 @@
-use font8x8;
-use mmio;
-
-const PROPERTY_CHANNEL: usize = 8;
-
-#[allow(dead_code)] const FRAMEBUFFER_CHANNEL: usize = 1;
+use core::cmp;
+use core::fmt;
+use core::i16;
+use core::mem;
+use core::ptr;
+
+use crate::cache;
+use crate::dma::{self, ChannelKind, ControlBlock};
+use crate::font8x8;
+use crate::mmu;
+use crate::mailbox::{MailMessage, PROPERTY_CHANNEL, REQUEST, RESPONSE_ERROR, RESPONSE_SUCCESS};
 
 const CHAR_WIDTH:  u32 = 8;
 const CHAR_HEIGHT: u32 = 8;
 
-// Current property mailbox status
-struct MailStatus {
-    // Fields: -
-    //  - [0..29]: reserved
-    //  - 30: read buffer empty flag
-    //  - 31: write buffer full flag
-    bitfield: u32,
-}
-impl MailStatus {
-    fn new() -> MailStatus {
-        MailStatus { bitfield: 0 }
-    }
-
-    pub fn is_read_empty(&self) -> bool {
-        (self.bitfield & (1 << 30)) > 0
-    }
-
-    pub fn is_write_full(&self) -> bool {
-        (self.bitfield & (1 << 31)) > 0
-    }
-
-    pub fn update(&mut self) {
-        self.bitfield = mmio::Mmio::read(mmio::GPU_MAILBOX_STATUS);
-    }
-}
-
-// A single property mailbox message which can be read from/written to a channel
-struct MailMessage {
-    // Fields: -
-    //  - [0..3]:  channel ID
-    //  - [4..31]: message data
-    bitfield: u32,
-}
-impl MailMessage {
-    fn new() -> MailMessage {
-        MailMessage { bitfield: 0 }
-    }
-
-    pub fn get_channel_id(&self) -> usize {
-        (self.bitfield & 0x0F) as usize
-    }
-
-    pub fn set_channel_id(&mut self, id: u8) {
-        self.bitfield = (self.bitfield & 0xFFFFFFF0) | ((id & 0x0F) as u32)
-    }
-
-    pub fn get_data(&self) -> usize {
-        ((self.bitfield & 0xFFFFFFF0) >> 4) as usize
-    }
-
-    pub fn set_data(&mut self, data: u32) {
-        self.bitfield = (self.bitfield & 0x0F) | (((data << 4) & 0xFFFFFFF0) as u32)
-    }
-
-    pub fn update(&mut self) {
-        self.bitfield = mmio::Mmio::read(mmio::GPU_MAILBOX_READ);
-    }
-
-    pub fn write(&self) {
-        mmio::Mmio::write(mmio::GPU_MAILBOX_WRITE, self.bitfield);
-    }
-
-    pub fn mailbox_read(channel: usize) -> MailMessage {
-        let mut counter:   usize =     0;
-        let     max_count: usize = 20000;
-        let mut status:    MailStatus  = MailStatus::new();
-        let mut res:       MailMessage = MailMessage::new();
-
-        // Loop until the channel read matches the requested channel
-        loop {
-
-            // Loop until the mailbox status shows not empty
-            loop {
-                status.update();
-                counter += 1;
-                if counter >= max_count || !status.is_read_empty() {
-                    break;
-                }
-            }
-
-            // Read from mailbox
-            res.update();
-
-            if res.get_channel_id() == channel || counter >= max_count {
-                break;
-            }
-        }
-
-        res
-    }
-
-    pub fn mailbox_write(&mut self, channel: usize) {
-        let mut status: MailStatus = MailStatus::new();
-        self.set_channel_id(channel as u8);
-
-        // Loop until the mailbox status shows not full
-        loop {
-            status.update();
-            if !status.is_write_full() {
-                break;
-            }
-        }
-
-        // Write message to mailbox
-        self.write();
-    }
-}
-
 // Result type returned from FrameBuffer24::init() and ::alloc()
 pub enum FBInitResult {
     Success,
@@ -136,7 +33,12 @@ pub struct FrameBuffer24 {
     pub chars_height: u32,
     pub x:            u32,
     pub y:            u32,
+    pub style:        TextStyle, // Style used when writing through core::fmt::Write
+    dma:              Option<dma::Channel>, // Accelerates fills and copies when available
 }
+
+// "buf" points at the GPU's framebuffer, which belongs to whoever owns the FrameBuffer24
+unsafe impl Send for FrameBuffer24 {}
 impl FrameBuffer24 {
     pub fn new(width: u32, height: u32) -> Result<FrameBuffer24, ()> {
         let mut fb = FrameBuffer24 {
@@ -150,6 +52,8 @@ impl FrameBuffer24 {
             chars_height: 0,
             x:            0,
             y:            0,
+            style:        TextStyle::new(Pixel24 {r: 255, g: 255, b: 255}),
+            dma:          None,
         };
 
         match fb.init() {
@@ -164,6 +68,9 @@ impl FrameBuffer24 {
             _ => {},
         };
 
+        // Fills and copies need 2D mode; without a full channel the CPU does them
+        fb.dma = dma::alloc_channel(ChannelKind::Full).ok();
+
         Ok(fb)
     }
 
@@ -207,17 +114,21 @@ impl FrameBuffer24 {
         req_init.size = 3 * 4 + (req_init.tags[0].size + req_init.tags[1].size + req_init.tags[2].size) + (9 * 4);
         req_init.size += if req_init.size % 16 > 0 { 16 - (req_init.size % 16) } else { 0 };
 
+        // The request must reach memory before the GPU reads it, and the response must not be read
+        // from stale cache lines
+        let (req_addr, req_len) = (&req_init as *const _ as usize, mem::size_of::<FBInitMessage>());
+        cache::clean_range(req_addr, req_len);
+
         // Low 4 bits of address are 0 as address is 16 byte aligned and "data" must be highest 28 bits
         // (32 - 4), so shift right by 4
-        mail.set_data((((&req_init as *const _) as u32) >> 4) as u32);
-        mail.mailbox_write(PROPERTY_CHANNEL);
-
-        let mail = MailMessage::mailbox_read(PROPERTY_CHANNEL);
+        mail.set_data(mmu::virt_to_bus(req_addr) >> 4);
+        let mail = mail.exchange(PROPERTY_CHANNEL);
+        cache::invalidate_range(req_addr, req_len);
 
         // After writing and reading, the MailMessage will contain the address of the FBInitMessage
         // that is filled out by the GPU. Therefore, access this struct as a raw pointer to get the
         // result.
-        let res_init: *mut FBInitMessage = (mail.get_data() << 4) as *mut FBInitMessage;
+        let res_init: *mut FBInitMessage = mmu::phys_to_virt(mmu::bus_to_phys((mail.get_data() << 4) as u32)) as *mut FBInitMessage;
 
         unsafe {
             if (*res_init).mtype == REQUEST {
@@ -253,11 +164,14 @@ impl FrameBuffer24 {
         req_alloc.size = 6 * 4 + req_alloc.tag.size;
         req_alloc.size += if req_alloc.size % 16 > 0 { 16 - (req_alloc.size % 16) } else { 0 };
 
-        mail.set_data((((&req_alloc as *const _) as u32) >> 4) as u32);
-        mail.mailbox_write(PROPERTY_CHANNEL);
-        let mail = MailMessage::mailbox_read(PROPERTY_CHANNEL);
+        let (req_addr, req_len) = (&req_alloc as *const _ as usize, mem::size_of::<FBAllocMessage>());
+        cache::clean_range(req_addr, req_len);
+
+        mail.set_data(mmu::virt_to_bus(req_addr) >> 4);
+        let mail = mail.exchange(PROPERTY_CHANNEL);
+        cache::invalidate_range(req_addr, req_len);
 
-        let res_alloc: *mut FBAllocMessage = (mail.get_data() << 4) as *mut FBAllocMessage;
+        let res_alloc: *mut FBAllocMessage = mmu::phys_to_virt(mmu::bus_to_phys((mail.get_data() << 4) as u32)) as *mut FBAllocMessage;
 
         unsafe {
             if (*res_alloc).mtype == REQUEST {
@@ -266,7 +180,11 @@ impl FrameBuffer24 {
                 return FBInitResult::ResponseError;
             }
 
-            self.buf  = (*res_alloc).tag.value_buffer.fb_allocate_res.fb_addr;
+            // The GPU returns a bus address (in the uncached 0xC0000000 alias); the ARM sees the same
+            // memory at the physical address with the top two bits cleared. GPU memory is mapped
+            // uncached (see mmu.rs), so drawing needs no cache maintenance.
+            let fb_addr = (*res_alloc).tag.value_buffer.fb_allocate_res.fb_addr as u32;
+            self.buf  = mmu::phys_to_virt(mmu::bus_to_phys(fb_addr)) as *mut u8;
             self.size = (*res_alloc).tag.value_buffer.fb_allocate_res.fb_size;
         }
 
@@ -290,6 +208,108 @@ impl FrameBuffer24 {
         }
     }
 
+    pub fn fill(&mut self, p: &Pixel24) {
+        let (width, height) = (self.width, self.height);
+        self.fill_rect(0, 0, width, height, p);
+    }
+
+    // Fills the w x h rectangle at (x, y), which must lie within the screen
+    pub fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32, p: &Pixel24) {
+        if w == 0 || h == 0 || x + w > self.width || y + h > self.height {
+            return;
+        }
+
+        // Draw the top row, then have the DMA engine repeat it down the rectangle
+        for col in x..(x + w) {
+            self.putpixel(col, y, p);
+        }
+        if h == 1 || self.dma_repeat_row(x, y, w, h - 1) {
+            return;
+        }
+
+        for row in (y + 1)..(y + h) {
+            for col in x..(x + w) {
+                self.putpixel(col, row, p);
+            }
+        }
+    }
+
+    // Copies the w x h rectangle at (sx, sy) to (dx, dy). Both must lie within the screen; they
+    // may overlap.
+    pub fn copy_rect(&mut self, sx: u32, sy: u32, dx: u32, dy: u32, w: u32, h: u32) {
+        if w == 0 || h == 0 || sx + w > self.width || dx + w > self.width || sy + h > self.height || dy + h > self.height {
+            return;
+        }
+
+        let row_len = w * 3;
+        let src = sy * self.pitch + sx * 3;
+        let dst = dy * self.pitch + dx * 3;
+
+        // The engine copies forwards and top to bottom, which is only safe for overlapping
+        // rectangles when the destination comes first
+        if dst <= src && row_len <= i16::MAX as u32 && self.pitch - row_len <= i16::MAX as u32 {
+            let stride = (self.pitch - row_len) as i16;
+            let block  = ControlBlock::copy_2d(self.bus_addr(dst), self.bus_addr(src), row_len as u16, h as usize, stride, stride);
+            if self.dma_run(block) {
+                return;
+            }
+        }
+
+        // Rows are copied in the order that does not overwrite rows still to be copied
+        for i in 0..h {
+            let row = if dst > src { h - 1 - i } else { i };
+            unsafe {
+                ptr::copy(self.buf.offset((src + row * self.pitch) as isize),
+                          self.buf.offset((dst + row * self.pitch) as isize),
+                          row_len as usize);
+            }
+        }
+    }
+
+    // Bus address of the byte at "offset" in the framebuffer, for the DMA engine
+    fn bus_addr(&self, offset: u32) -> u32 {
+        mmu::virt_to_bus(self.buf as usize + offset as usize)
+    }
+
+    // Copies the w pixels at (x, y) to the "rows" rows below with the DMA engine. Returns false,
+    // having done nothing, if the engine is not available.
+    fn dma_repeat_row(&mut self, x: u32, y: u32, w: u32, rows: u32) -> bool {
+        let row_len = w * 3;
+        if row_len > i16::MAX as u32 || self.pitch - row_len > i16::MAX as u32 || rows as usize > dma::MAX_2D_ROWS {
+            return false;
+        }
+
+        // The source goes back to the start of the row after each one; the destination moves on
+        let src   = self.bus_addr(y * self.pitch + x * 3);
+        let dst   = self.bus_addr((y + 1) * self.pitch + x * 3);
+        let block = ControlBlock::copy_2d(dst, src, row_len as u16, rows as usize, (self.pitch - row_len) as i16, -(row_len as i16));
+        self.dma_run(block)
+    }
+
+    // Runs a single control block on the framebuffer's channel, waiting for it to finish
+    fn dma_run(&mut self, block: ControlBlock) -> bool {
+        match self.dma {
+            Some(ref mut channel) => {
+                {
+                    let chain = channel.chain();
+                    chain.clear();
+                    if chain.push(block).is_err() {
+                        return false;
+                    }
+                }
+                channel.run().is_ok()
+            },
+            None => false,
+        }
+    }
+
+    // Blanks the screen and moves the text cursor to the top left
+    pub fn clear(&mut self) {
+        self.fill(&Pixel24 {r: 0, g: 0, b: 0});
+        self.x = 0;
+        self.y = 0;
+    }
+
     pub fn putpixel(&self, x: u32, y: u32, p: &Pixel24) {
         if x > self.width - 1 {
             return;
@@ -302,45 +322,52 @@ impl FrameBuffer24 {
         }
     }
 
-    pub fn putchar(&self, ch: char, posx: u32, posy: u32, col: &Pixel24) {
+    pub fn putchar(&self, ch: char, posx: u32, posy: u32, style: &TextStyle) {
         if ch as u8 >= 0x80 {
             return;
         }
 
-        let black: Pixel24 = Pixel24 {r: 0, g: 0, b: 0};
+        // Inverse rendering swaps the colours used for set and unset glyph pixels. A transparent
+        // background therefore makes the glyph itself transparent and fills the rest of the cell.
+        let (set, unset) = if style.inverse {
+            (style.bg.as_ref(), Some(&style.fg))
+        } else {
+            (Some(&style.fg), style.bg.as_ref())
+        };
 
         // Draw character at (posx,posy)
         for y in 0..8 {
+            let mut row: u8 = font8x8::CHARS[ch as usize][y];
+
+            // Bold is a double-strike: the glyph is drawn again one pixel to the right
+            if style.bold {
+                row |= row >> 1;
+            }
+
+            // Underline occupies the bottom row of the cell
+            if style.underline && y == CHAR_HEIGHT as usize - 1 {
+                row = 0xFF;
+            }
+
             for x in 0..8 {
-                let row: u8 = font8x8::CHARS[ch as usize][y];
-                self.putpixel(posx + x as u32, posy + y as u32, if row & (0x80 >> x) > 0 { col } else { &black });
+                let col = if row & (0x80 >> x) > 0 { set } else { unset };
+
+                // Leave pixels untouched when there is no colour to draw (transparent)
+                if let Some(col) = col {
+                    self.putpixel(posx + x as u32, posy + y as u32, col);
+                }
             }
         }
     }
 
     pub fn scroll_y(&mut self, pixels: u32) {
         let black: Pixel24 = Pixel24 {r: 0, g: 0, b: 0};
+        let (width, height) = (self.width, self.height);
+        let pixels = cmp::min(pixels, height);
 
-        // Scroll current pixels
-        unsafe {
-            for y in pixels..self.height {
-                for x in 0..self.width {
-                    let src = self.buf.offset(( y           * self.pitch + (x * 3)) as isize) as *const Pixel24;
-                    let dst = self.buf.offset(((y - pixels) * self.pitch + (x * 3)) as isize) as *mut   Pixel24;
-                    *dst = (*src).clone();
-                }
-            }
-        }
-
-        // Fill new pixels with black
-        unsafe {
-            for y in (self.height - pixels)..self.height {
-                for x in 0..self.width {
-                    let dst = self.buf.offset((y * self.pitch + (x * 3)) as isize) as *mut Pixel24;
-                    *dst = black.clone();
-                }
-            }
-        }
+        // Scroll current pixels, then fill new pixels with black
+        self.copy_rect(0, pixels, 0, 0, width, height - pixels);
+        self.fill_rect(0, height - pixels, width, pixels, &black);
     }
 
     fn handle_scroll(&mut self) {
@@ -351,23 +378,24 @@ impl FrameBuffer24 {
       }
     }
 
-    pub fn writechar(&mut self, ch: char, col: &Pixel24) {
+    pub fn writechar(&mut self, ch: char, style: &TextStyle) {
       let offx: u32 = self.x * CHAR_WIDTH;
       let offy: u32 = self.y * CHAR_HEIGHT;
-      let black: Pixel24 = Pixel24 {r: 0, g: 0, b: 0};
 
       // Carriage return
       if ch == '\n' || ch == '\r' {
-        self.putcursor(&black);
         self.x  = 0;
         self.y += 1;
         self.handle_scroll();
         return;
       }
 
-      // Backspace
+      // Backspace (moves the cursor back without erasing, wrapping to the end of the previous row)
       if ch == '\x08' {
         if self.x == 0 {
+          if self.y == 0 {
+            return;
+          }
           self.x = self.chars_width - 1;
           self.y -= 1;
         } else {
@@ -376,7 +404,7 @@ impl FrameBuffer24 {
         return;
       }
 
-      self.putchar(ch, offx, offy, col);
+      self.putchar(ch, offx, offy, style);
 
       // Increment FB cursor position
       self.x += 1;
@@ -387,19 +415,42 @@ impl FrameBuffer24 {
       }
     }
 
-    pub fn putcursor(&self, col: &Pixel24) {
-        for y in 0..8 {
-            for x in 0..8 {
-                self.putpixel(self.x * CHAR_WIDTH + x, self.y * CHAR_HEIGHT + y, col);
+    // Inverts every pixel of the character cell under the text cursor. Calling this twice restores
+    // the cell, so it can show a cursor over existing text without having to remember it.
+    pub fn toggle_cursor(&self) {
+        let (posx, posy) = (self.x * CHAR_WIDTH, self.y * CHAR_HEIGHT);
+        if posx + CHAR_WIDTH > self.width || posy + CHAR_HEIGHT > self.height {
+            return;
+        }
+
+        unsafe {
+            for y in posy..(posy + CHAR_HEIGHT) {
+                let row = self.buf.offset((y * self.pitch + posx * 3) as isize);
+                for i in 0..(CHAR_WIDTH * 3) {
+                    *row.offset(i as isize) ^= 0xFF;
+                }
             }
         }
     }
 
-    pub fn write_string(&mut self, s: &str, col: &Pixel24) {
+    pub fn write_string(&mut self, s: &str, style: &TextStyle) {
         for ch in s.as_bytes() {
-            self.writechar(ch.clone() as char, col);
+            self.writechar(ch.clone() as char, style);
         }
     }
+
+    pub fn set_style(&mut self, style: TextStyle) {
+        self.style = style;
+    }
+}
+
+// Writes formatted text at the current cursor position using the framebuffer's current style
+impl fmt::Write for FrameBuffer24 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let style = self.style.clone();
+        self.write_string(s, &style);
+        Ok(())
+    }
 }
 
 
@@ -416,10 +467,6 @@ const FB_SET_BITS_PER_PIXEL:      u32 = 0x00048005;
 #[allow(dead_code)] const FB_GET_BITS_PER_PIXEL:      u32 = 0x00040005;
 #[allow(dead_code)] const FB_GET_BYTES_PER_ROW:       u32 = 0x00040008;
 
-// Property mailbox request/response types
-const REQUEST:          u32 = 0x0;
-const RESPONSE_SUCCESS: u32 = 0x80000000;
-const RESPONSE_ERROR:   u32 = 0x80000001;
 
 // Framebuffer allocation data (for FB_ALLOCATE_BUFFER)
 #[derive(Copy, Clone)]
@@ -455,7 +502,7 @@ struct MessageTag {
 
 // Message to send to the property mailbox in order to initialise a framebuffer
 #[repr(C)]
-#[repr(align(16))]
+#[repr(align(64))] // Cache line aligned, so that it can be invalidated (see cache.rs)
 struct FBInitMessage {
     size:    u32,             // Size of buffer, including "size"
     mtype:   u32,             // Request/response type
@@ -466,7 +513,7 @@ struct FBInitMessage {
 // Message to send to the property mailbox in order to allocate a previously initialised
 // framebuffer
 #[repr(C)]
-#[repr(align(16))]
+#[repr(align(64))] // Cache line aligned, so that it can be invalidated (see cache.rs)
 struct FBAllocMessage {
     size:    u32,        // Size of buffer, including "size"
     mtype:   u32,        // Request/response and response type
@@ -474,9 +521,36 @@ struct FBAllocMessage {
     end_tag: u32,        // NULL tag
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Pixel24 {
     pub r: u8,
     pub g: u8,
     pub b: u8,
 }
+
+// Text rendering style used by putchar(), writechar() and write_string()
+#[derive(Clone, Debug)]
+pub struct TextStyle {
+    pub fg:        Pixel24,         // Glyph colour
+    pub bg:        Option<Pixel24>, // Cell background colour (None: transparent)
+    pub bold:      bool,            // Double-strike the glyph
+    pub underline: bool,            // Draw a line along the bottom row of the cell
+    pub inverse:   bool,            // Swap foreground and background colours
+}
+impl TextStyle {
+    // Plain style: foreground colour on a black background
+    pub fn new(fg: Pixel24) -> TextStyle {
+        TextStyle {
+            fg:        fg,
+            bg:        Some(Pixel24 {r: 0, g: 0, b: 0}),
+            bold:      false,
+            underline: false,
+            inverse:   false,
+        }
+    }
+
+    // Plain style with a transparent background, so text can be drawn over existing pixels
+    pub fn transparent(fg: Pixel24) -> TextStyle {
+        TextStyle { bg: None, ..TextStyle::new(fg) }
+    }
+}
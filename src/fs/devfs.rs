@@ -0,0 +1,196 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console;
+use crate::framebuffer::FrameBuffer24;
+use crate::fs::{DirEntry, FileSystem, FileType, FsError, Inode, Stat};
+use crate::uart::Uart;
+
+/*
+ * Device filesystem: a flat directory of device files, which read and write the devices
+ * themselves.
+ *  - console: writes go to every console sink, as kprint!() does; reads take keyboard input,
+ *             which comes from the serial port
+ *  - uart:    the serial port alone
+ *  - fb:      the console framebuffer's pixels, 3 bytes each, a row every "pitch" bytes (see
+ *             framebuffer.rs); seekable, with the size of the framebuffer. Each read or write
+ *             moves at most FB_CHUNK bytes, as it holds the screen's lock with IRQs masked.
+ * Reads of the console and the serial port wait for at least one byte, and then return what has
+ * already been received; they ignore the offset, as writes do. On the console, Ctrl-D marks the end
+ * of the input: a read stops before it, and the next one returns 0.
+ */
+
+struct Device {
+    name:  &'static str,
+    mode:  u32,
+    size:  fn() -> usize,
+    read:  fn(usize, &mut [u8]) -> Result<usize, FsError>,
+    write: fn(usize, &[u8]) -> Result<usize, FsError>,
+}
+
+static DEVICES: [Device; 3] = [
+    Device { name: "console", mode: 0o620, size: no_size, read: read_console, write: write_console },
+    Device { name: "uart",    mode: 0o660, size: no_size, read: read_serial,  write: write_serial },
+    Device { name: "fb",      mode: 0o660, size: fb_size, read: read_fb,      write: write_fb },
+];
+
+struct DevFs;
+
+struct Root;
+
+struct Node {
+    device: &'static Device,
+}
+
+pub fn create() -> Arc<dyn FileSystem> {
+    Arc::new(DevFs)
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+impl Inode for Root {
+    fn stat(&self) -> Stat {
+        Stat { file_type: FileType::Directory, mode: 0o755, size: 0 }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match DEVICES.iter().find(|d| d.name == name) {
+            Some(device) => Ok(Arc::new(Node { device: device })),
+            None         => Err(FsError::NotFound),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(DEVICES.iter().map(|d| DirEntry { name: String::from(d.name), file_type: FileType::Device }).collect())
+    }
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Stat {
+        Stat { file_type: FileType::Device, mode: self.device.mode, size: (self.device.size)() }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        (self.device.read)(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        (self.device.write)(offset, buf)
+    }
+}
+
+/*
+ * Devices
+ */
+
+fn no_size() -> usize {
+    0
+}
+
+// Ctrl-D, which ends console input
+const EOF_CHAR: u8 = 0x04;
+
+// Set when a console read stops at a Ctrl-D, so that the next read returns 0
+static CONSOLE_EOF: AtomicBool = AtomicBool::new(false);
+
+fn read_serial(_offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+
+    buf[0] = Uart::getc();
+    let mut count = 1;
+    while count < buf.len() {
+        match Uart::try_getc() {
+            Some(ch) => buf[count] = ch,
+            None     => break,
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn read_console(_offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    if buf.is_empty() || CONSOLE_EOF.swap(false, Ordering::Relaxed) {
+        return Ok(0);
+    }
+
+    // Waits for the first byte only, as read_serial() does
+    let mut count = 0;
+    while count < buf.len() {
+        let ch = if count == 0 { Some(Uart::getc()) } else { Uart::try_getc() };
+        match ch {
+            // Returned at once if nothing has been read yet, otherwise by the next read
+            Some(EOF_CHAR) => {
+                CONSOLE_EOF.store(count > 0, Ordering::Relaxed);
+                break;
+            },
+            Some(ch) => buf[count] = ch,
+            None     => break,
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn write_console(_offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+    match str::from_utf8(buf) {
+        Ok(s)  => kprint!("{}", s),
+        Err(_) => for &ch in buf {
+            kprint!("{}", ch as char);
+        },
+    }
+    Ok(buf.len())
+}
+
+fn write_serial(_offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+    for &ch in buf {
+        Uart::putc(ch);
+    }
+    Ok(buf.len())
+}
+
+fn fb_size() -> usize {
+    console::with_screen(|fb| fb.size as usize).unwrap_or(0)
+}
+
+// Bytes of the framebuffer read or written at a time
+const FB_CHUNK: usize = 4096;
+
+// How many of "len" bytes from "offset" are within the framebuffer, up to FB_CHUNK
+fn fb_span(fb: &FrameBuffer24, offset: usize, len: usize) -> usize {
+    len.min(FB_CHUNK).min((fb.size as usize).saturating_sub(offset))
+}
+
+// The copies run with the screen locked, so that they do not tear text being drawn
+fn read_fb(offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    console::with_screen(|fb| {
+        let count = fb_span(fb, offset, buf.len());
+        if count > 0 {
+            unsafe { ptr::copy_nonoverlapping(fb.buf.add(offset), buf.as_mut_ptr(), count); }
+        }
+        count
+    }).ok_or(FsError::NoDevice)
+}
+
+fn write_fb(offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+    console::with_screen(|fb| {
+        let count = fb_span(fb, offset, buf.len());
+        if count > 0 {
+            unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), fb.buf.add(offset), count); }
+        }
+        count
+    }).ok_or(FsError::NoDevice)
+}
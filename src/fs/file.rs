@@ -0,0 +1,155 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::fs::{self, DirEntry, FileType, FsError, Inode, Stat};
+use crate::sync::Mutex;
+
+/*
+ * Open files and the descriptor tables that number them. A File is an inode opened for reading,
+ * writing or both, with the offset the next read or write starts at; descriptors in any number of
+ * tables may share one, and with it the offset. The offset is locked for the whole of each
+ * operation, so a read waiting for input holds up other reads and writes of the same File.
+ */
+
+pub type Fd = usize;
+
+// Access modes, as open() takes them (Linux's values)
+pub const O_RDONLY:  usize = 0;
+pub const O_WRONLY:  usize = 1;
+pub const O_RDWR:    usize = 2;
+pub const O_ACCMODE: usize = 3;
+
+// Descriptors in one table
+const MAX_FILES: usize = 32;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+pub struct File {
+    inode:  Arc<dyn Inode>,
+    path:   String, // Absolute, as opened; read_dir() needs it to list the mount points
+    flags:  usize,
+    offset: Mutex<usize>,
+}
+
+impl File {
+    pub fn new(inode: Arc<dyn Inode>, flags: usize, path: String) -> File {
+        File { inode: inode, path: path, flags: flags, offset: Mutex::new(0) }
+    }
+
+    pub fn stat(&self) -> Stat {
+        self.inode.stat()
+    }
+
+    fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    // Reads into "buf" from the offset, and moves it on. Returns 0 at the end of the file.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.readable() {
+            return Err(FsError::BadDescriptor);
+        }
+        if self.stat().file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        let mut offset = self.offset.lock();
+        let count = self.inode.read_at(*offset, buf)?;
+        *offset += count;
+        Ok(count)
+    }
+
+    // Writes "buf" at the offset, and moves it on
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.writable() {
+            return Err(FsError::BadDescriptor);
+        }
+        if self.stat().file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        let mut offset = self.offset.lock();
+        let count = self.inode.write_at(*offset, buf)?;
+        *offset += count;
+        Ok(count)
+    }
+
+    // Moves the offset, which may go past the end but not before the start, and returns it
+    pub fn seek(&self, pos: SeekFrom) -> Result<usize, FsError> {
+        let mut offset = self.offset.lock();
+        let (base, delta) = match pos {
+            SeekFrom::Start(n)   => (0, n as isize),
+            SeekFrom::Current(n) => (*offset, n),
+            SeekFrom::End(n)     => (self.stat().size, n),
+        };
+        let target = if delta < 0 { base.checked_sub(delta.wrapping_neg() as usize) } else { base.checked_add(delta as usize) };
+        *offset = target.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    // Hands the next entry of an open directory, mount points included as fs::read_dir() lists
+    // them, to "deliver", and returns its result; None after the last entry. The offset counts the
+    // entries, and moves on only if "deliver" succeeds, so an entry it fails to pass on is not lost.
+    pub fn read_dir<T, F: FnOnce(DirEntry) -> Result<T, FsError>>(&self, deliver: F) -> Result<Option<T>, FsError> {
+        if !self.readable() {
+            return Err(FsError::BadDescriptor);
+        }
+
+        let mut offset = self.offset.lock();
+        let entry = match fs::list_dir(&*self.inode, &self.path)?.into_iter().nth(*offset) {
+            Some(entry) => entry,
+            None        => return Ok(None),
+        };
+        let result = deliver(entry)?;
+        *offset += 1;
+        Ok(Some(result))
+    }
+}
+
+// Open files, numbered from 0 by the lowest descriptors free
+pub struct FdTable {
+    files: Vec<Option<Arc<File>>>,
+}
+
+impl FdTable {
+    pub const fn new() -> FdTable {
+        FdTable { files: Vec::new() }
+    }
+
+    // Gives "file" the lowest free descriptor
+    pub fn insert(&mut self, file: Arc<File>) -> Result<Fd, FsError> {
+        if let Some(fd) = self.files.iter().position(|f| f.is_none()) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() == MAX_FILES {
+            return Err(FsError::TooManyOpen);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd: Fd) -> Result<Arc<File>, FsError> {
+        self.files.get(fd).and_then(|f| f.clone()).ok_or(FsError::BadDescriptor)
+    }
+
+    // Frees descriptor "fd", returning its file
+    pub fn remove(&mut self, fd: Fd) -> Result<Arc<File>, FsError> {
+        self.files.get_mut(fd).and_then(|f| f.take()).ok_or(FsError::BadDescriptor)
+    }
+
+    // Closes every descriptor
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
@@ -0,0 +1,276 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::slice;
+use core::str;
+
+use crate::fs::{DirEntry, FileSystem, FileType, FsError, Inode, Stat};
+use crate::sync::Once;
+
+/*
+ * Initial RAM filesystem: a read-only tree of files and directories, from a cpio archive ("newc"
+ * format) that the build packs and links into .rodata (see src/initramfs.S and
+ * tools/mkinitramfs.sh). init() indexes the archive once; files are then served straight from the
+ * kernel image, without copying.
+ *
+ * Paths are relative to the archive's root; a leading "/" is ignored. Directories the archive
+ * does not list but that hold listed entries exist too.
+ *
+ * Every instance mounted (see fs/mod.rs) serves the same archive.
+ */
+
+// Linker symbols (see linker.ld)
+extern {
+    static __initramfs_start: u8;
+    static __initramfs_end:   u8;
+}
+
+// newc header: the magic, then 13 fields of 8 hex digits, of which these are used
+const MAGIC:          &[u8] = b"070701";
+const HEADER_SIZE:    usize = 110;
+const FIELD_MODE:     usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+const TRAILER:        &str  = "TRAILER!!!";
+
+// File types, from the mode
+const S_IFMT:  u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    path: &'static str, // Without a leading "/"; "" is the root
+    mode: u32,          // Permission bits and type
+    data: &'static [u8],
+}
+
+impl Entry {
+    fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFREG => FileType::File,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            _       => FileType::Other,
+        }
+    }
+
+    // The last component of the path
+    fn name(&self) -> &'static str {
+        match self.path.rfind('/') {
+            Some(i) => &self.path[i + 1..],
+            None    => self.path,
+        }
+    }
+
+    fn directory(path: &'static str) -> Entry {
+        Entry { path: path, mode: S_IFDIR | 0o755, data: &[] }
+    }
+}
+
+static ENTRIES: Once<Vec<Entry>> = Once::new();
+
+fn archive() -> &'static [u8] {
+    unsafe {
+        let start = &__initramfs_start as *const u8;
+        let end   = &__initramfs_end as *const u8;
+        slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn hex_field(header: &[u8], field: usize) -> Option<u32> {
+    let start = MAGIC.len() + field * 8;
+    let digits = str::from_utf8(&header[start..start + 8]).ok()?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+// Indexes the archive, stopping at the trailer or at the first malformed header
+fn parse(archive: &'static [u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + HEADER_SIZE <= archive.len() {
+        let header = &archive[offset..offset + HEADER_SIZE];
+        if &header[..MAGIC.len()] != MAGIC {
+            kwarn!("initramfs: bad header at offset {:#x}", offset);
+            break;
+        }
+        let fields = (hex_field(header, FIELD_MODE), hex_field(header, FIELD_FILESIZE),
+                      hex_field(header, FIELD_NAMESIZE));
+        let (mode, size, name_size) = match fields {
+            (Some(mode), Some(size), Some(names)) if names > 0 => (mode, size as usize, names as usize),
+            _ => {
+                kwarn!("initramfs: bad header at offset {:#x}", offset);
+                break;
+            },
+        };
+
+        // The name (NUL-terminated) follows the header, and the data follows the name, each
+        // padded to 4 bytes. The sizes come from the archive, so the sums are checked.
+        let name_start = offset + HEADER_SIZE;
+        let data_start = name_start.checked_add(name_size).filter(|&end| end <= archive.len()).map(align4);
+        let data_end   = data_start.and_then(|start| start.checked_add(size)).filter(|&end| end <= archive.len());
+        let (data_start, data_end) = match (data_start, data_end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => {
+                kwarn!("initramfs: truncated entry at offset {:#x}", offset);
+                break;
+            },
+        };
+        let name = match str::from_utf8(&archive[name_start..name_start + name_size - 1]) {
+            Ok(name) => name,
+            Err(_)   => {
+                kwarn!("initramfs: bad name at offset {:#x}", offset);
+                break;
+            },
+        };
+        if name == TRAILER {
+            break;
+        }
+
+        let path = normalize(name);
+        if !path.is_empty() {
+            entries.push(Entry { path: path, mode: mode, data: &archive[data_start..data_end] });
+        }
+        offset = align4(data_end);
+    }
+    entries
+}
+
+// Drops leading "/" and "./" and any trailing "/"
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if path.starts_with('/') {
+            path = &path[1..];
+        } else if path.starts_with("./") {
+            path = &path[2..];
+        } else if path == "." {
+            path = "";
+        } else {
+            break;
+        }
+    }
+    path.trim_end_matches('/')
+}
+
+// Indexes the archive linked into the kernel. Needs the heap.
+pub fn init() {
+    let entries = ENTRIES.call_once(|| parse(archive()));
+    let bytes: usize = entries.iter().map(|e| e.data.len()).sum();
+    kinfo!("initramfs: {} entries, {} bytes of data in a {} byte archive", entries.len(), bytes, archive().len());
+}
+
+fn entries() -> &'static [Entry] {
+    ENTRIES.get().map_or(&[], |e| &e[..])
+}
+
+// Whether "path" is below directory "dir", both normalized
+fn is_below(dir: &str, path: &str) -> bool {
+    dir.is_empty() || (path.starts_with(dir) && path[dir.len()..].starts_with('/'))
+}
+
+// The entry at "path", which may be a directory that only exists because it holds other entries
+fn lookup(path: &str) -> Result<Entry, FsError> {
+    let path = normalize(path);
+    if let Some(entry) = entries().iter().find(|e| e.path == path) {
+        return Ok(*entry);
+    }
+
+    if path.is_empty() {
+        return Ok(Entry::directory(""));
+    }
+
+    // An implied directory, with its path borrowed from an entry below it
+    match entries().iter().find(|e| is_below(path, e.path)) {
+        Some(e) => Ok(Entry::directory(&e.path[..path.len()])),
+        None    => Err(FsError::NotFound),
+    }
+}
+
+// The entries of directory "path", in archive order
+fn read_dir(path: &str) -> Result<Vec<Entry>, FsError> {
+    let dir = lookup(path)?;
+    if dir.file_type() != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    let prefix_len = if dir.path.is_empty() { 0 } else { dir.path.len() + 1 };
+    let mut children: Vec<Entry> = Vec::new();
+    for e in entries().iter() {
+        if !is_below(dir.path, e.path) {
+            continue;
+        }
+
+        // A child, or something deeper whose first component below "dir" is an implied child
+        let rest = &e.path[prefix_len..];
+        let child = match rest.find('/') {
+            None    => *e,
+            Some(i) => match lookup(&e.path[..prefix_len + i]) {
+                Ok(child) => child,
+                Err(_)    => continue,
+            },
+        };
+        if !children.iter().any(|c| c.path == child.path) {
+            children.push(child);
+        }
+    }
+    Ok(children)
+}
+
+/*
+ * Filesystem interface
+ */
+
+struct Initramfs;
+
+// An entry, as an inode
+struct Node {
+    entry: Entry,
+}
+
+pub fn create() -> Arc<dyn FileSystem> {
+    Arc::new(Initramfs)
+}
+
+impl FileSystem for Initramfs {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Node { entry: Entry::directory("") })
+    }
+}
+
+impl Inode for Node {
+    fn stat(&self) -> Stat {
+        Stat { file_type: self.entry.file_type(), mode: self.entry.mode & 0o7777, size: self.entry.data.len() }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if self.entry.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let path = if self.entry.path.is_empty() { String::from(name) } else { format!("{}/{}", self.entry.path, name) };
+        Ok(Arc::new(Node { entry: lookup(&path)? }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let entries = read_dir(self.entry.path)?;
+        Ok(entries.iter().map(|e| DirEntry { name: String::from(e.name()), file_type: e.file_type() }).collect())
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.entry.data.get(offset..).unwrap_or(&[]);
+        let count = data.len().min(buf.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+}
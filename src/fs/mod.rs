@@ -0,0 +1,505 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::str;
+
+use crate::shell;
+use crate::sync::{RwLock, SpinLock};
+
+/*
+ * Virtual filesystem: one tree of paths over any number of mounted filesystems. A filesystem is a
+ * tree of inodes (files, directories, symbolic links and devices), each of which implements the
+ * Inode operations it supports; the VFS resolves paths across mount points and symbolic links,
+ * and open() wraps an inode in a File, which keeps the offset that reads and writes advance (see
+ * file.rs).
+ *
+ * Paths are "/"-separated. Relative paths start from the working directory, of which there is
+ * one: the shell's, which processes share. A mount point need not exist in the filesystem it is
+ * mounted over; it shows up in its parent's listing either way.
+ *
+ * At boot, the initial RAM filesystem (see initramfs.rs) is mounted at "/" and the devices (see
+ * devfs.rs) at "/dev".
+ */
+
+mod devfs;
+mod file;
+mod initramfs;
+
+pub use self::file::{Fd, FdTable, File, SeekFrom, O_ACCMODE, O_RDONLY, O_RDWR};
+
+// Symbolic links followed while resolving one path
+const MAX_LINKS: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NotSupported,    // The inode does not support the operation
+    ReadOnly,
+    BadDescriptor,   // Not an open descriptor, or not open for the operation
+    InvalidArgument,
+    TooManyOpen,
+    TooManyLinks,    // Symbolic links nested too deep, or in a loop
+    Busy,            // Already a mount point
+    NoDevice,        // The device is not there, such as the framebuffer when there is no screen
+    NameTooLong,     // A name does not fit where the caller wants it
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink, // Reading one gives its target
+    Device,
+    Other,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Stat {
+    pub file_type: FileType,
+    pub mode:      u32,   // Permission bits
+    pub size:      usize, // In bytes; 0 for directories
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name:      String,
+    pub file_type: FileType,
+}
+
+// A file, directory or other node of a filesystem. Operations an inode does not support fail.
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Stat;
+
+    // The entry called "name" in this directory, without following it if it is a symbolic link
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    // The entries of this directory, without "." and ".."
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    // Reads from "offset" into "buf", returning how many bytes were read: 0 at the end
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    // Writes "buf" at "offset", returning how many bytes were written
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    // The type of filesystem, as mount takes it
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    path: String, // Absolute and normalized
+    fs:   Arc<dyn FileSystem>,
+}
+
+// Later mounts at the same path hide earlier ones
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+static CWD:    SpinLock<String>   = SpinLock::new(String::new());
+
+// Types of filesystem the mount command can create
+const FS_TYPES: [(&str, fn() -> Arc<dyn FileSystem>); 2] = [
+    ("devfs",     devfs::create),
+    ("initramfs", initramfs::create),
+];
+
+// Mounted at boot
+const BOOT_MOUNTS: [(&str, fn() -> Arc<dyn FileSystem>); 2] = [
+    ("/",    initramfs::create),
+    ("/dev", devfs::create),
+];
+
+// Indexes the initial RAM filesystem and mounts it and the devices. Needs the heap.
+pub fn init() {
+    initramfs::init();
+
+    for &(path, create) in BOOT_MOUNTS.iter() {
+        if let Err(e) = mount(path, create()) {
+            kerror!("fs: cannot mount {}: {:?}", path, e);
+        }
+    }
+}
+
+/*
+ * Paths
+ */
+
+// "path" made absolute, relative to "base", with "." and ".." and repeated "/" resolved
+fn normalize(base: &str, path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    let start = if path.starts_with('/') { "" } else { base };
+    for name in start.split('/').chain(path.split('/')) {
+        match name {
+            "" | "." => {},
+            ".."     => { components.pop(); },
+            name     => components.push(name),
+        }
+    }
+
+    let mut normal = String::new();
+    for name in components.iter() {
+        normal.push('/');
+        normal.push_str(name);
+    }
+    if normal.is_empty() {
+        normal.push('/');
+    }
+    normal
+}
+
+// "path" made absolute, relative to the working directory
+pub fn absolute(path: &str) -> String {
+    normalize(&cwd(), path)
+}
+
+pub fn cwd() -> String {
+    let cwd = CWD.lock();
+    if cwd.is_empty() { String::from("/") } else { cwd.clone() }
+}
+
+// Makes directory "path" the working directory
+pub fn set_cwd(path: &str) -> Result<(), FsError> {
+    let path = absolute(path);
+    if lookup(&path)?.stat().file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    *CWD.lock() = path;
+    Ok(())
+}
+
+// The last component of an absolute path, and the path of the directory it is in
+fn split_last(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None    => ("/", path),
+    }
+}
+
+fn mount_root(mounts: &[Mount], path: &str) -> Option<Arc<dyn Inode>> {
+    mounts.iter().rev().find(|m| m.path == path).map(|m| m.fs.root())
+}
+
+// The whole contents of "inode", such as a symbolic link's target
+fn read_inode(inode: &dyn Inode) -> Result<Vec<u8>, FsError> {
+    let mut data = Vec::new();
+    data.resize(inode.stat().size, 0);
+    let mut done = 0;
+    while done < data.len() {
+        match inode.read_at(done, &mut data[done..])? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+// The inode at "path", following symbolic links on the way and, if "follow", at the end
+fn resolve(path: &str, follow: bool) -> Result<Arc<dyn Inode>, FsError> {
+    let mut path = absolute(path);
+    let mut links = 0;
+
+    // Each symbolic link followed rewrites the path, which is then walked again from the root
+    'walk: loop {
+        let mounts = MOUNTS.read();
+        let mut inode = mount_root(&mounts, "/").ok_or(FsError::NotFound)?;
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let mut walked = String::new();
+
+        for (i, name) in components.iter().enumerate() {
+            walked.push('/');
+            walked.push_str(name);
+            let next = match mount_root(&mounts, &walked) {
+                Some(root) => root,
+                None       => inode.lookup(name)?,
+            };
+
+            let last = i + 1 == components.len();
+            if next.stat().file_type == FileType::Symlink && (follow || !last) {
+                links += 1;
+                if links > MAX_LINKS {
+                    return Err(FsError::TooManyLinks);
+                }
+                let target = read_inode(&*next)?;
+                let target = str::from_utf8(&target).map_err(|_| FsError::NotFound)?;
+                let (dir, _) = split_last(&walked);
+                let mut rewritten = String::from(target);
+                for rest in components[i + 1..].iter() {
+                    rewritten.push('/');
+                    rewritten.push_str(rest);
+                }
+                path = normalize(dir, &rewritten);
+                continue 'walk;
+            }
+            inode = next;
+        }
+        return Ok(inode);
+    }
+}
+
+/*
+ * Mounts
+ */
+
+// Mounts "fs" at "path": "/", or a directory or a path that does not exist yet, in a directory
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = absolute(path);
+    if MOUNTS.read().iter().any(|m| m.path == path) {
+        return Err(FsError::Busy);
+    }
+    if path != "/" {
+        let (dir, _) = split_last(&path);
+        if stat(dir)?.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        match stat(&path) {
+            Ok(stat) if stat.file_type != FileType::Directory => return Err(FsError::NotADirectory),
+            Ok(_) | Err(FsError::NotFound)                    => {},
+            Err(e)                                            => return Err(e),
+        }
+    }
+
+    // Checked again with the lock held for writing, as another mount may have come in meanwhile
+    let name = fs.name();
+    {
+        let mut mounts = MOUNTS.write();
+        if mounts.iter().any(|m| m.path == path) {
+            return Err(FsError::Busy);
+        }
+        mounts.push(Mount { path: path.clone(), fs: fs });
+    }
+    kinfo!("fs: mounted {} at {}", name, path);
+    Ok(())
+}
+
+// The mount points and the type of filesystem mounted at each, in the order they were mounted
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.read().iter().map(|m| (m.path.clone(), m.fs.name())).collect()
+}
+
+/*
+ * Operations on paths
+ */
+
+// The inode at "path", following symbolic links
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    resolve(path, true)
+}
+
+pub fn stat(path: &str) -> Result<Stat, FsError> {
+    Ok(lookup(path)?.stat())
+}
+
+// Like stat(), but describes a symbolic link itself rather than what it points to
+pub fn lstat(path: &str) -> Result<Stat, FsError> {
+    Ok(resolve(path, false)?.stat())
+}
+
+// The target of the symbolic link at "path"
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    let inode = resolve(path, false)?;
+    if inode.stat().file_type != FileType::Symlink {
+        return Err(FsError::InvalidArgument);
+    }
+    String::from_utf8(read_inode(&*inode)?).map_err(|_| FsError::InvalidArgument)
+}
+
+// The entries of directory "path", including the mount points in it
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let path = absolute(path);
+    list_dir(&*lookup(&path)?, &path)
+}
+
+// The entries of directory "dir", found at the absolute path "path", and the mount points in it.
+// Both read_dir() and File::read_dir() list directories through here.
+fn list_dir(dir: &dyn Inode, path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let mut entries = dir.read_dir()?;
+    for m in MOUNTS.read().iter().filter(|m| m.path != "/") {
+        let (parent, name) = split_last(&m.path);
+        if parent == path && !entries.iter().any(|e| e.name == name) {
+            entries.push(DirEntry { name: String::from(name), file_type: FileType::Directory });
+        }
+    }
+    Ok(entries)
+}
+
+// Opens "path" for reading, writing or both ("flags" is O_RDONLY, O_WRONLY or O_RDWR)
+pub fn open(path: &str, flags: usize) -> Result<Arc<File>, FsError> {
+    if flags & !O_ACCMODE != 0 || flags & O_ACCMODE == O_ACCMODE {
+        return Err(FsError::InvalidArgument);
+    }
+    let path = absolute(path);
+    Ok(Arc::new(File::new(lookup(&path)?, flags, path)))
+}
+
+// The whole contents of the file at "path"
+pub fn read_all(path: &str) -> Result<Vec<u8>, FsError> {
+    let inode = lookup(path)?;
+    match inode.stat().file_type {
+        FileType::Directory => Err(FsError::IsADirectory),
+        _                   => read_inode(&*inode),
+    }
+}
+
+/*
+ * Shell commands
+ */
+
+pub fn register_commands() {
+    shell::register("ls",    "[<path>]",         "List a directory, or describe a file",              cmd_ls);
+    shell::register("cat",   "<path>...",        "Print files",                                       cmd_cat);
+    shell::register("cd",    "[<path>]",         "Change the working directory (to / by default)",    cmd_cd);
+    shell::register("pwd",   "",                 "Print the working directory",                       cmd_pwd);
+    shell::register("mount", "[<type> <path>]",  "Mount a filesystem, or list the mounts and types",  cmd_mount);
+}
+
+fn type_char(t: FileType) -> char {
+    match t {
+        FileType::File      => '-',
+        FileType::Directory => 'd',
+        FileType::Symlink   => 'l',
+        FileType::Device    => 'c',
+        FileType::Other     => '?',
+    }
+}
+
+// Prints one line of ls for the entry called "name" at "path"
+fn ls_line(path: &str, name: &str) {
+    let stat = match lstat(path) {
+        Ok(stat) => stat,
+        Err(e)   => {
+            kprintln!("? {:>12} {}: {:?}", "", name, e);
+            return;
+        },
+    };
+
+    let t = stat.file_type;
+    kprint!("{}{:03o} {:>8} {}", type_char(t), stat.mode & 0o777, stat.size, name);
+    match t {
+        FileType::Directory => kprintln!("/"),
+        FileType::Symlink   => kprintln!(" -> {}", read_link(path).unwrap_or_else(|_| String::from("?"))),
+        _                   => kprintln!(),
+    }
+}
+
+fn cmd_ls(args: &[&str]) -> i32 {
+    let path = absolute(args.get(1).map_or(".", |p| *p));
+    let stat = match stat(&path) {
+        Ok(stat) => stat,
+        Err(e)   => {
+            shell::error(format_args!("ls: {}: {:?}", path, e));
+            return shell::STATUS_FAILED;
+        },
+    };
+
+    if stat.file_type != FileType::Directory {
+        ls_line(&path, split_last(&path).1);
+        return shell::STATUS_OK;
+    }
+
+    let entries = match read_dir(&path) {
+        Ok(entries) => entries,
+        Err(e)      => {
+            shell::error(format_args!("ls: {}: {:?}", path, e));
+            return shell::STATUS_FAILED;
+        },
+    };
+    for e in entries.iter() {
+        ls_line(&normalize(&path, &e.name), &e.name);
+    }
+    shell::STATUS_OK
+}
+
+fn cmd_cat(args: &[&str]) -> i32 {
+    if args.len() < 2 {
+        return shell::usage(args[0]);
+    }
+
+    let mut buf = [0u8; 512];
+    for path in args[1..].iter() {
+        let file = match open(path, O_RDONLY) {
+            Ok(file) => file,
+            Err(e)   => {
+                shell::error(format_args!("cat: {}: {:?}", path, e));
+                return shell::STATUS_FAILED;
+            },
+        };
+
+        loop {
+            let data = match file.read(&mut buf) {
+                Ok(0)  => break,
+                Ok(n)  => &buf[..n],
+                Err(e) => {
+                    shell::error(format_args!("cat: {}: {:?}", path, e));
+                    return shell::STATUS_FAILED;
+                },
+            };
+            match str::from_utf8(data) {
+                Ok(text) => kprint!("{}", text),
+                Err(_)   => for &ch in data {
+                    kprint!("{}", ch as char);
+                },
+            }
+        }
+    }
+    shell::STATUS_OK
+}
+
+fn cmd_cd(args: &[&str]) -> i32 {
+    let path = args.get(1).map_or("/", |p| *p);
+    match set_cwd(path) {
+        Ok(()) => shell::STATUS_OK,
+        Err(e)  => {
+            shell::error(format_args!("cd: {}: {:?}", path, e));
+            shell::STATUS_FAILED
+        },
+    }
+}
+
+fn cmd_pwd(_args: &[&str]) -> i32 {
+    kprintln!("{}", cwd());
+    shell::STATUS_OK
+}
+
+fn cmd_mount(args: &[&str]) -> i32 {
+    match args.len() {
+        1 => {
+            for (path, name) in mounts().iter() {
+                kprintln!("{:<16} {}", path, name);
+            }
+            let types: Vec<&str> = FS_TYPES.iter().map(|t| t.0).collect();
+            kprintln!("types: {}", types.join(", "));
+            return shell::STATUS_OK;
+        },
+        3 => {},
+        _ => return shell::usage(args[0]),
+    }
+
+    let create = match FS_TYPES.iter().find(|t| t.0 == args[1]) {
+        Some(&(_, create)) => create,
+        None               => {
+            shell::error(format_args!("mount: no filesystem type called {}", args[1]));
+            return shell::STATUS_FAILED;
+        },
+    };
+    match mount(args[2], create()) {
+        Ok(()) => shell::STATUS_OK,
+        Err(e)  => {
+            shell::error(format_args!("mount: {}: {:?}", args[2], e));
+            shell::STATUS_FAILED
+        },
+    }
+}
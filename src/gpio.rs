@@ -0,0 +1,59 @@
+use crate::mmio::{self, Mmio};
+use crate::sync::SpinLock;
+
+/*
+ * BCM2835 GPIO pins. Each pin's function is a 3-bit field in one of the function select
+ * registers, ten pins to a register, so changing it is a read-modify-write done under FSEL_LOCK.
+ * Outputs are set and cleared through write-only registers, which need no lock.
+ */
+
+pub const PINS: usize = 54;
+
+// The green activity LED on the Pi 2 (lit when high)
+pub const ACT_LED: usize = 47;
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Function {
+    Input  = 0,
+    Output = 1,
+    Alt0   = 4,
+    Alt1   = 5,
+    Alt2   = 6,
+    Alt3   = 7,
+    Alt4   = 3,
+    Alt5   = 2,
+}
+
+static FSEL_LOCK: SpinLock<()> = SpinLock::new(());
+
+// The register for "pin" in a bank of one-bit-per-pin registers starting at "base", and its bit
+fn bank(base: usize, pin: usize) -> (usize, u32) {
+    assert!(pin < PINS, "gpio: no pin {}", pin);
+    (base + (pin / 32) * 4, 1 << (pin % 32))
+}
+
+pub fn set_function(pin: usize, function: Function) {
+    assert!(pin < PINS, "gpio: no pin {}", pin);
+    let reg   = mmio::GPFSEL0 + (pin / 10) * 4;
+    let shift = (pin % 10) * 3;
+
+    let _lock   = FSEL_LOCK.lock();
+    let _access = Mmio::enter();
+    let val = Mmio::read(reg) & !(7 << shift);
+    Mmio::write(reg, val | (function as u32) << shift);
+}
+
+// Drives an output pin high or low
+pub fn set(pin: usize, high: bool) {
+    let (reg, bit) = bank(if high { mmio::GPSET0 } else { mmio::GPCLR0 }, pin);
+    let _access = Mmio::enter();
+    Mmio::write(reg, bit);
+}
+
+// The level of a pin, whatever its function
+pub fn get(pin: usize) -> bool {
+    let (reg, bit) = bank(mmio::GPLEV0, pin);
+    let _access = Mmio::enter();
+    Mmio::read(reg) & bit != 0
+}
@@ -0,0 +1,158 @@
+use core::alloc::Layout;
+use core::ptr;
+
+use super::{HeapStrategy, HEAP_ALIGN};
+
+/*
+ * Binary buddy allocator. Blocks are powers of two from 2^MIN_ORDER bytes upwards, aligned to their
+ * size relative to the heap start, with one free list per order. A freed block is merged with its
+ * buddy (the block it was split from) whenever that is free too.
+ *
+ * Block arithmetic is done on offsets from the heap start: the heap may sit at the top of the
+ * address space, where the address just past a block may not fit in a usize.
+ */
+
+const MIN_ORDER: usize = 4; // 16 bytes
+const ORDERS:    usize = 32;
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+pub struct BuddyHeap {
+    base:       usize,
+    size:       usize,
+    free_lists: [*mut FreeBlock; ORDERS],
+}
+impl BuddyHeap {
+    pub const INIT: BuddyHeap = BuddyHeap {
+        base:       0,
+        size:       0,
+        free_lists: [ptr::null_mut(); ORDERS],
+    };
+
+    // Smallest order whose blocks satisfy both the size and the alignment of the request, or ORDERS
+    // if none does
+    fn order_for(layout: &Layout) -> usize {
+        let size = if layout.size() > layout.align() { layout.size() } else { layout.align() };
+        let mut order = MIN_ORDER;
+        while order < ORDERS && (1 << order) < size {
+            order += 1;
+        }
+        order
+    }
+
+    unsafe fn push(&mut self, order: usize, addr: usize) {
+        let block = addr as *mut FreeBlock;
+        (*block).next = self.free_lists[order];
+        self.free_lists[order] = block;
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order];
+        if block.is_null() {
+            None
+        } else {
+            self.free_lists[order] = (*block).next;
+            Some(block as usize)
+        }
+    }
+
+    // Removes a specific block from a free list, returning false if it is not there
+    unsafe fn remove(&mut self, order: usize, addr: usize) -> bool {
+        let mut link: *mut *mut FreeBlock = &mut self.free_lists[order];
+        while !(*link).is_null() {
+            if *link as usize == addr {
+                *link = (**link).next;
+                return true;
+            }
+            link = &mut (**link).next;
+        }
+        false
+    }
+}
+
+impl HeapStrategy for BuddyHeap {
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        // The region cannot run past the top of the address space
+        let size = if start == 0 { size } else { size.min(start.wrapping_neg()) };
+        self.base = start;
+        self.size = size;
+
+        // Carve the region into the largest blocks that are aligned (relative to base) and fit
+        let mut offset = 0;
+        while size - offset >= 1 << MIN_ORDER {
+            let mut order = ORDERS - 1;
+            while order > MIN_ORDER && (offset & ((1 << order) - 1) != 0 || size - offset < 1 << order) {
+                order -= 1;
+            }
+            self.push(order, self.base + offset);
+            offset += 1 << order;
+        }
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        // Blocks are only aligned relative to the heap start
+        if layout.align() > HEAP_ALIGN {
+            return ptr::null_mut();
+        }
+
+        let order = BuddyHeap::order_for(&layout);
+        if order >= ORDERS {
+            return ptr::null_mut();
+        }
+
+        // Find the smallest free block that is big enough, then split it down to size, freeing
+        // the upper half at each step
+        let mut found = order;
+        while found < ORDERS && self.free_lists[found].is_null() {
+            found += 1;
+        }
+        if found == ORDERS {
+            return ptr::null_mut();
+        }
+
+        let addr = self.pop(found).unwrap();
+        while found > order {
+            found -= 1;
+            self.push(found, addr + (1 << found));
+        }
+
+        addr as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut order  = BuddyHeap::order_for(&layout);
+        let mut offset = ptr as usize - self.base;
+
+        while order < ORDERS - 1 {
+            let buddy = offset ^ (1 << order);
+            if buddy >= self.size || self.size - buddy < 1 << order || !self.remove(order, self.base + buddy) {
+                break;
+            }
+            if buddy < offset {
+                offset = buddy;
+            }
+            order += 1;
+        }
+
+        self.push(order, self.base + offset);
+    }
+
+    fn free_space(&self) -> (usize, usize) {
+        let (mut free, mut largest) = (0, 0);
+        for order in 0..ORDERS {
+            let mut block = self.free_lists[order];
+            while !block.is_null() {
+                free += 1 << order;
+                largest = 1 << order;
+                block = unsafe { (*block).next };
+            }
+        }
+        (free, largest)
+    }
+
+    fn name(&self) -> &'static str {
+        "buddy"
+    }
+}
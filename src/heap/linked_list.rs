@@ -0,0 +1,124 @@
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+
+use super::HeapStrategy;
+
+/*
+ * First-fit allocator over an address-ordered list of free blocks. Each free block starts with a
+ * Node recording its size. Freed blocks are merged with adjacent free neighbours.
+ */
+
+struct Node {
+    size: usize,
+    next: *mut Node,
+}
+
+// Smallest block that can be tracked once freed; all block sizes are multiples of this
+const MIN_BLOCK: usize = 8;
+
+pub struct LinkedListHeap {
+    head: Node, // Dummy node; head.next is the lowest free block
+}
+impl LinkedListHeap {
+    pub const INIT: LinkedListHeap = LinkedListHeap {
+        head: Node { size: 0, next: ptr::null_mut() },
+    };
+
+    // Rounds a request up to the size actually taken from the heap
+    fn block_size(layout: &Layout) -> usize {
+        let size = if layout.size() < MIN_BLOCK { MIN_BLOCK } else { layout.size() };
+        (size + MIN_BLOCK - 1) & !(MIN_BLOCK - 1)
+    }
+
+    // Inserts a free block, keeping the list sorted by address and merging neighbours
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut Node = &mut self.head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+            prev = (*prev).next;
+        }
+
+        let node = addr as *mut Node;
+        ptr::write(node, Node { size: size, next: (*prev).next });
+        (*prev).next = node;
+
+        // Merge with the following block
+        let next = (*node).next;
+        if !next.is_null() && addr + (*node).size == next as usize {
+            (*node).size += (*next).size;
+            (*node).next  = (*next).next;
+        }
+
+        // Merge with the preceding block (never the dummy head)
+        if prev != &mut self.head as *mut Node && prev as usize + (*prev).size == addr {
+            (*prev).size += (*node).size;
+            (*prev).next  = (*node).next;
+        }
+    }
+}
+
+impl HeapStrategy for LinkedListHeap {
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        debug_assert!(mem::size_of::<Node>() <= MIN_BLOCK);
+        self.insert(start, size & !(MIN_BLOCK - 1));
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let size  = LinkedListHeap::block_size(&layout);
+        let align = if layout.align() < MIN_BLOCK { MIN_BLOCK } else { layout.align() };
+
+        let mut prev: *mut Node = &mut self.head;
+        while !(*prev).next.is_null() {
+            let block = (*prev).next;
+            let block_start = block as usize;
+            let block_end   = block_start + (*block).size;
+
+            // Any padding in front of the allocation must be big enough to stay a free block
+            let mut alloc_start = (block_start + align - 1) & !(align - 1);
+            if alloc_start != block_start && alloc_start - block_start < MIN_BLOCK {
+                alloc_start += align;
+            }
+
+            if alloc_start + size <= block_end {
+                // Unlink the block, then give back whatever is left either side of the allocation
+                (*prev).next = (*block).next;
+
+                if alloc_start > block_start {
+                    self.insert(block_start, alloc_start - block_start);
+                }
+                if alloc_start + size < block_end {
+                    self.insert(alloc_start + size, block_end - (alloc_start + size));
+                }
+
+                return alloc_start as *mut u8;
+            }
+
+            prev = block;
+        }
+
+        ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.insert(ptr as usize, LinkedListHeap::block_size(&layout));
+    }
+
+    fn free_space(&self) -> (usize, usize) {
+        let (mut free, mut largest) = (0, 0);
+        let mut node = self.head.next;
+        while !node.is_null() {
+            unsafe {
+                free += (*node).size;
+                if (*node).size > largest {
+                    largest = (*node).size;
+                }
+                node = (*node).next;
+            }
+        }
+        (free, largest)
+    }
+
+    fn name(&self) -> &'static str {
+        "linked list (first fit)"
+    }
+}
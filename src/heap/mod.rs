@@ -0,0 +1,192 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::mailbox;
+use crate::mmu;
+use crate::shell;
+use crate::sync::IrqSpinLock;
+
+/*
+ * Kernel heap. The global allocator manages HEAP_SIZE bytes from __heap_start (see linker.ld),
+ * capped at the end of ARM RAM as reported by the mailbox; the rest of RAM is left to the frame
+ * allocator. The allocation strategy is chosen at build time: a first-fit linked list by default,
+ * or a buddy allocator ("heap-buddy") or TLSF ("heap-tlsf") allocator.
+ */
+
+#[cfg(not(any(feature = "heap-buddy", feature = "heap-tlsf")))]
+mod linked_list;
+#[cfg(not(any(feature = "heap-buddy", feature = "heap-tlsf")))]
+use self::linked_list::LinkedListHeap as Strategy;
+
+#[cfg(feature = "heap-buddy")]
+mod buddy;
+#[cfg(feature = "heap-buddy")]
+use self::buddy::BuddyHeap as Strategy;
+
+#[cfg(all(feature = "heap-tlsf", not(feature = "heap-buddy")))]
+mod tlsf;
+#[cfg(all(feature = "heap-tlsf", not(feature = "heap-buddy")))]
+use self::tlsf::TlsfHeap as Strategy;
+
+// Linker symbols
+extern {
+    static __heap_start: u32;
+}
+
+// Heap start alignment; also the largest alignment the buddy strategy can honour
+pub const HEAP_ALIGN: usize = 4096;
+
+// Size of the kernel heap
+pub const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+// Used when the mailbox cannot tell us how much RAM the ARM has (the smallest Pi 2 split)
+pub const FALLBACK_RAM_END: usize = 0x10000000;
+
+// Interface implemented by each allocation strategy
+trait HeapStrategy {
+    // Takes ownership of [start, start + size). Called once, before any allocation.
+    unsafe fn init(&mut self, start: usize, size: usize);
+
+    // Returns null if the request cannot be satisfied
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8;
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+
+    // Total free bytes and the size of the largest free block
+    fn free_space(&self) -> (usize, usize);
+
+    fn name(&self) -> &'static str;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats {
+    pub start:        usize,
+    pub end:          usize,
+    pub used:         usize, // Bytes currently allocated (as requested)
+    pub high_water:   usize, // Largest value "used" has reached
+    pub free:         usize, // Bytes available to the strategy
+    pub largest_free: usize, // Largest single free block
+    pub allocations:  usize, // Live allocations
+}
+impl HeapStats {
+    // Percentage of free memory that is not part of the largest free block
+    pub fn fragmentation(&self) -> usize {
+        if self.free == 0 {
+            0
+        } else {
+            100 - self.largest_free * 100 / self.free
+        }
+    }
+}
+
+struct State {
+    strategy:    Strategy,
+    start:       usize,
+    end:         usize,
+    used:        usize,
+    high_water:  usize,
+    allocations: usize,
+}
+
+// The strategies keep raw pointers into the heap region, which only they touch
+unsafe impl Send for State {}
+
+// Allocations may come from any core, and from interrupt handlers
+pub struct KernelHeap {
+    state: IrqSpinLock<State>,
+}
+
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap {
+    state: IrqSpinLock::new(State {
+        strategy:    Strategy::INIT,
+        start:       0,
+        end:         0,
+        used:        0,
+        high_water:  0,
+        allocations: 0,
+    }),
+};
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut state = self.state.lock();
+        if state.end == 0 {
+            return ptr::null_mut();
+        }
+
+        let ptr = state.strategy.alloc(layout);
+        if !ptr.is_null() {
+            state.used += layout.size();
+            state.allocations += 1;
+            if state.used > state.high_water {
+                state.high_water = state.used;
+            }
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut state = self.state.lock();
+        state.strategy.dealloc(ptr, layout);
+        state.used -= layout.size();
+        state.allocations -= 1;
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// Sets up the heap. Must be called before anything allocates.
+pub fn init() {
+    let start = align_up(unsafe { &__heap_start as *const u32 as usize }, HEAP_ALIGN);
+    let ram_end = mmu::phys_to_virt(match mailbox::get_arm_memory() {
+        Some((base, size)) => base + size,
+        None               => FALLBACK_RAM_END,
+    });
+    let end = if start + HEAP_SIZE < ram_end { start + HEAP_SIZE } else { ram_end };
+
+    let mut state = HEAP.state.lock();
+    unsafe { state.strategy.init(start, end - start); }
+    state.start = start;
+    state.end   = end;
+}
+
+// The (virtual) memory range managed by the heap
+pub fn region() -> (usize, usize) {
+    let state = HEAP.state.lock();
+    (state.start, state.end)
+}
+
+pub fn stats() -> HeapStats {
+    let state = HEAP.state.lock();
+    let (free, largest_free) = state.strategy.free_space();
+
+    HeapStats {
+        start:        state.start,
+        end:          state.end,
+        used:         state.used,
+        high_water:   state.high_water,
+        free:         free,
+        largest_free: largest_free,
+        allocations:  state.allocations,
+    }
+}
+
+pub fn register_commands() {
+    shell::register("heap", "", "Show kernel heap statistics", cmd_heap);
+}
+
+fn cmd_heap(_args: &[&str]) -> i32 {
+    let s = stats();
+    let name = HEAP.state.lock().strategy.name();
+
+    kprintln!("Strategy:      {}", name);
+    kprintln!("Region:        {:#010x} - {:#010x} ({} KiB)", s.start, s.end, (s.end - s.start) / 1024);
+    kprintln!("Used:          {} bytes in {} allocation(s)", s.used, s.allocations);
+    kprintln!("High water:    {} bytes", s.high_water);
+    kprintln!("Free:          {} bytes (largest block {} bytes)", s.free, s.largest_free);
+    kprintln!("Fragmentation: {}%", s.fragmentation());
+    shell::STATUS_OK
+}
@@ -0,0 +1,286 @@
+use core::alloc::Layout;
+use core::ptr;
+
+use super::HeapStrategy;
+
+/*
+ * Two-Level Segregated Fit allocator. Free blocks are binned by size: the first level is the
+ * power of two below the size and the second level splits each power of two into SL_COUNT ranges.
+ * Bitmaps over both levels find a suitable non-empty bin in constant time. Every block (free or
+ * used) has a header linking it to its physical predecessor, so freed blocks merge with free
+ * neighbours immediately.
+ */
+
+const ALIGN:         usize = 8;
+const SL_LOG2:       usize = 4;
+const SL_COUNT:      usize = 1 << SL_LOG2;
+const FL_SHIFT:      usize = SL_LOG2 + 3;      // log2(ALIGN) = 3
+const SMALL_BLOCK:   usize = 1 << FL_SHIFT;    // Sizes below this all live in first level 0
+const FL_COUNT:      usize = 32 - FL_SHIFT + 1;
+
+// Block header. next_free/prev_free overlay the start of the payload and are only valid while the
+// block is free.
+struct Block {
+    prev_phys: *mut Block, // Physically preceding block (null for the first)
+    size:      usize,      // Payload size; bit 0 set while the block is free
+    next_free: *mut Block,
+    prev_free: *mut Block,
+}
+
+const HEADER:      usize = 8;           // prev_phys + size
+const MIN_PAYLOAD: usize = 8;           // Room for the free list links
+const MIN_BLOCK:   usize = HEADER + MIN_PAYLOAD;
+const FREE_BIT:    usize = 1;
+
+impl Block {
+    unsafe fn payload_size(b: *mut Block) -> usize {
+        (*b).size & !FREE_BIT
+    }
+
+    unsafe fn is_free(b: *mut Block) -> bool {
+        (*b).size & FREE_BIT != 0
+    }
+
+    unsafe fn set_free(b: *mut Block, free: bool) {
+        if free { (*b).size |= FREE_BIT; } else { (*b).size &= !FREE_BIT; }
+    }
+
+    unsafe fn payload(b: *mut Block) -> *mut u8 {
+        (b as usize + HEADER) as *mut u8
+    }
+
+    unsafe fn from_payload(p: *mut u8) -> *mut Block {
+        (p as usize - HEADER) as *mut Block
+    }
+
+    unsafe fn next_phys(b: *mut Block) -> *mut Block {
+        (b as usize + HEADER + Block::payload_size(b)) as *mut Block
+    }
+}
+
+// Index of the most significant set bit
+fn fls(x: usize) -> usize {
+    31 - (x as u32).leading_zeros() as usize
+}
+
+// Bin holding blocks of exactly "size"
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK {
+        (0, size / (SMALL_BLOCK / SL_COUNT))
+    } else {
+        let f = fls(size);
+        ((f - (FL_SHIFT - 1)), (size >> (f - SL_LOG2)) ^ SL_COUNT)
+    }
+}
+
+// First bin whose blocks are all at least "size"
+fn mapping_search(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK {
+        mapping(size)
+    } else {
+        mapping(size + (1 << (fls(size) - SL_LOG2)) - 1)
+    }
+}
+
+// Bits of a bitmap at or above "bit"
+fn mask_from(bit: usize) -> u32 {
+    (!0u32).checked_shl(bit as u32).unwrap_or(0)
+}
+
+pub struct TlsfHeap {
+    fl_bitmap: u32,
+    sl_bitmap: [u32; FL_COUNT],
+    bins:      [[*mut Block; SL_COUNT]; FL_COUNT],
+    first:     *mut Block,
+}
+impl TlsfHeap {
+    pub const INIT: TlsfHeap = TlsfHeap {
+        fl_bitmap: 0,
+        sl_bitmap: [0; FL_COUNT],
+        bins:      [[ptr::null_mut(); SL_COUNT]; FL_COUNT],
+        first:     ptr::null_mut(),
+    };
+
+    unsafe fn insert(&mut self, b: *mut Block) {
+        let (fl, sl) = mapping(Block::payload_size(b));
+        let head = self.bins[fl][sl];
+
+        (*b).next_free = head;
+        (*b).prev_free = ptr::null_mut();
+        if !head.is_null() {
+            (*head).prev_free = b;
+        }
+        self.bins[fl][sl] = b;
+
+        self.fl_bitmap     |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+        Block::set_free(b, true);
+    }
+
+    unsafe fn remove(&mut self, b: *mut Block) {
+        let (fl, sl) = mapping(Block::payload_size(b));
+        let (next, prev) = ((*b).next_free, (*b).prev_free);
+
+        if !next.is_null() {
+            (*next).prev_free = prev;
+        }
+        if !prev.is_null() {
+            (*prev).next_free = next;
+        } else {
+            self.bins[fl][sl] = next;
+            if next.is_null() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+        Block::set_free(b, false);
+    }
+
+    // Finds a free block with a payload of at least "size", or null
+    unsafe fn find(&self, size: usize) -> *mut Block {
+        let (mut fl, sl) = mapping_search(size);
+        if fl >= FL_COUNT {
+            return ptr::null_mut();
+        }
+
+        let mut sl_map = self.sl_bitmap[fl] & mask_from(sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & mask_from(fl + 1);
+            if fl_map == 0 {
+                return ptr::null_mut();
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+        }
+
+        self.bins[fl][sl_map.trailing_zeros() as usize]
+    }
+
+    // Splits "b" (not in a free list) so that its payload is "size" bytes, returning the remainder
+    // to the free lists if it is big enough to be a block of its own
+    unsafe fn trim(&mut self, b: *mut Block, size: usize) {
+        let total = Block::payload_size(b);
+        if total < size + MIN_BLOCK {
+            return;
+        }
+
+        let rest = (b as usize + HEADER + size) as *mut Block;
+        (*rest).prev_phys = b;
+        (*rest).size      = total - size - HEADER;
+        (*Block::next_phys(rest)).prev_phys = rest;
+        (*b).size = size | ((*b).size & FREE_BIT);
+
+        self.release(rest);
+    }
+
+    // Merges a block that is not in any free list with its free neighbours and files the result
+    unsafe fn release(&mut self, mut b: *mut Block) {
+        let next = Block::next_phys(b);
+        if Block::is_free(next) {
+            self.remove(next);
+            (*b).size += HEADER + Block::payload_size(next);
+            (*Block::next_phys(b)).prev_phys = b;
+        }
+
+        let prev = (*b).prev_phys;
+        if !prev.is_null() && Block::is_free(prev) {
+            self.remove(prev);
+            (*prev).size += HEADER + Block::payload_size(b);
+            (*Block::next_phys(prev)).prev_phys = prev;
+            b = prev;
+        }
+
+        self.insert(b);
+    }
+}
+
+impl HeapStrategy for TlsfHeap {
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        // One free block covering the region, followed by a zero-sized used sentinel so that
+        // next_phys() of the last real block is always valid
+        let start = (start + ALIGN - 1) & !(ALIGN - 1);
+        let end   = (start + size) & !(ALIGN - 1);
+
+        let first = start as *mut Block;
+        (*first).prev_phys = ptr::null_mut();
+        (*first).size      = end - start - 2 * HEADER;
+
+        let sentinel = Block::next_phys(first);
+        (*sentinel).prev_phys = first;
+        (*sentinel).size      = 0;
+
+        self.first = first;
+        self.insert(first);
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let size = if layout.size() < MIN_PAYLOAD { MIN_PAYLOAD } else { layout.size() };
+        let size = (size + ALIGN - 1) & !(ALIGN - 1);
+        let align = layout.align();
+
+        // Over-aligned requests need room to split off a leading free block
+        let search = if align > ALIGN { size + align + MIN_BLOCK } else { size };
+
+        let mut b = self.find(search);
+        if b.is_null() {
+            return ptr::null_mut();
+        }
+        self.remove(b);
+
+        if align > ALIGN {
+            let payload = Block::payload(b) as usize;
+            let mut aligned = (payload + align - 1) & !(align - 1);
+            if aligned != payload && aligned - payload < MIN_BLOCK {
+                aligned = (payload + MIN_BLOCK + align - 1) & !(align - 1);
+            }
+
+            if aligned != payload {
+                // The gap in front becomes a free block of its own
+                let total   = Block::payload_size(b);
+                let gap     = aligned - payload;
+                let moved   = Block::from_payload(aligned as *mut u8);
+                (*moved).prev_phys = b;
+                (*moved).size      = total - gap;
+                (*Block::next_phys(moved)).prev_phys = moved;
+                (*b).size = gap - HEADER;
+
+                self.release(b);
+                b = moved;
+            }
+        }
+
+        self.trim(b, size);
+        Block::payload(b)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, _layout: Layout) {
+        self.release(Block::from_payload(ptr));
+    }
+
+    fn free_space(&self) -> (usize, usize) {
+        let (mut free, mut largest) = (0, 0);
+        if self.first.is_null() {
+            return (0, 0);
+        }
+
+        unsafe {
+            let mut b = self.first;
+            while Block::payload_size(b) > 0 {
+                if Block::is_free(b) {
+                    free += Block::payload_size(b);
+                    if Block::payload_size(b) > largest {
+                        largest = Block::payload_size(b);
+                    }
+                }
+                b = Block::next_phys(b);
+            }
+        }
+        (free, largest)
+    }
+
+    fn name(&self) -> &'static str {
+        "TLSF"
+    }
+}
@@ -0,0 +1,321 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cache;
+use crate::cpu;
+use crate::irq;
+use crate::mmio::{self, Mmio};
+use crate::mmu;
+use crate::shell;
+use crate::smp;
+use crate::sync::{IrqSpinLock, MpscQueue, Once};
+use crate::timer::Timer;
+
+/*
+ * Inter-processor interrupts, through the BCM2836 local mailboxes. Writing bits to a mailbox's set
+ * register raises a local interrupt on the core that owns it, which acknowledges them through the
+ * clear register. Each core's IPI_MAILBOX carries:
+ *  - doorbells (bits 0 - 15): call the handler registered for the bell, with nothing attached
+ *  - MSG_CALL: run the functions waiting in the core's call queue (cross-calls)
+ *  - MSG_STOP: halt, for panics
+ * Cross-calls are built on a lock-free MpscQueue per core, and TLB and cache shootdowns on
+ * cross-calls. Mailbox 3 is left alone, as the firmware uses it to start the cores.
+ *
+ * Senders also signal an event, so that a core waiting in WFE with IRQs masked (in call() below,
+ * or for a lock) notices calls aimed at it.
+ */
+
+const IPI_MAILBOX: usize = 0;
+const IPI_IRQ:     usize = irq::LOCAL_MAILBOX0 + IPI_MAILBOX;
+
+pub const DOORBELLS: usize = 16;
+const MSG_CALL:      u32   = 1 << 16;
+const MSG_STOP:      u32   = 1 << 17;
+
+// Cross-calls each core can have outstanding
+const CALL_QUEUE_LEN: usize = 32;
+
+// Round trips timed by "ipi ping"
+const PING_COUNT: usize = 1000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IpiError {
+    NoSuchCore,
+    Offline,
+    QueueFull,
+}
+
+// A function to run on another core. "pending", if set, is decremented once it has run; it lives
+// on the stack of the caller, which waits for that.
+struct Call {
+    func:    fn(usize),
+    arg:     usize,
+    pending: *const AtomicUsize,
+}
+
+unsafe impl Send for Call {}
+
+// Each core's call queue, and the interrupts each core has taken
+static CALLS:    Once<Vec<MpscQueue<Call>>> = Once::new();
+static RECEIVED: [AtomicUsize; cpu::CORES] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+static DOORBELL: IrqSpinLock<[Option<fn(usize)>; DOORBELLS]> = IrqSpinLock::new([None; DOORBELLS]);
+
+fn mailbox_set(core: usize) -> usize {
+    mmio::LOCAL_MAILBOX_SET0 + core * mmio::LOCAL_MAILBOX_CORE_STRIDE + IPI_MAILBOX * 4
+}
+
+fn mailbox_clear(core: usize) -> usize {
+    mmio::LOCAL_MAILBOX_CLR0 + core * mmio::LOCAL_MAILBOX_CORE_STRIDE + IPI_MAILBOX * 4
+}
+
+// Sets up the call queues and takes IPIs on core 0. Needs the heap and the interrupt controller.
+pub fn init() {
+    CALLS.call_once(|| (0..cpu::CORES).map(|_| MpscQueue::new(CALL_QUEUE_LEN)).collect());
+    irq::register(IPI_IRQ, handle_irq);
+    init_core();
+}
+
+// Takes IPIs on the current core; called by each secondary core as it comes up
+pub fn init_core() {
+    {
+        let _access = Mmio::enter();
+        Mmio::write(mailbox_clear(cpu::core_id()), 0xFFFFFFFF);
+    }
+    irq::enable(IPI_IRQ);
+}
+
+// Installs the handler called, in interrupt context, when "bell" is rung on a core
+pub fn register_doorbell(bell: usize, handler: fn(usize)) {
+    assert!(bell < DOORBELLS, "ipi: no doorbell {}", bell);
+    DOORBELL.lock()[bell] = Some(handler);
+}
+
+fn check_target(core: usize) -> Result<(), IpiError> {
+    if core >= cpu::CORES {
+        Err(IpiError::NoSuchCore)
+    } else if !smp::is_online(core) {
+        Err(IpiError::Offline)
+    } else {
+        Ok(())
+    }
+}
+
+fn send(core: usize, bits: u32) {
+    {
+        let _access = Mmio::enter();
+        Mmio::write(mailbox_set(core), bits);
+    }
+    cpu::send_event();
+}
+
+// Rings doorbell "bell" on "core"
+pub fn ring(core: usize, bell: usize) -> Result<(), IpiError> {
+    assert!(bell < DOORBELLS, "ipi: no doorbell {}", bell);
+    check_target(core)?;
+    send(core, 1 << bell);
+    Ok(())
+}
+
+fn queue(core: usize) -> &'static MpscQueue<Call> {
+    &CALLS.get().expect("ipi: not initialised")[core]
+}
+
+// Runs the calls queued for the current core. IRQs are masked throughout, as both the IPI handler
+// and waiting callers come here and the queue has a single consumer.
+fn run_pending() {
+    let saved = cpu::irq_save();
+    if let Some(calls) = CALLS.get() {
+        while let Some(call) = calls[cpu::core_id()].pop() {
+            (call.func)(call.arg);
+            if !call.pending.is_null() {
+                unsafe { (*call.pending).fetch_sub(1, Ordering::Release); }
+                cpu::send_event();
+            }
+        }
+    }
+    cpu::irq_restore(saved);
+}
+
+// Queues a call for "core" and interrupts it. A full queue is retried while "wait" is set.
+fn post(core: usize, mut call: Call, wait: bool) -> Result<(), IpiError> {
+    loop {
+        match queue(core).push(call) {
+            Ok(())          => break,
+            Err(_) if !wait => return Err(IpiError::QueueFull),
+            Err(back)       => {
+                call = back;
+                run_pending();
+            },
+        }
+    }
+    send(core, MSG_CALL);
+    Ok(())
+}
+
+// Waits for "pending" calls to finish, running any aimed at this core meanwhile, so that two cores
+// calling each other do not wait for each other forever
+fn wait_for(pending: &AtomicUsize) {
+    while pending.load(Ordering::Acquire) != 0 {
+        run_pending();
+        cpu::wait_for_event();
+    }
+}
+
+// Runs func(arg) on "core", in interrupt context, and waits for it to return. Runs it directly
+// (with IRQs masked) if "core" is the current core.
+pub fn call(core: usize, func: fn(usize), arg: usize) -> Result<(), IpiError> {
+    check_target(core)?;
+
+    if core == cpu::core_id() {
+        let saved = cpu::irq_save();
+        func(arg);
+        cpu::irq_restore(saved);
+        return Ok(());
+    }
+
+    let pending = AtomicUsize::new(1);
+    post(core, Call { func: func, arg: arg, pending: &pending }, true)?;
+    wait_for(&pending);
+    Ok(())
+}
+
+// Queues func(arg) to run on "core" without waiting for it
+pub fn call_async(core: usize, func: fn(usize), arg: usize) -> Result<(), IpiError> {
+    check_target(core)?;
+    post(core, Call { func: func, arg: arg, pending: 0 as *const AtomicUsize }, false)
+}
+
+// Runs func(arg) on every other online core and waits for all of them
+pub fn call_others(func: fn(usize), arg: usize) {
+    if CALLS.get().is_none() {
+        return;
+    }
+
+    let me = cpu::core_id();
+    let pending = AtomicUsize::new(0);
+    for core in (0..cpu::CORES).filter(|&c| c != me && smp::is_online(c)) {
+        pending.fetch_add(1, Ordering::Relaxed);
+        let _ = post(core, Call { func: func, arg: arg, pending: &pending }, true);
+    }
+    wait_for(&pending);
+}
+
+// Halts every other online core, without waiting for them. For the panic path.
+pub fn stop_others() {
+    let me = cpu::core_id();
+    for core in (0..cpu::CORES).filter(|&c| c != me && smp::is_online(c)) {
+        send(core, MSG_STOP);
+    }
+}
+
+/*
+ * Shootdowns. TLB maintenance and cache maintenance by set/way only affect the core doing them,
+ * so page table changes other cores may have cached, and whole-cache operations, are repeated on
+ * every online core. Callers must not hold an IrqSpinLock that the other cores might be waiting
+ * for with IRQs masked.
+ */
+
+fn tlb_invalidate_page(addr: usize) {
+    mmu::tlb_invalidate_page(addr);
+}
+
+fn tlb_invalidate_all(_arg: usize) {
+    mmu::tlb_invalidate_all();
+}
+
+fn cache_flush_all(_arg: usize) {
+    cache::flush_all();
+}
+
+fn icache_invalidate(_arg: usize) {
+    cache::invalidate_icache();
+}
+
+// Invalidates the TLB entries for the page at "addr" on all cores
+pub fn tlb_shootdown_page(addr: usize) {
+    tlb_invalidate_page(addr);
+    call_others(tlb_invalidate_page, addr);
+}
+
+pub fn tlb_shootdown_all() {
+    tlb_invalidate_all(0);
+    call_others(tlb_invalidate_all, 0);
+}
+
+// Writes back and discards the data cache of every core
+pub fn cache_shootdown() {
+    cache_flush_all(0);
+    call_others(cache_flush_all, 0);
+}
+
+// Discards the instruction cache of every core, after code has been written and cleaned to memory
+pub fn icache_shootdown() {
+    icache_invalidate(0);
+    call_others(icache_invalidate, 0);
+}
+
+// Mailbox interrupt: acknowledges the messages, then handles them
+fn handle_irq(_irq: usize) {
+    let core = cpu::core_id();
+    let bits = {
+        let _access = Mmio::enter();
+        let bits = Mmio::read(mailbox_clear(core));
+        Mmio::write(mailbox_clear(core), bits);
+        bits
+    };
+    RECEIVED[core].fetch_add(1, Ordering::Relaxed);
+
+    if bits & MSG_STOP != 0 {
+        cpu::halt();
+    }
+    if bits & MSG_CALL != 0 {
+        run_pending();
+    }
+
+    for bell in (0..DOORBELLS).filter(|b| bits & (1 << b) != 0) {
+        let handler = DOORBELL.lock()[bell];
+        match handler {
+            Some(handler) => handler(bell),
+            None          => kwarn!("ipi: doorbell {} rung on core {} with no handler", bell, core),
+        }
+    }
+}
+
+pub fn register_commands() {
+    shell::register("ipi", "[ping <core>]", "Show IPI counts or time cross-calls to a core", cmd_ipi);
+}
+
+fn nothing(_arg: usize) {}
+
+fn cmd_ipi(args: &[&str]) -> i32 {
+    match args.get(1) {
+        Some(&"ping") => {
+            let core = match args.get(2).and_then(|c| shell::parse_number(c)) {
+                Some(core) => core,
+                None       => return shell::usage(args[0]),
+            };
+
+            let start = Timer::now_us();
+            for _ in 0..PING_COUNT {
+                if let Err(e) = call(core, nothing, 0) {
+                    shell::error(format_args!("cannot call core {}: {:?}", core, e));
+                    return shell::STATUS_FAILED;
+                }
+            }
+            let elapsed = Timer::now_us() - start;
+            kprintln!("{} cross-calls to core {}: {} us each", PING_COUNT, core, elapsed / PING_COUNT as u64);
+            return shell::STATUS_OK;
+        },
+        Some(_) => return shell::usage(args[0]),
+        None    => {},
+    }
+
+    kprintln!("CORE  IPIS");
+    for core in (0..cpu::CORES).filter(|&c| smp::is_online(c)) {
+        kprintln!("{:<5} {}", core, RECEIVED[core].load(Ordering::Relaxed));
+    }
+    shell::STATUS_OK
+}
@@ -0,0 +1,179 @@
+use crate::cpu;
+use crate::mmio::{self, Mmio};
+use crate::sched;
+use crate::shell;
+use crate::sync::IrqSpinLock;
+
+/*
+ * Interrupt dispatch. Each core has a source register in the BCM2836 local interrupt controller
+ * with a bit for each of its own timers and mailboxes, and one for the BCM2835 ("GPU") interrupt
+ * controller, which collects the peripheral interrupts and is routed to core 0. Both are folded
+ * into one numbering:
+ *  - 0 - 63:  BCM2835 peripheral interrupts (IRQ pending 1 and 2)
+ *  - 64 - 71: local interrupts of the core taking them (LOCAL_* below)
+ * A handler is called with the number of the interrupt, with IRQs masked, and must clear the
+ * interrupt at its source. Once they have run, the interrupted thread may be preempted.
+ */
+
+pub type Handler = fn(usize);
+
+pub const GPU_IRQS:   usize = 64;
+pub const LOCAL_BASE: usize = GPU_IRQS;
+
+// Local interrupts (bits of the core's source register)
+pub const LOCAL_CNTPS:    usize = LOCAL_BASE + 0; // Secure physical timer
+pub const LOCAL_CNTPNS:   usize = LOCAL_BASE + 1; // Non-secure physical timer
+pub const LOCAL_CNTHP:    usize = LOCAL_BASE + 2; // Hypervisor timer
+pub const LOCAL_CNTV:     usize = LOCAL_BASE + 3; // Virtual timer
+pub const LOCAL_MAILBOX0: usize = LOCAL_BASE + 4; // Mailboxes 0 - 3 follow
+pub const LOCAL_IRQS:     usize = 8;
+
+pub const IRQ_COUNT: usize = LOCAL_BASE + LOCAL_IRQS;
+
+// Source register bit for the BCM2835 interrupt controller
+const SOURCE_GPU: u32 = 1 << 8;
+
+struct State {
+    handlers:    [Option<Handler>; IRQ_COUNT],
+    counts:      [u32; IRQ_COUNT],
+    // Peripheral interrupts enabled in the BCM2835 controller (it reports pending interrupts
+    // whether or not they are enabled)
+    gpu_enabled: u64,
+}
+
+// Shared by all cores; handlers are called without it held
+static STATE: IrqSpinLock<State> = IrqSpinLock::new(State {
+    handlers:    [None; IRQ_COUNT],
+    counts:      [0; IRQ_COUNT],
+    gpu_enabled: 0,
+});
+
+pub fn init() {
+    let _access = Mmio::enter();
+
+    // Everything off until a driver asks for it
+    Mmio::write(mmio::IRQ_DISABLE1, 0xFFFFFFFF);
+    Mmio::write(mmio::IRQ_DISABLE2, 0xFFFFFFFF);
+    for core in 0..cpu::CORES {
+        Mmio::write(mmio::LOCAL_TIMER_INT_CTRL0   + core * 4, 0);
+        Mmio::write(mmio::LOCAL_MAILBOX_INT_CTRL0 + core * 4, 0);
+    }
+
+    // Peripheral interrupts go to core 0 as IRQs
+    Mmio::write(mmio::LOCAL_GPU_INT_ROUTING, 0);
+}
+
+// Installs the handler for "irq". The interrupt still has to be enabled.
+pub fn register(irq: usize, handler: Handler) {
+    assert!(irq < IRQ_COUNT, "irq: no interrupt {}", irq);
+
+    STATE.lock().handlers[irq] = Some(handler);
+}
+
+// Enables "irq"; local interrupts are enabled on the current core
+pub fn enable(irq: usize) {
+    set_enabled(irq, true);
+}
+
+pub fn disable(irq: usize) {
+    set_enabled(irq, false);
+}
+
+fn set_enabled(irq: usize, on: bool) {
+    let mut state = STATE.lock();
+    let _access = Mmio::enter();
+
+    if irq < GPU_IRQS {
+        let (reg, bit) = match (irq < 32, on) {
+            (true,  true)  => (mmio::IRQ_ENABLE1,  irq),
+            (false, true)  => (mmio::IRQ_ENABLE2,  irq - 32),
+            (true,  false) => (mmio::IRQ_DISABLE1, irq),
+            (false, false) => (mmio::IRQ_DISABLE2, irq - 32),
+        };
+        Mmio::write(reg, 1 << bit);
+        if on {
+            state.gpu_enabled |= 1 << irq;
+        } else {
+            state.gpu_enabled &= !(1 << irq);
+        }
+    } else if irq < IRQ_COUNT {
+        // Each core only changes its own control registers, and does so with IRQs masked
+        let core = cpu::core_id();
+        let (reg, bit) = if irq < LOCAL_MAILBOX0 {
+            (mmio::LOCAL_TIMER_INT_CTRL0 + core * 4, irq - LOCAL_BASE)
+        } else {
+            (mmio::LOCAL_MAILBOX_INT_CTRL0 + core * 4, irq - LOCAL_MAILBOX0)
+        };
+        let val = Mmio::read(reg);
+        Mmio::write(reg, if on { val | (1 << bit) } else { val & !(1 << bit) });
+    }
+}
+
+// Called from vectors.S, with IRQs masked, when the current core takes an IRQ
+#[no_mangle]
+pub extern "C" fn irq_dispatch() {
+    let core = cpu::core_id();
+    let source = {
+        let _access = Mmio::enter();
+        Mmio::read(mmio::LOCAL_IRQ_SOURCE0 + core * 4)
+    };
+
+    for bit in 0..LOCAL_IRQS {
+        if source & (1 << bit) != 0 {
+            handle(LOCAL_BASE + bit);
+        }
+    }
+
+    if source & SOURCE_GPU != 0 {
+        let pending = {
+            let _access = Mmio::enter();
+            Mmio::read(mmio::IRQ_PENDING1) as u64 | (Mmio::read(mmio::IRQ_PENDING2) as u64) << 32
+        };
+        let pending = pending & STATE.lock().gpu_enabled;
+
+        for irq in 0..GPU_IRQS {
+            if pending & (1 << irq) != 0 {
+                handle(irq);
+            }
+        }
+    }
+
+    sched::preempt();
+}
+
+fn handle(irq: usize) {
+    let handler = {
+        let mut state = STATE.lock();
+        state.counts[irq] = state.counts[irq].wrapping_add(1);
+        state.handlers[irq]
+    };
+
+    match handler {
+        Some(handler) => handler(irq),
+        None          => {
+            // Nothing will clear it, so it would fire forever
+            disable(irq);
+            kwarn!("no handler for irq {}, disabled", irq);
+        },
+    }
+}
+
+pub fn register_commands() {
+    shell::register("irqs", "", "Show interrupt handlers and counts", cmd_irqs);
+}
+
+fn cmd_irqs(_args: &[&str]) -> i32 {
+    kprintln!("IRQ  COUNT       HANDLER");
+    for irq in 0..IRQ_COUNT {
+        let (handler, count) = {
+            let state = STATE.lock();
+            (state.handlers[irq], state.counts[irq])
+        };
+        if handler.is_none() && count == 0 {
+            continue;
+        }
+        let name = if irq < GPU_IRQS { "" } else { " (local)" };
+        kprintln!("{:<4} {:<11} {}{}", irq, count, if handler.is_some() { "yes" } else { "no" }, name);
+    }
+    shell::STATUS_OK
+}
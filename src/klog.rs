@@ -0,0 +1,342 @@
+use core::fmt::{self, Write};
+use core::str;
+
+use log;
+
+use crate::console::Console;
+use crate::shell;
+use crate::sync::IrqSpinLock;
+use crate::timer::Timer;
+
+/*
+ * Leveled kernel log. Every record that passes the filters is timestamped and appended to an
+ * in-memory ring buffer (dumped like dmesg), and records at or below the console level are also
+ * printed with kprint!(). The kerror!()..ktrace!() macros are the native interface; records from
+ * crates using the `log` facade arrive through KernelLogger.
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Error => "ERROR",
+            Level::Warn  => "WARN",
+            Level::Info  => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn"  => Some(Level::Warn),
+            "info"  => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _       => None,
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => ($crate::klog::_log($level, module_path!(), format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! kerror { ($($arg:tt)*) => (klog!($crate::klog::Level::Error, $($arg)*)); }
+#[macro_export]
+macro_rules! kwarn  { ($($arg:tt)*) => (klog!($crate::klog::Level::Warn,  $($arg)*)); }
+#[macro_export]
+macro_rules! kinfo  { ($($arg:tt)*) => (klog!($crate::klog::Level::Info,  $($arg)*)); }
+#[macro_export]
+macro_rules! kdebug { ($($arg:tt)*) => (klog!($crate::klog::Level::Debug, $($arg)*)); }
+#[macro_export]
+macro_rules! ktrace { ($($arg:tt)*) => (klog!($crate::klog::Level::Trace, $($arg)*)); }
+
+const RING_SIZE:   usize = 16 * 1024;
+const MAX_FILTERS: usize = 8;
+
+// Circular byte buffer holding formatted log lines. When full, the oldest bytes are overwritten.
+struct Ring {
+    buf:  [u8; RING_SIZE],
+    head: usize, // Next write position
+    len:  usize, // Number of valid bytes
+}
+impl Ring {
+    fn push(&mut self, byte: u8) {
+        self.buf[self.head] = byte;
+        self.head = (self.head + 1) % RING_SIZE;
+        if self.len < RING_SIZE {
+            self.len += 1;
+        }
+    }
+
+    fn wrapped(&self) -> bool {
+        self.len == RING_SIZE
+    }
+
+    fn byte(&self, i: usize) -> u8 {
+        self.buf[(self.head + RING_SIZE - self.len + i) % RING_SIZE]
+    }
+}
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.as_bytes() {
+            self.push(*b);
+        }
+        Ok(())
+    }
+}
+
+// A filter applies its level to every module whose path starts with the prefix
+#[derive(Copy, Clone)]
+struct Filter {
+    prefix: &'static str,
+    level:  Level,
+}
+
+struct State {
+    ring:          Ring,
+    default_level: Level,
+    console_level: Level,
+    filters:       [Option<Filter>; MAX_FILTERS],
+}
+
+// Any core may log, including from interrupt handlers. The lock is never held while printing.
+static STATE: IrqSpinLock<State> = IrqSpinLock::new(State {
+    ring:          Ring { buf: [0; RING_SIZE], head: 0, len: 0 },
+    default_level: Level::Info,
+    console_level: Level::Info,
+    filters:       [None; MAX_FILTERS],
+});
+
+// Bytes dump() copies out of the ring per lock
+const DUMP_CHUNK: usize = 128;
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl log::Log for KernelLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        enabled(from_log_level(metadata.level()), metadata.target())
+    }
+
+    fn log(&self, record: &log::Record) {
+        _log(from_log_level(record.level()), record.target(), *record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+fn from_log_level(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::Error,
+        log::Level::Warn  => Level::Warn,
+        log::Level::Info  => Level::Info,
+        log::Level::Debug => Level::Debug,
+        log::Level::Trace => Level::Trace,
+    }
+}
+
+// Registers the kernel logger with the `log` facade
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+}
+
+// Sets the level used for modules without a matching filter
+pub fn set_level(level: Level) {
+    STATE.lock().default_level = level;
+}
+
+// Sets the most verbose level that is also printed on the console
+pub fn set_console_level(level: Level) {
+    STATE.lock().console_level = level;
+}
+
+// Adds or replaces the filter for all modules starting with "prefix" (e.g. "os_rpi::framebuffer").
+// Returns false if the filter table is full.
+pub fn set_filter(prefix: &'static str, level: Level) -> bool {
+    let mut state = STATE.lock();
+
+    for slot in state.filters.iter_mut() {
+        if let Some(ref mut f) = *slot {
+            if f.prefix == prefix {
+                f.level = level;
+                return true;
+            }
+        }
+    }
+
+    for slot in state.filters.iter_mut() {
+        if slot.is_none() {
+            *slot = Some(Filter { prefix: prefix, level: level });
+            return true;
+        }
+    }
+
+    false
+}
+
+// Returns true if a record at "level" from "module" would be logged. The longest matching filter
+// prefix wins.
+pub fn enabled(level: Level, module: &str) -> bool {
+    let state = STATE.lock();
+    let mut max_level = state.default_level;
+    let mut best_len  = 0;
+
+    for f in state.filters.iter().filter_map(|f| f.as_ref()) {
+        if module.starts_with(f.prefix) && f.prefix.len() >= best_len {
+            max_level = f.level;
+            best_len  = f.prefix.len();
+        }
+    }
+
+    level <= max_level
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+
+    let us = Timer::now_us();
+    let (secs, micros) = (us / 1_000_000, us % 1_000_000);
+
+    let console_level = {
+        let mut state = STATE.lock();
+        let _ = write!(state.ring, "[{:5}.{:06}] {:5} {}: {}\n", secs, micros, level.name(), module, args);
+        state.console_level
+    };
+
+    if level <= console_level {
+        kprintln!("[{:5}.{:06}] {:5} {}: {}", secs, micros, level.name(), module, args);
+    }
+}
+
+// Writes the contents of the ring buffer, oldest first. If the buffer has wrapped, the first
+// (partially overwritten) line is skipped. The ring is copied out a chunk at a time so that "out"
+// is never written with the lock held; records logged meanwhile may shift or cut the output. A
+// character cut off at the end of a chunk is carried over to the next.
+pub fn dump<W: Write>(out: &mut W) -> fmt::Result {
+    let mut i = {
+        let state = STATE.lock();
+        let ring = &state.ring;
+        let mut i = 0;
+        if ring.wrapped() {
+            while i < ring.len && ring.byte(i) != b'\n' {
+                i += 1;
+            }
+            i += 1;
+        }
+        i
+    };
+
+    let mut chunk = [0u8; DUMP_CHUNK];
+    let mut carried = 0;
+    loop {
+        let count = {
+            let state = STATE.lock();
+            let ring = &state.ring;
+            let count = ring.len.saturating_sub(i).min(DUMP_CHUNK - carried);
+            for (j, b) in chunk[carried..carried + count].iter_mut().enumerate() {
+                *b = ring.byte(i + j);
+            }
+            count
+        };
+        if count == 0 {
+            break;
+        }
+
+        let len = carried + count;
+        let written = write_lossy(out, &chunk[..len])?;
+        chunk.copy_within(written..len, 0);
+        carried = len - written;
+        i += count;
+    }
+
+    // A character the ring ends part way through
+    if carried > 0 {
+        out.write_char('\u{FFFD}')?;
+    }
+    Ok(())
+}
+
+// Writes "bytes" as UTF-8, with U+FFFD in place of each invalid sequence, as from_utf8_lossy()
+// does. Stops before a sequence cut off at the end, and returns how many bytes it wrote.
+fn write_lossy<W: Write>(out: &mut W, bytes: &[u8]) -> Result<usize, fmt::Error> {
+    let mut done = 0;
+    while done < bytes.len() {
+        match str::from_utf8(&bytes[done..]) {
+            Ok(s) => {
+                out.write_str(s)?;
+                done = bytes.len();
+            },
+            Err(e) => {
+                let valid = e.valid_up_to();
+                out.write_str(unsafe { str::from_utf8_unchecked(&bytes[done..done + valid]) })?;
+                done += valid;
+                match e.error_len() {
+                    Some(len) => {
+                        out.write_char('\u{FFFD}')?;
+                        done += len;
+                    },
+                    None => break,
+                }
+            },
+        }
+    }
+    Ok(done)
+}
+
+// Empties the ring buffer
+pub fn clear() {
+    let mut state = STATE.lock();
+    state.ring.head = 0;
+    state.ring.len  = 0;
+}
+
+pub fn register_commands() {
+    shell::register("dmesg",    "[-c]",              "Print the kernel log (-c: clear it afterwards)", cmd_dmesg);
+    shell::register("loglevel", "<level> [console]", "Set the log level, or the console log level",    cmd_loglevel);
+}
+
+fn cmd_dmesg(args: &[&str]) -> i32 {
+    let clear_after = match args.get(1) {
+        Some(&"-c") => true,
+        Some(_)     => return shell::usage(args[0]),
+        None        => false,
+    };
+
+    let _ = dump(&mut Console);
+    if clear_after {
+        clear();
+    }
+    shell::STATUS_OK
+}
+
+fn cmd_loglevel(args: &[&str]) -> i32 {
+    let level = match args.get(1).and_then(|l| Level::from_name(l)) {
+        Some(l) => l,
+        None    => return shell::usage(args[0]),
+    };
+
+    match args.get(2) {
+        Some(&"console") => set_console_level(level),
+        Some(_)          => return shell::usage(args[0]),
+        None             => set_level(level),
+    }
+    shell::STATUS_OK
+}
@@ -8,67 +8,20 @@ use std::alloc::{GlobalAlloc, Layout};
 */
 
 #![no_std]
-#![feature(alloc, allocator_api, core_intrinsics, lang_items, panic_implementation)]
-
-// Linker symbols
-extern {
-    static __heap_start: u32;
-}
+#![feature(alloc_error_handler, allocator_api, core_intrinsics, panic_info_message, const_fn)]
 
 extern crate alloc;
 
 // Needed for LLVM symbols such as memcpy
 extern crate rlibc;
 
+// Logging facade used by third-party no_std crates; records are routed into klog
+extern crate log;
 
-/*
- * Use WeeAlloc as global allocator
- */
-extern crate wee_alloc;
-
-#[global_allocator]
-static GLOBAL: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 /*
- * Define a custom allocator
+ * The global allocator is the kernel heap (see heap/mod.rs)
  */
-/*
-use core::alloc::{GlobalAlloc, Layout};
-
-struct HeapAllocator;
-
-unsafe impl GlobalAlloc for HeapAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        static mut OFF: u32 = 0x0;
-        let ptr: *mut u8 = (&__heap_start + OFF) as *mut u8;
-        OFF += layout.size() as u32;
-        ptr
-    }
-
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        // TODO: implement!
-    }
-}
-
-#[global_allocator]
-static GLOBAL: HeapAllocator = HeapAllocator;
-*/
-
-use core::panic::PanicInfo;
-
-#[panic_handler]
-#[no_mangle]
-pub fn panic(_info: &PanicInfo) -> ! {
-    loop {}
-}
-
-use core::alloc::Layout;
-
-#[lang = "oom"]
-#[no_mangle]
-pub extern "C" fn oom(_layout: Layout) -> ! {
-    loop {}
-}
 
 #[no_mangle]
 pub fn __aeabi_unwind_cpp_pr0() {
@@ -80,75 +33,127 @@ pub fn __aeabi_unwind_cpp_pr1() {
     loop {}
 }
 
-use alloc::fmt::*;
-use alloc::string::String;
+use core::fmt::Write;
 
 
+#[macro_use]
+mod console;
+#[macro_use]
+mod klog;
+mod barrier;
+mod cache;
+mod cpu;
+mod dma;
+mod dmabuf;
+mod exception;
+mod executor;
 mod font8x8;
+mod frame;
 mod framebuffer;
+mod fs;
 mod gpio;
+mod heap;
+mod ipi;
+mod irq;
+mod lineedit;
+mod mailbox;
+mod memtools;
 mod mmio;
+mod mmu;
+mod panic;
+mod process;
+mod sched;
+mod shell;
+mod smp;
+mod spi;
+mod sync;
+mod tasks;
+mod timer;
 mod uart;
+mod vm;
+mod watchdog;
 
-use uart::Uart;
-use framebuffer::{FrameBuffer24, Pixel24};
-
-fn write_prompt(fb: &mut FrameBuffer24, col: &Pixel24) {
-    fb.writechar('>', col);
-    fb.writechar(' ', col);
-}
+use crate::uart::Uart;
+use crate::framebuffer::{FrameBuffer24, Pixel24, TextStyle};
 
 #[no_mangle]
 pub extern "C" fn rust_main() {
+    smp::init();
     Uart::init();
+    exception::init();
+    klog::init();
+    heap::init();
+    fs::init();
+    frame::init();
+    mmu::init();
+    irq::init();
+    ipi::init();
+    dma::init();
+    spi::init();
+    Uart::enable_interrupts();
+    sched::init();
+    executor::init();
+    cpu::enable_interrupts();
 
     let col_blue:   Pixel24 = Pixel24 {r: 100, g: 128, b: 250};
     let col_green:  Pixel24 = Pixel24 {r: 100, g: 250, b: 128};
-    let col_white:  Pixel24 = Pixel24 {r: 255, g: 255, b: 255};
 
-    Uart::puts("Initialising framebuffer... ");
+    // The banner is drawn straight over the test pattern; everything else is on black
+    let style_banner: TextStyle = TextStyle { bold: true, ..TextStyle::transparent(col_blue) };
+    let style_green:  TextStyle = TextStyle::new(col_green);
+
+    kprint!("Initialising framebuffer... ");
 
-    let mut fb_res = FrameBuffer24::new(800, 600);
+    match FrameBuffer24::new(800, 600) {
+        Ok(mut fb) => {
+            kprintln!("OK");
+
+            // Normally lies in GPU memory, above ARM RAM, in which case this is a no-op
+            let fb_start = mmu::virt_to_phys(fb.buf as usize);
+            frame::reserve("framebuffer", fb_start, fb_start + fb.size as usize);
 
-    match fb_res {
-        Ok(ref mut fb) => {
-            Uart::puts("OK\n");
-            let s = format(format_args!("{:?}", fb));
             fb.draw_test_pattern();
-            fb.write_string("-------------------------------------------------------------------------------\n",   &col_blue);
-            fb.write_string("--== Welcome to the Raspberry Pi bare-metal system, by Simon Pugnet (2018) ==--\n",   &col_blue);
-            fb.write_string("-------------------------------------------------------------------------------\n\n", &col_blue);
-            fb.write_string("Framebuffer details: -\n", &col_green);
-            fb.write_string(&s, &col_green);
-            fb.write_string("\n\n", &col_green);
-            write_prompt(fb, &col_green);
+            fb.write_string("-------------------------------------------------------------------------------\n",   &style_banner);
+            fb.write_string("--== Welcome to the Raspberry Pi bare-metal system, by Simon Pugnet (2018) ==--\n",   &style_banner);
+            fb.write_string("-------------------------------------------------------------------------------\n\n", &style_banner);
+            fb.write_string("Framebuffer details: -\n", &TextStyle { underline: true, ..style_green.clone() });
+
+            // Framebuffer is now owned by the console; from here on kprint!() reaches both sinks
+            console::attach_screen(fb);
+            console::set_sinks(console::SINK_ALL);
+
+            console::with_screen(|fb| {
+                let (width, height, bpp, pitch, buf, size) = (fb.width, fb.height, fb.bpp, fb.pitch, fb.buf, fb.size);
+                fb.set_style(style_green.clone());
+                let _ = write!(fb, "{}x{}, {} bpp, pitch {}, {} bytes at {:p}\n\n", width, height, bpp, pitch, size, buf);
+            });
         },
-        Err(_) => Uart::puts("ERROR\n"),
+        Err(_) => kprintln!("ERROR"),
     }
 
-    let mut command: String = String::with_capacity(255);
-
-    loop {
-        match fb_res {
-            Ok(ref mut fb) => fb.putcursor(&col_white),
-            _ => {},
-        }
-        let ch: u8 = Uart::getc();
-        Uart::putc(ch);
-        match fb_res {
-            Ok(ref mut fb) => {
-                fb.writechar(ch as char, &col_white);
-                if ch as char == '\n' || ch as char == '\r' {
-                    let s = format(format_args!("Your command was: {}\n", command));
-                    fb.write_string(&s, &col_green);
-                    command.clear();
-                    write_prompt(fb, &col_green);
-                } else {
-                    command.push(ch as char);
-                }
-            },
-            _ => {},
-        }
-
-    }
+    shell::register_builtins();
+    dma::register_commands();
+    executor::register_commands();
+    frame::register_commands();
+    fs::register_commands();
+    heap::register_commands();
+    ipi::register_commands();
+    irq::register_commands();
+    klog::register_commands();
+    memtools::register_commands();
+    mmu::register_commands();
+    process::register_commands();
+    sched::register_commands();
+    smp::register_commands();
+    sync::register_commands();
+    watchdog::register_commands();
+
+    let (heap_start, heap_end) = heap::region();
+    kinfo!("heap: {:#010x} - {:#010x}", heap_start, heap_end);
+
+    // The shell carries on as the main thread, alongside these
+    tasks::start();
+    kinfo!("boot complete");
+
+    shell::run();
 }
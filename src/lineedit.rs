@@ -0,0 +1,333 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::console;
+use crate::uart::Uart;
+
+/*
+ * Console line editor with history and completion. All editing is rendered with printable
+ * characters, spaces and backspaces (which move the cursor left without erasing), so the line
+ * looks the same on a serial terminal and on the framebuffer.
+ */
+
+const MAX_LINE: usize = 255;
+
+// Keys decoded from the input stream
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Key {
+    Char(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    Tab,
+    KillToStart, // Ctrl-U
+    KillToEnd,   // Ctrl-K
+    KillWord,    // Ctrl-W
+    Interrupt,   // Ctrl-C
+    Ignored,
+}
+
+// Reads one key, decoding VT100/xterm escape sequences (ESC [ <params> <final> and ESC O <final>)
+fn read_key() -> Key {
+    match Uart::getc() {
+        b'\r' | b'\n'   => Key::Enter,
+        0x08 | 0x7f     => Key::Backspace,
+        b'\t'           => Key::Tab,
+        0x01            => Key::Home,
+        0x02            => Key::Left,
+        0x03            => Key::Interrupt,
+        0x04            => Key::Delete,
+        0x05            => Key::End,
+        0x06            => Key::Right,
+        0x0b            => Key::KillToEnd,
+        0x0e            => Key::Down,
+        0x10            => Key::Up,
+        0x15            => Key::KillToStart,
+        0x17            => Key::KillWord,
+        0x1b            => read_escape(),
+        c if c >= 0x20 && c < 0x7f => Key::Char(c),
+        _               => Key::Ignored,
+    }
+}
+
+fn read_escape() -> Key {
+    match Uart::getc() {
+        b'[' => {},
+        b'O' => return match Uart::getc() {
+            b'H' => Key::Home,
+            b'F' => Key::End,
+            _    => Key::Ignored,
+        },
+        _ => return Key::Ignored,
+    }
+
+    // Numeric parameter (e.g. the 3 in ESC [ 3 ~), then the final byte
+    let mut param: u32 = 0;
+    loop {
+        match Uart::getc() {
+            c @ b'0'..=b'9'   => param = param * 10 + (c - b'0') as u32,
+            b';'              => {},
+            b'A'              => return Key::Up,
+            b'B'              => return Key::Down,
+            b'C'              => return Key::Right,
+            b'D'              => return Key::Left,
+            b'H'              => return Key::Home,
+            b'F'              => return Key::End,
+            b'~'              => return match param {
+                1 | 7 => Key::Home,
+                3     => Key::Delete,
+                4 | 8 => Key::End,
+                _     => Key::Ignored,
+            },
+            _                 => return Key::Ignored,
+        }
+    }
+}
+
+pub struct LineEditor {
+    history:     VecDeque<String>,
+    max_history: usize,
+    buf:         Vec<u8>, // Line being edited (ASCII only)
+    pos:         usize,   // Cursor position within buf
+}
+impl LineEditor {
+    pub fn new(max_history: usize) -> LineEditor {
+        LineEditor {
+            history:     VecDeque::with_capacity(max_history),
+            max_history: max_history,
+            buf:         Vec::with_capacity(MAX_LINE),
+            pos:         0,
+        }
+    }
+
+    // Reads a line. "prompt" prints the prompt (it is called again when the line has to be
+    // redrawn) and "complete" returns the completion candidates for a command name prefix. Returns
+    // None if the line was abandoned with Ctrl-C.
+    pub fn read_line(&mut self, prompt: &dyn Fn(), complete: &dyn Fn(&str) -> Vec<&'static str>) -> Option<String> {
+        self.buf.clear();
+        self.pos = 0;
+
+        // Index into history while browsing it, and the line that was being typed beforehand
+        let mut hist_idx: Option<usize> = None;
+        let mut saved: Vec<u8> = Vec::new();
+
+        prompt();
+        console::show_cursor(true);
+
+        let res = loop {
+            match read_key() {
+                Key::Char(c) => {
+                    if self.buf.len() < MAX_LINE {
+                        let (from, old_len) = (self.pos, self.buf.len());
+                        self.buf.insert(from, c);
+                        self.pos += 1;
+                        self.redraw(from, old_len);
+                    }
+                },
+                Key::Enter => {
+                    let end = self.buf.len();
+                    self.move_to(end);
+                    kprintln!();
+                    break Some(String::from_utf8_lossy(&self.buf).into_owned());
+                },
+                Key::Interrupt => {
+                    let end = self.buf.len();
+                    self.move_to(end);
+                    kprintln!("^C");
+                    break None;
+                },
+                Key::Backspace => {
+                    if self.pos > 0 {
+                        let (at, old_len) = (self.pos - 1, self.buf.len());
+                        self.move_to(at);
+                        self.buf.remove(at);
+                        self.redraw(at, old_len);
+                    }
+                },
+                Key::Delete => {
+                    if self.pos < self.buf.len() {
+                        let (at, old_len) = (self.pos, self.buf.len());
+                        self.buf.remove(at);
+                        self.redraw(at, old_len);
+                    }
+                },
+                Key::Left  => if self.pos > 0 { let p = self.pos - 1; self.move_to(p); },
+                Key::Right => if self.pos < self.buf.len() { let p = self.pos + 1; self.move_to(p); },
+                Key::Home  => self.move_to(0),
+                Key::End   => { let end = self.buf.len(); self.move_to(end); },
+                Key::KillToStart => {
+                    let (old_len, end) = (self.buf.len(), self.pos);
+                    self.move_to(0);
+                    self.buf.drain(0..end);
+                    self.redraw(0, old_len);
+                },
+                Key::KillToEnd => {
+                    let (at, old_len) = (self.pos, self.buf.len());
+                    self.buf.truncate(at);
+                    self.redraw(at, old_len);
+                },
+                Key::KillWord => {
+                    // Delete any spaces before the cursor, then the word before them
+                    let mut start = self.pos;
+                    while start > 0 && self.buf[start - 1] == b' ' {
+                        start -= 1;
+                    }
+                    while start > 0 && self.buf[start - 1] != b' ' {
+                        start -= 1;
+                    }
+                    let (old_len, end) = (self.buf.len(), self.pos);
+                    self.move_to(start);
+                    self.buf.drain(start..end);
+                    self.redraw(start, old_len);
+                },
+                Key::Up => {
+                    let idx = match hist_idx {
+                        None if !self.history.is_empty() => {
+                            saved = self.buf.clone();
+                            Some(self.history.len() - 1)
+                        },
+                        Some(i) if i > 0 => Some(i - 1),
+                        other => other,
+                    };
+                    if idx != hist_idx {
+                        hist_idx = idx;
+                        let line = self.history[idx.unwrap()].clone().into_bytes();
+                        self.replace(line);
+                    }
+                },
+                Key::Down => {
+                    if let Some(i) = hist_idx {
+                        let line = if i + 1 < self.history.len() {
+                            hist_idx = Some(i + 1);
+                            self.history[i + 1].clone().into_bytes()
+                        } else {
+                            hist_idx = None;
+                            saved.clone()
+                        };
+                        self.replace(line);
+                    }
+                },
+                Key::Tab => self.complete(prompt, complete),
+                Key::Ignored => {},
+            }
+        };
+
+        console::show_cursor(false);
+
+        if let Some(ref line) = res {
+            self.add_history(line);
+        }
+        res
+    }
+
+    fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.back().map(|l| l == line).unwrap_or(false) {
+            return;
+        }
+        if self.history.len() >= self.max_history {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+
+    // Moves the cursor to "target" by echoing backspaces or the characters it passes over
+    fn move_to(&mut self, target: usize) {
+        while self.pos > target {
+            kprint!("\x08");
+            self.pos -= 1;
+        }
+        while self.pos < target {
+            kprint!("{}", self.buf[self.pos] as char);
+            self.pos += 1;
+        }
+    }
+
+    // Redraws the line from "from" (where the cursor is, or where it is about to be) to the end,
+    // blanking what remains of a line that used to be "old_len" long, then moves the cursor back
+    // to self.pos
+    fn redraw(&mut self, from: usize, old_len: usize) {
+        let pos = self.pos;
+        self.pos = from;
+        let end = self.buf.len();
+        self.move_to(end);
+
+        let blanks = if old_len > end { old_len - end } else { 0 };
+        for _ in 0..blanks {
+            kprint!(" ");
+        }
+        for _ in 0..blanks {
+            kprint!("\x08");
+        }
+
+        self.move_to(pos);
+    }
+
+    // Replaces the whole line, leaving the cursor at its end
+    fn replace(&mut self, line: Vec<u8>) {
+        let old_len = self.buf.len();
+        self.move_to(0);
+        self.buf = line;
+        self.pos = self.buf.len();
+        self.redraw(0, old_len);
+    }
+
+    // Completes the command name under the cursor: a unique match is inserted in full, several
+    // matches are extended to their common prefix or, failing that, listed
+    fn complete(&mut self, prompt: &dyn Fn(), complete: &dyn Fn(&str) -> Vec<&'static str>) {
+        // Only the first word is a command name
+        if self.buf[..self.pos].contains(&b' ') {
+            return;
+        }
+
+        let prefix = String::from_utf8_lossy(&self.buf[..self.pos]).into_owned();
+        let candidates = complete(&prefix);
+
+        let insert: String = match candidates.len() {
+            0 => return,
+            1 => String::from(&candidates[0][prefix.len()..]) + " ",
+            _ => {
+                let common = candidates.iter().fold(candidates[0], |acc, c| {
+                    let n = acc.bytes().zip(c.bytes()).take_while(|&(a, b)| a == b).count();
+                    &acc[..n]
+                });
+
+                if common.len() > prefix.len() {
+                    String::from(&common[prefix.len()..])
+                } else {
+                    // Nothing to add: list the candidates and redraw the prompt and line
+                    let end = self.buf.len();
+                    self.move_to(end);
+                    kprintln!();
+                    for c in candidates.iter() {
+                        kprint!("{}  ", c);
+                    }
+                    kprintln!();
+                    prompt();
+                    for &b in self.buf.iter() {
+                        kprint!("{}", b as char);
+                    }
+                    self.pos = self.buf.len();
+                    self.move_to(prefix.len());
+                    return;
+                }
+            },
+        };
+
+        let old_len = self.buf.len();
+        for b in insert.bytes() {
+            if self.buf.len() < MAX_LINE {
+                let at = self.pos;
+                self.buf.insert(at, b);
+                self.pos += 1;
+            }
+        }
+        self.redraw(prefix.len(), old_len);
+    }
+}
@@ -0,0 +1,215 @@
+use core::mem;
+use core::ptr;
+
+use crate::barrier;
+use crate::cache;
+use crate::mmio;
+use crate::mmu;
+use crate::sync::SpinLock;
+
+pub const PROPERTY_CHANNEL: usize = 8;
+
+#[allow(dead_code)] pub const FRAMEBUFFER_CHANNEL: usize = 1;
+
+// Property mailbox request/response types
+pub const REQUEST:          u32 = 0x0;
+pub const RESPONSE_SUCCESS: u32 = 0x80000000;
+pub const RESPONSE_ERROR:   u32 = 0x80000001;
+
+// Property mailbox tag types (framebuffer tags live in framebuffer.rs)
+const NULL_TAG:       u32 = 0x0;
+const GET_ARM_MEMORY: u32 = 0x00010005;
+
+// Largest value buffer (in words) supported by property()
+const PROPERTY_MAX_VALUES: usize = 8;
+
+// Held by exchange() from sending a message until its reply has been read, so that cores cannot
+// take each other's replies
+static EXCHANGE_LOCK: SpinLock<()> = SpinLock::new(());
+
+// Current property mailbox status
+struct MailStatus {
+    // Fields: -
+    //  - [0..29]: reserved
+    //  - 30: read buffer empty flag
+    //  - 31: write buffer full flag
+    bitfield: u32,
+}
+impl MailStatus {
+    fn new() -> MailStatus {
+        MailStatus { bitfield: 0 }
+    }
+
+    pub fn is_read_empty(&self) -> bool {
+        (self.bitfield & (1 << 30)) > 0
+    }
+
+    pub fn is_write_full(&self) -> bool {
+        (self.bitfield & (1 << 31)) > 0
+    }
+
+    pub fn update(&mut self) {
+        self.bitfield = mmio::Mmio::read(mmio::GPU_MAILBOX_STATUS);
+    }
+}
+
+// A single property mailbox message which can be read from/written to a channel
+pub struct MailMessage {
+    // Fields: -
+    //  - [0..3]:  channel ID
+    //  - [4..31]: message data
+    bitfield: u32,
+}
+impl MailMessage {
+    pub fn new() -> MailMessage {
+        MailMessage { bitfield: 0 }
+    }
+
+    pub fn get_channel_id(&self) -> usize {
+        (self.bitfield & 0x0F) as usize
+    }
+
+    pub fn set_channel_id(&mut self, id: u8) {
+        self.bitfield = (self.bitfield & 0xFFFFFFF0) | ((id & 0x0F) as u32)
+    }
+
+    pub fn get_data(&self) -> usize {
+        ((self.bitfield & 0xFFFFFFF0) >> 4) as usize
+    }
+
+    pub fn set_data(&mut self, data: u32) {
+        self.bitfield = (self.bitfield & 0x0F) | (((data << 4) & 0xFFFFFFF0) as u32)
+    }
+
+    pub fn update(&mut self) {
+        self.bitfield = mmio::Mmio::read(mmio::GPU_MAILBOX_READ);
+    }
+
+    pub fn write(&self) {
+        mmio::Mmio::write(mmio::GPU_MAILBOX_WRITE, self.bitfield);
+    }
+
+    pub fn mailbox_read(channel: usize) -> MailMessage {
+        let mut counter:   usize =     0;
+        let     max_count: usize = 20000;
+        let mut status:    MailStatus  = MailStatus::new();
+        let mut res:       MailMessage = MailMessage::new();
+        let _access = mmio::Mmio::enter();
+
+        // Loop until the channel read matches the requested channel
+        loop {
+
+            // Loop until the mailbox status shows not empty
+            loop {
+                status.update();
+                counter += 1;
+                if counter >= max_count || !status.is_read_empty() {
+                    break;
+                }
+            }
+
+            // Read from mailbox
+            res.update();
+
+            if res.get_channel_id() == channel || counter >= max_count {
+                break;
+            }
+        }
+
+        res
+    }
+
+    pub fn mailbox_write(&mut self, channel: usize) {
+        let mut status: MailStatus = MailStatus::new();
+        self.set_channel_id(channel as u8);
+
+        // Whatever the message points at (the request buffer, already cleaned from the cache)
+        // must have reached memory before the GPU can see the message
+        barrier::dsb();
+        let _access = mmio::Mmio::enter();
+
+        // Loop until the mailbox status shows not full
+        loop {
+            status.update();
+            if !status.is_write_full() {
+                break;
+            }
+        }
+
+        // Write message to mailbox
+        self.write();
+    }
+
+    // Sends the message on "channel" and returns the reply
+    pub fn exchange(&mut self, channel: usize) -> MailMessage {
+        let _lock = EXCHANGE_LOCK.lock();
+        self.mailbox_write(channel);
+        MailMessage::mailbox_read(channel)
+    }
+}
+
+// Property mailbox message holding a single tag
+#[repr(C)]
+#[repr(align(64))] // Cache line aligned, so that it can be invalidated (see cache.rs)
+struct PropertyMessage {
+    size:     u32,                        // Size of buffer, including "size"
+    mtype:    u32,                        // Request/response type
+    proptag:  u32,                        // Tag type
+    tag_size: u32,                        // Size of the value buffer (bytes)
+    tag_type: u32,                        // Tag request/response type
+    values:   [u32; PROPERTY_MAX_VALUES], // Tag value buffer
+    end_tag:  u32,                        // NULL tag
+}
+
+// Sends a single-tag property request. "values" holds the request values on entry and the response
+// values on return, so it must be large enough for whichever of the two is bigger.
+pub fn property(proptag: u32, values: &mut [u32]) -> Result<(), ()> {
+    if values.len() > PROPERTY_MAX_VALUES {
+        return Err(());
+    }
+
+    let mut msg: PropertyMessage = PropertyMessage {
+        size:     0,
+        mtype:    REQUEST,
+        proptag:  proptag,
+        tag_size: (values.len() * 4) as u32,
+        tag_type: REQUEST,
+        values:   [0; PROPERTY_MAX_VALUES],
+        end_tag:  NULL_TAG,
+    };
+    msg.values[..values.len()].copy_from_slice(values);
+    msg.size = (6 + PROPERTY_MAX_VALUES) as u32 * 4;
+
+    // The GPU does not look in the ARM's data cache, so push the request out before sending it and
+    // drop any stale lines before reading the response
+    let (msg_addr, msg_len) = (&msg as *const _ as usize, mem::size_of::<PropertyMessage>());
+    cache::clean_range(msg_addr, msg_len);
+
+    let mut mail: MailMessage = MailMessage::new();
+    mail.set_data(mmu::virt_to_bus(msg_addr) >> 4);
+    mail.exchange(PROPERTY_CHANNEL);
+
+    cache::invalidate_range(msg_addr, msg_len);
+
+    // The GPU has written the response into msg behind the compiler's back
+    let res: *const PropertyMessage = &msg;
+    unsafe {
+        if ptr::read_volatile(&(*res).mtype) != RESPONSE_SUCCESS {
+            return Err(());
+        }
+        for (i, v) in values.iter_mut().enumerate() {
+            *v = ptr::read_volatile(&(*res).values[i]);
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the base address and size of the memory assigned to the ARM (the rest belongs to the GPU)
+pub fn get_arm_memory() -> Option<(usize, usize)> {
+    let mut values = [0u32; 2];
+    match property(GET_ARM_MEMORY, &mut values) {
+        Ok(_)  => Some((values[0] as usize, values[1] as usize)),
+        Err(_) => None,
+    }
+}
@@ -0,0 +1,359 @@
+use core::ptr;
+
+use crate::frame;
+use crate::mmio::Mmio;
+use crate::mmu;
+use crate::shell;
+
+/*
+ * Memory and register inspection commands for board bring-up: peek/poke, hexdump, fill, copy,
+ * compare and a destructive RAM tester. Addresses are virtual and used as given, so all of these
+ * can reach peripheral registers as well as RAM; each command checks its range is mapped first.
+ */
+
+// Number of mismatches reported by cmp and memtest before giving up
+const MAX_ERRORS: usize = 16;
+
+pub fn register_commands() {
+    shell::register("peek",    "<addr> [8|16|32]",                   "Read a value",                         cmd_peek);
+    shell::register("poke",    "<addr> <value> [8|16|32]",           "Write a value",                        cmd_poke);
+    shell::register("hexdump", "<addr> <len>",                       "Dump memory as hex and ASCII",         cmd_hexdump);
+    shell::register("fill",    "<addr> <len> <byte>",                "Fill memory with a byte",              cmd_fill);
+    shell::register("copy",    "<src> <dst> <len>",                  "Copy memory (ranges may overlap)",     cmd_copy);
+    shell::register("cmp",     "<addr1> <addr2> <len>",              "Compare two memory ranges",            cmd_cmp);
+    shell::register("memtest", "<addr> <len> [walk|addr|march|all]", "Test RAM (destroys its contents)",     cmd_memtest);
+}
+
+// Parses the numeric argument at "idx"
+fn arg(args: &[&str], idx: usize) -> Option<usize> {
+    args.get(idx).and_then(|a| shell::parse_number(a))
+}
+
+// Parses an optional access width argument (default 32)
+fn width_arg(args: &[&str], idx: usize) -> Option<usize> {
+    match args.get(idx) {
+        None        => Some(32),
+        Some(&"8")  => Some(8),
+        Some(&"16") => Some(16),
+        Some(&"32") => Some(32),
+        Some(_)     => None,
+    }
+}
+
+// Checks that [addr, addr + len) does not wrap past the end of the address space and that every
+// page of it is mapped for reading (or writing), reporting the first one that is not
+fn check_mapped(cmd: &str, addr: usize, len: usize, write: bool) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None      => {
+            shell::error(format_args!("{}: range wraps past the end of the address space", cmd));
+            return false;
+        },
+    };
+
+    let mut page = addr & !(mmu::PAGE_SIZE - 1);
+    while page < end {
+        let ok = if write { mmu::is_writable(page) } else { mmu::is_readable(page) };
+        if !ok {
+            let a = if page < addr { addr } else { page };
+            shell::error(format_args!("{}: {:#010x} is not mapped{}", cmd, a, if write { " for writing" } else { "" }));
+            return false;
+        }
+        page = match page.checked_add(mmu::PAGE_SIZE) {
+            Some(p) => p,
+            None    => break,
+        };
+    }
+    true
+}
+
+// Reads a byte for display. The range may cover peripheral registers and the console output in
+// between goes to the UART, so each read gets its own barriers.
+fn read_byte(addr: usize) -> u8 {
+    let _access = Mmio::enter();
+    Mmio::read8(addr)
+}
+
+fn cmd_peek(args: &[&str]) -> i32 {
+    let (addr, width) = match (arg(args, 1), width_arg(args, 2)) {
+        (Some(a), Some(w)) if a % (w / 8) == 0 => (a, w),
+        _ => return shell::usage(args[0]),
+    };
+    if !check_mapped(args[0], addr, width / 8, false) {
+        return shell::STATUS_FAILED;
+    }
+
+    let value = {
+        let _access = Mmio::enter();
+        match width {
+            8  => Mmio::read8(addr) as u32,
+            16 => Mmio::read16(addr) as u32,
+            _  => Mmio::read(addr),
+        }
+    };
+    kprintln!("{:#010x}: {:#0w$x}", addr, value, w = width / 4 + 2);
+    shell::STATUS_OK
+}
+
+fn cmd_poke(args: &[&str]) -> i32 {
+    let (addr, value, width) = match (arg(args, 1), arg(args, 2), width_arg(args, 3)) {
+        (Some(a), Some(v), Some(w)) if a % (w / 8) == 0 && (w == 32 || v < 1 << w) => (a, v, w),
+        _ => return shell::usage(args[0]),
+    };
+    if !check_mapped(args[0], addr, width / 8, true) {
+        return shell::STATUS_FAILED;
+    }
+
+    let _access = Mmio::enter();
+    match width {
+        8  => Mmio::write8(addr,  value as u8),
+        16 => Mmio::write16(addr, value as u16),
+        _  => Mmio::write(addr,   value as u32),
+    }
+    shell::STATUS_OK
+}
+
+fn cmd_hexdump(args: &[&str]) -> i32 {
+    let (addr, len) = match (arg(args, 1), arg(args, 2)) {
+        (Some(a), Some(l)) => (a, l),
+        _ => return shell::usage(args[0]),
+    };
+    if !check_mapped(args[0], addr, len, false) {
+        return shell::STATUS_FAILED;
+    }
+
+    // Rows are aligned to 16 bytes; bytes outside the range are left blank. check_mapped() made
+    // sure that the range does not wrap, but the last row may end at the top of the address space.
+    let start = addr & !0xF;
+    let end   = addr + len;
+    let mut row = start;
+
+    while row < end {
+        kprint!("{:08x}  ", row);
+        for i in 0..16 {
+            let a = row + i;
+            if a >= addr && a < end {
+                kprint!("{:02x} ", read_byte(a));
+            } else {
+                kprint!("   ");
+            }
+            if i == 7 {
+                kprint!(" ");
+            }
+        }
+
+        kprint!(" |");
+        for i in 0..16 {
+            let a = row + i;
+            if a >= addr && a < end {
+                let b = read_byte(a);
+                kprint!("{}", if b >= 0x20 && b < 0x7f { b as char } else { '.' });
+            } else {
+                kprint!(" ");
+            }
+        }
+        kprintln!("|");
+
+        row = match row.checked_add(16) {
+            Some(next) => next,
+            None       => break,
+        };
+    }
+    shell::STATUS_OK
+}
+
+fn cmd_fill(args: &[&str]) -> i32 {
+    let (addr, len, byte) = match (arg(args, 1), arg(args, 2), arg(args, 3)) {
+        (Some(a), Some(l), Some(b)) if b <= 0xFF => (a, l, b as u8),
+        _ => return shell::usage(args[0]),
+    };
+    if !check_mapped(args[0], addr, len, true) {
+        return shell::STATUS_FAILED;
+    }
+
+    unsafe { ptr::write_bytes(addr as *mut u8, byte, len); }
+    shell::STATUS_OK
+}
+
+fn cmd_copy(args: &[&str]) -> i32 {
+    let (src, dst, len) = match (arg(args, 1), arg(args, 2), arg(args, 3)) {
+        (Some(s), Some(d), Some(l)) => (s, d, l),
+        _ => return shell::usage(args[0]),
+    };
+    if !check_mapped(args[0], src, len, false) || !check_mapped(args[0], dst, len, true) {
+        return shell::STATUS_FAILED;
+    }
+
+    unsafe { ptr::copy(src as *const u8, dst as *mut u8, len); }
+    shell::STATUS_OK
+}
+
+fn cmd_cmp(args: &[&str]) -> i32 {
+    let (a, b, len) = match (arg(args, 1), arg(args, 2), arg(args, 3)) {
+        (Some(a), Some(b), Some(l)) => (a, b, l),
+        _ => return shell::usage(args[0]),
+    };
+    if !check_mapped(args[0], a, len, false) || !check_mapped(args[0], b, len, false) {
+        return shell::STATUS_FAILED;
+    }
+
+    let mut differences = 0;
+    for i in 0..len {
+        let (va, vb) = (read_byte(a + i), read_byte(b + i));
+        if va != vb {
+            if differences < MAX_ERRORS {
+                kprintln!("  {:#010x}: {:02x}  {:#010x}: {:02x}", a + i, va, b + i, vb);
+            }
+            differences += 1;
+        }
+    }
+
+    if differences == 0 {
+        kprintln!("Ranges are identical");
+        shell::STATUS_OK
+    } else {
+        kprintln!("{} byte(s) differ", differences);
+        shell::STATUS_FAILED
+    }
+}
+
+/*
+ * RAM tests. All tests work on 32-bit words and stop after MAX_ERRORS failures.
+ */
+
+type MemTest = fn(usize, usize, &mut Failures);
+
+struct Failures {
+    count: usize,
+}
+impl Failures {
+    fn report(&mut self, addr: usize, expected: u32, actual: u32) {
+        if self.count < MAX_ERRORS {
+            kprintln!("  FAIL at {:#010x}: expected {:#010x}, read {:#010x}", addr, expected, actual);
+        }
+        self.count += 1;
+    }
+
+    fn limit_reached(&self) -> bool {
+        self.count >= MAX_ERRORS
+    }
+}
+
+fn write_word(addr: usize, value: u32) {
+    unsafe { ptr::write_volatile(addr as *mut u32, value); }
+}
+
+fn read_word(addr: usize) -> u32 {
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+fn check_word(addr: usize, expected: u32, failures: &mut Failures) {
+    let actual = read_word(addr);
+    if actual != expected {
+        failures.report(addr, expected, actual);
+    }
+}
+
+// Walking ones: each word in turn holds every single-bit pattern, catching stuck or shorted data
+// lines
+fn test_walking_ones(start: usize, words: usize, failures: &mut Failures) {
+    for w in 0..words {
+        let addr = start + w * 4;
+        for bit in 0..32 {
+            write_word(addr, 1 << bit);
+            check_word(addr, 1 << bit, failures);
+        }
+        if failures.limit_reached() {
+            return;
+        }
+    }
+}
+
+// Address-in-address: every word is written with its own address (then its complement) and only
+// read back once the whole range has been written, catching address line faults and aliasing
+fn test_address(start: usize, words: usize, failures: &mut Failures) {
+    for &invert in [0, !0u32].iter() {
+        for w in 0..words {
+            let addr = start + w * 4;
+            write_word(addr, addr as u32 ^ invert);
+        }
+        for w in 0..words {
+            let addr = start + w * 4;
+            check_word(addr, addr as u32 ^ invert, failures);
+            if failures.limit_reached() {
+                return;
+            }
+        }
+    }
+}
+
+// March C-: {up(w0); up(r0,w1); up(r1,w0); down(r0,w1); down(r1,w0); any(r0)}, with all-zero and
+// all-one words. Detects stuck-at, transition and most coupling faults.
+fn test_march_c(start: usize, words: usize, failures: &mut Failures) {
+    let (zero, one) = (0u32, !0u32);
+    let addr = |w: usize| start + w * 4;
+
+    for w in 0..words {
+        write_word(addr(w), zero);
+    }
+
+    // Each element: (ascending, value read, value written)
+    let elements = [(true, zero, one), (true, one, zero), (false, zero, one), (false, one, zero)];
+    for &(ascending, read, write) in elements.iter() {
+        for i in 0..words {
+            let w = if ascending { i } else { words - 1 - i };
+            check_word(addr(w), read, failures);
+            write_word(addr(w), write);
+        }
+        if failures.limit_reached() {
+            return;
+        }
+    }
+
+    for w in 0..words {
+        check_word(addr(w), zero, failures);
+    }
+}
+
+fn cmd_memtest(args: &[&str]) -> i32 {
+    let (start, len) = match (arg(args, 1), arg(args, 2)) {
+        (Some(a), Some(l)) if a & 0x3 == 0 && l & 0x3 == 0 && l > 0 => (a, l),
+        _ => return shell::usage(args[0]),
+    };
+
+    // Only test RAM in the kernel's linear map that nothing has allocated: this keeps the stack, the
+    // kernel image, the heap and the page tables safe
+    let free = start >= mmu::KERNEL_BASE && start.checked_add(len).is_some()
+        && frame::is_free_range(mmu::virt_to_phys(start), mmu::virt_to_phys(start + len));
+    if !free {
+        shell::error(format_args!("memtest: range is not free RAM (see \"frames\")"));
+        return shell::STATUS_FAILED;
+    }
+
+    let tests: [(&str, MemTest); 3] = [
+        ("walk",  test_walking_ones as MemTest),
+        ("addr",  test_address      as MemTest),
+        ("march", test_march_c      as MemTest),
+    ];
+
+    let which = args.get(3).cloned().unwrap_or("all");
+    if which != "all" && !tests.iter().any(|&(name, _)| name == which) {
+        return shell::usage(args[0]);
+    }
+
+    let mut failures = Failures { count: 0 };
+    for &(name, test) in tests.iter() {
+        if which == "all" || which == name {
+            kprintln!("Running {} over {:#010x} - {:#010x}", name, start, start + len);
+            test(start, len / 4, &mut failures);
+        }
+    }
+
+    if failures.count == 0 {
+        kprintln!("PASS");
+        shell::STATUS_OK
+    } else {
+        kprintln!("FAIL: {} error(s)", failures.count);
+        shell::STATUS_FAILED
+    }
+}
@@ -1,8 +1,20 @@
 use core::ptr;
 
-// 0x20000000 on RPi 1
-// 0x3F000000 on RPi 2+
-pub const PERIPHERAL_BASE: usize = 0x3F000000;
+use crate::barrier;
+
+// Physical addresses: 0x20000000 on RPi 1, 0x3F000000 on RPi 2+
+pub const PERIPHERAL_PHYS: usize = 0x3F000000;
+
+// BCM2836 ARM-local peripherals (core timers, local interrupts and mailboxes)
+pub const LOCAL_PERIPHERAL_PHYS: usize = 0x40000000;
+pub const LOCAL_PERIPHERAL_SIZE: usize = 0x100000;
+
+// Where the kernel sees them (KERNEL_BASE + physical, see mmu.rs)
+pub const PERIPHERAL_BASE:       usize = 0xBF000000;
+pub const LOCAL_PERIPHERAL_BASE: usize = 0xC0000000;
+
+// Where bus masters such as the DMA engine see the (non-local) peripherals
+pub const PERIPHERAL_BUS: u32 = 0x7E000000;
 
 // GPIO registers
 pub const GPIO_BASE: usize = 0x200000;
@@ -10,6 +22,13 @@ pub const GPPUD:     usize = GPIO_BASE + 0x94; // GPIO pin pull-up/down enable
 pub const GPPUDCLK0: usize = GPIO_BASE + 0x98; // GPIO pin pull-up/down enable clock 0
 #[allow(dead_code)] pub const GPPUDCLK1: usize = GPIO_BASE + 0x9c; // GPIO pin pull-up/down enable clock 1
 
+// GPIO registers, as the kernel sees them (each register block covers pins 0 - 31, then 32 - 53)
+pub const GPIO_REGS: usize = PERIPHERAL_BASE + GPIO_BASE;
+pub const GPFSEL0:   usize = GPIO_REGS + 0x00; // Function select, 10 pins per register
+pub const GPSET0:    usize = GPIO_REGS + 0x1C; // Output set
+pub const GPCLR0:    usize = GPIO_REGS + 0x28; // Output clear
+pub const GPLEV0:    usize = GPIO_REGS + 0x34; // Pin level
+
 // UART0 registers
 pub const UART0_BASE:   usize = PERIPHERAL_BASE + GPIO_BASE + 0x1000;
 pub const UART0_DR:     usize = UART0_BASE + 0x00; // Data register
@@ -19,9 +38,65 @@ pub const UART0_FBRD:   usize = UART0_BASE + 0x28; // Fractional Baud rate divis
 pub const UART0_LCRH:   usize = UART0_BASE + 0x2c; // Line control register
 pub const UART0_CR:     usize = UART0_BASE + 0x30; // Control register
 pub const UART0_IMSC:   usize = UART0_BASE + 0x38; // Interrupt mask set clear register
+pub const UART0_MIS:    usize = UART0_BASE + 0x40; // Masked interrupt status register
 pub const UART0_ICR:    usize = UART0_BASE + 0x44; // Interrupt clear register
+pub const UART0_IFLS:   usize = UART0_BASE + 0x34; // Interrupt FIFO level select register
+pub const UART0_DMACR:  usize = UART0_BASE + 0x48; // DMA control register
 #[allow(dead_code)] pub const UART0_RSRECR: usize = UART0_BASE + 0x04; // Read status register
 
+// SPI0 registers
+pub const SPI0_BASE: usize = PERIPHERAL_BASE + 0x204000;
+pub const SPI0_CS:   usize = SPI0_BASE + 0x00; // Control and status
+pub const SPI0_FIFO: usize = SPI0_BASE + 0x04; // TX and RX FIFOs
+pub const SPI0_CLK:  usize = SPI0_BASE + 0x08; // Clock divider
+pub const SPI0_DLEN: usize = SPI0_BASE + 0x0C; // Bytes to transfer in DMA mode
+
+// Power management / watchdog registers
+pub const PM_BASE:                  usize = PERIPHERAL_BASE + 0x100000;
+pub const PM_RSTC:                  usize = PM_BASE + 0x1c; // Reset control
+pub const PM_RSTS:                  usize = PM_BASE + 0x20; // Reset status (boot partition)
+pub const PM_WDOG:                  usize = PM_BASE + 0x24; // Watchdog timer
+pub const PM_PASSWORD:              u32   = 0x5a000000;     // Must be written along with any value
+pub const PM_RSTC_WRCFG_CLR:        u32   = 0xffffffcf;     // Mask clearing the reset config bits
+pub const PM_RSTC_WRCFG_FULL_RESET: u32   = 0x00000020;
+pub const PM_RSTC_RESET:            u32   = 0x00000102;     // Cancels a pending watchdog reset
+pub const PM_WDOG_TIME_SET:         u32   = 0x000fffff;     // Watchdog timeout (16us ticks)
+
+// System timer registers (free-running 1MHz counter)
+pub const SYSTIMER_BASE: usize = PERIPHERAL_BASE + 0x3000;
+pub const SYSTIMER_CS:   usize = SYSTIMER_BASE + 0x00; // Compare match flags (write 1 to clear)
+pub const SYSTIMER_CLO:  usize = SYSTIMER_BASE + 0x04; // Counter lower 32 bits
+pub const SYSTIMER_CHI:  usize = SYSTIMER_BASE + 0x08; // Counter higher 32 bits
+pub const SYSTIMER_C0:   usize = SYSTIMER_BASE + 0x0C; // Compare channels 0 - 3 follow
+
+// BCM2835 interrupt controller
+pub const IRQ_BASE:          usize = PERIPHERAL_BASE + 0xB000;
+pub const IRQ_BASIC_PENDING: usize = IRQ_BASE + 0x200;
+pub const IRQ_PENDING1:      usize = IRQ_BASE + 0x204; // Peripheral interrupts 0 - 31
+pub const IRQ_PENDING2:      usize = IRQ_BASE + 0x208; // Peripheral interrupts 32 - 63
+pub const IRQ_ENABLE1:       usize = IRQ_BASE + 0x210;
+pub const IRQ_ENABLE2:       usize = IRQ_BASE + 0x214;
+pub const IRQ_DISABLE1:      usize = IRQ_BASE + 0x21C;
+pub const IRQ_DISABLE2:      usize = IRQ_BASE + 0x220;
+
+// DMA controller (channels 0 - 14; channel 15 lives elsewhere and is not used)
+pub const DMA_BASE:           usize = PERIPHERAL_BASE + 0x7000;
+pub const DMA_CHANNEL_STRIDE: usize = 0x100;
+pub const DMA_INT_STATUS:     usize = DMA_BASE + 0xFE0; // Interrupt status of each channel
+pub const DMA_ENABLE:         usize = DMA_BASE + 0xFF0; // Global enable bit of each channel
+
+// BCM2836 local interrupt controller (one register per core, 4 bytes apart)
+pub const LOCAL_GPU_INT_ROUTING:   usize = LOCAL_PERIPHERAL_BASE + 0x0C;
+pub const LOCAL_TIMER_INT_CTRL0:   usize = LOCAL_PERIPHERAL_BASE + 0x40;
+pub const LOCAL_MAILBOX_INT_CTRL0: usize = LOCAL_PERIPHERAL_BASE + 0x50;
+pub const LOCAL_IRQ_SOURCE0:       usize = LOCAL_PERIPHERAL_BASE + 0x60;
+
+// BCM2836 local mailboxes: 4 per core, each with a write-to-set and a write-to-clear register.
+// Mailbox m of core c is at + c * LOCAL_MAILBOX_CORE_STRIDE + m * 4.
+pub const LOCAL_MAILBOX_SET0:        usize = LOCAL_PERIPHERAL_BASE + 0x80;
+pub const LOCAL_MAILBOX_CLR0:        usize = LOCAL_PERIPHERAL_BASE + 0xC0;
+pub const LOCAL_MAILBOX_CORE_STRIDE: usize = 0x10;
+
 pub const GPU_MAILBOX_BASE:   usize = PERIPHERAL_BASE + 0xB880;
 pub const GPU_MAILBOX_READ:   usize = GPU_MAILBOX_BASE;
 pub const GPU_MAILBOX_STATUS: usize = GPU_MAILBOX_BASE + 0x18;
@@ -36,7 +111,35 @@ pub const GPU_MAILBOX_WRITE:  usize = GPU_MAILBOX_BASE + 0x20;
 
 pub struct Mmio { }
 
+// Returns the bus address of the peripheral register at virtual address "addr", for bus masters
+pub fn bus_addr(addr: usize) -> u32 {
+    (addr - PERIPHERAL_BASE) as u32 + PERIPHERAL_BUS
+}
+
+/*
+ * The BCM2835 does not keep accesses to different peripherals in order: data from a read of one
+ * peripheral may arrive after that of a later read of another (BCM2835 ARM Peripherals, section
+ * 1.3). A memory barrier is needed before the first access to a peripheral and after the last one.
+ * Drivers wrap each run of accesses to their peripheral in a guard from Mmio::enter(), which issues
+ * a barrier when created and another when dropped.
+ */
+
+#[must_use = "the leaving barrier is issued when the guard is dropped"]
+pub struct PeripheralAccess { }
+
+impl Drop for PeripheralAccess {
+    fn drop(&mut self) {
+        barrier::dmb();
+    }
+}
+
 impl Mmio {
+    // Starts a run of accesses to one peripheral, ending when the guard is dropped
+    pub fn enter() -> PeripheralAccess {
+        barrier::dmb();
+        PeripheralAccess { }
+    }
+
     pub fn read(addr: usize) -> u32 {
         unsafe {
             ptr::read_volatile::<u32>(addr as *const u32)
@@ -48,4 +151,28 @@ impl Mmio {
             ptr::write_volatile::<u32>(addr as *mut u32, data);
         }
     }
+
+    pub fn read8(addr: usize) -> u8 {
+        unsafe {
+            ptr::read_volatile::<u8>(addr as *const u8)
+        }
+    }
+
+    pub fn write8(addr: usize, data: u8) {
+        unsafe {
+            ptr::write_volatile::<u8>(addr as *mut u8, data);
+        }
+    }
+
+    pub fn read16(addr: usize) -> u16 {
+        unsafe {
+            ptr::read_volatile::<u16>(addr as *const u16)
+        }
+    }
+
+    pub fn write16(addr: usize, data: u16) {
+        unsafe {
+            ptr::write_volatile::<u16>(addr as *mut u16, data);
+        }
+    }
 }
@@ -0,0 +1,330 @@
+use crate::frame;
+use crate::mmio;
+use crate::shell;
+use crate::vm;
+
+/*
+ * MMU setup. main.S enables the MMU at boot with a temporary table mapping physical memory both at
+ * its physical address and at KERNEL_BASE + physical, where the kernel is linked. init() then
+ * replaces it with the kernel address space proper (see vm.rs), which only covers the top half of
+ * the address space (TTBR1) and has no identity mapping:
+ *  - ARM RAM is mapped linearly at KERNEL_BASE as normal write-back cacheable memory and never
+ *    executable, except for
+ *  - the kernel image, mapped with 4 KiB pages so that .text is read-only and executable, .rodata
+ *    is read-only, and .data and .bss are read-write,
+ *  - the boot stack, read-write, with an unmapped guard page (physical page 0) below it,
+ *  - GPU memory (above ARM RAM, where the framebuffer lives) is normal non-cacheable memory,
+ *  - the peripherals and the ARM-local peripherals are device memory,
+ *  - kernel stacks live in their own area, each with an unmapped guard page below it,
+ *  - uncached aliases of RAM for DMA buffers live in another (see dmabuf.rs),
+ *  - everything else faults.
+ * The bottom half (TTBR0) belongs to user address spaces and is switched with them.
+ */
+
+extern "C" {
+    fn mmu_set_ttbr0(table: usize);
+    fn mmu_set_ttbr1(table: usize);
+    fn mmu_tlb_invalidate_all();
+    fn mmu_tlb_invalidate_page(addr: usize);
+    fn mmu_probe_read(addr: usize) -> u32;
+    fn mmu_probe_write(addr: usize) -> u32;
+    fn mmu_get_sctlr() -> u32;
+    fn mmu_get_ttbr0() -> u32;
+    fn mmu_get_ttbr1() -> u32;
+}
+
+// Linker symbols (see linker.ld)
+extern {
+    static __text_start:     u32;
+    static __text_end:       u32;
+    static __rodata_start:   u32;
+    static __rodata_end:     u32;
+    static __boot_stack_top: u32;
+    static __end:            u32;
+}
+
+// Physical address P is mapped at KERNEL_BASE + P (must match linker.ld and main.S)
+pub const KERNEL_BASE: usize = 0x80000000;
+
+pub const SECTION_SIZE:  usize = 0x100000;
+pub const SECTION_SHIFT: usize = 20;
+pub const PAGE_SIZE:     usize = frame::FRAME_SIZE;
+pub const PAGE_SHIFT:    usize = frame::FRAME_SHIFT;
+
+// Descriptor type bits
+pub const L1_FAULT:      u32 = 0b00;
+pub const L1_PAGE_TABLE: u32 = 0b01;
+pub const L1_SECTION:    u32 = 0b10;
+pub const L2_FAULT:      u32 = 0b00;
+pub const L2_SMALL_PAGE: u32 = 0b10;
+
+const SCTLR_M: u32 = 1 << 0;
+const SCTLR_C: u32 = 1 << 2;
+const SCTLR_Z: u32 = 1 << 11;
+const SCTLR_I: u32 = 1 << 12;
+
+const PAR_FAULT: u32 = 1 << 0;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MemType {
+    Normal,   // Write-back write-allocate cacheable
+    Uncached, // Normal, non-cacheable
+    Device,   // Shareable device
+}
+
+// Attributes of a mapping
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Attrs {
+    pub mem:        MemType,
+    pub writable:   bool,
+    pub executable: bool,
+    pub user:       bool, // Accessible from user mode (and not global, i.e. per address space)
+}
+
+pub const KERNEL_TEXT:   Attrs = Attrs { mem: MemType::Normal,   writable: false, executable: true,  user: false };
+pub const KERNEL_RODATA: Attrs = Attrs { mem: MemType::Normal,   writable: false, executable: false, user: false };
+pub const KERNEL_DATA:   Attrs = Attrs { mem: MemType::Normal,   writable: true,  executable: false, user: false };
+pub const UNCACHED:      Attrs = Attrs { mem: MemType::Uncached, writable: true,  executable: false, user: false };
+pub const GPU_MEMORY:    Attrs = UNCACHED;
+pub const DEVICE:        Attrs = Attrs { mem: MemType::Device,   writable: true,  executable: false, user: false };
+pub const USER_TEXT:     Attrs = Attrs { mem: MemType::Normal,   writable: false, executable: true,  user: true };
+pub const USER_RODATA:   Attrs = Attrs { mem: MemType::Normal,   writable: false, executable: false, user: true };
+pub const USER_DATA:     Attrs = Attrs { mem: MemType::Normal,   writable: true,  executable: false, user: true };
+
+impl Attrs {
+    // (TEX, C, B) for the memory type; TEX remap is off
+    fn tex_c_b(&self) -> (u32, u32, u32) {
+        match self.mem {
+            MemType::Normal   => (0b001, 1, 1),
+            MemType::Uncached => (0b001, 0, 0),
+            MemType::Device   => (0b000, 0, 1),
+        }
+    }
+
+    // AP[2] and AP[1:0]
+    fn ap(&self) -> (u32, u32) {
+        match (self.writable, self.user) {
+            (true,  false) => (0, 0b01), // Privileged read-write
+            (false, false) => (1, 0b01), // Privileged read-only
+            (true,  true)  => (0, 0b11), // Read-write at any privilege
+            (false, true)  => (1, 0b10), // Read-only at any privilege
+        }
+    }
+
+    // Normal memory is shareable, so that it stays coherent once the other cores are up
+    fn shareable(&self) -> u32 {
+        if self.mem == MemType::Device { 0 } else { 1 }
+    }
+
+    fn xn(&self) -> u32 {
+        if self.executable { 0 } else { 1 }
+    }
+
+    fn not_global(&self) -> u32 {
+        if self.user { 1 } else { 0 }
+    }
+
+    pub fn section_bits(&self) -> u32 {
+        let (tex, c, b) = self.tex_c_b();
+        let (ap2, ap)   = self.ap();
+
+        L1_SECTION | (b << 2) | (c << 3) | (self.xn() << 4) | (ap << 10) | (tex << 12) | (ap2 << 15)
+            | (self.shareable() << 16) | (self.not_global() << 17)
+    }
+
+    pub fn page_bits(&self) -> u32 {
+        let (tex, c, b) = self.tex_c_b();
+        let (ap2, ap)   = self.ap();
+
+        L2_SMALL_PAGE | self.xn() | (b << 2) | (c << 3) | (ap << 4) | (tex << 6) | (ap2 << 9)
+            | (self.shareable() << 10) | (self.not_global() << 11)
+    }
+
+    fn decode(tex: u32, c: u32, b: u32, ap2: u32, ap: u32, xn: u32) -> Attrs {
+        let mem = match (tex, c, b) {
+            (0b001, 1, 1) => MemType::Normal,
+            (0b001, 0, 0) => MemType::Uncached,
+            _             => MemType::Device,
+        };
+        Attrs { mem: mem, writable: ap2 == 0, executable: xn == 0, user: ap & 0b10 != 0 }
+    }
+
+    // Decodes the attributes of a section descriptor
+    pub fn from_section(desc: u32) -> Attrs {
+        Attrs::decode((desc >> 12) & 0b111, (desc >> 3) & 1, (desc >> 2) & 1,
+                      (desc >> 15) & 1, (desc >> 10) & 0b11, (desc >> 4) & 1)
+    }
+
+    // Decodes the attributes of a small page descriptor
+    pub fn from_page(desc: u32) -> Attrs {
+        Attrs::decode((desc >> 6) & 0b111, (desc >> 3) & 1, (desc >> 2) & 1,
+                      (desc >> 9) & 1, (desc >> 4) & 0b11, desc & 1)
+    }
+}
+
+// Converts between physical addresses and their kernel (linear map) virtual addresses
+pub fn phys_to_virt(addr: usize) -> usize {
+    addr + KERNEL_BASE
+}
+
+pub fn virt_to_phys(addr: usize) -> usize {
+    debug_assert!(addr >= KERNEL_BASE, "not a kernel address: {:#010x}", addr);
+    addr - KERNEL_BASE
+}
+
+// VideoCore bus alias of physical memory that bypasses the GPU's L2 cache. Addresses handed to the
+// GPU or the DMA engine must be bus addresses.
+pub const BUS_UNCACHED: usize = 0xC0000000;
+
+pub fn phys_to_bus(addr: usize) -> u32 {
+    (addr | BUS_UNCACHED) as u32
+}
+
+pub fn bus_to_phys(addr: u32) -> usize {
+    addr as usize & !BUS_UNCACHED
+}
+
+// Bus address of a kernel (linear map) virtual address
+pub fn virt_to_bus(addr: usize) -> u32 {
+    phys_to_bus(virt_to_phys(addr))
+}
+
+fn symbol_addr(sym: &u32) -> usize {
+    sym as *const u32 as usize
+}
+
+// Attributes of a page in the first sections of the linear map, which hold the boot stack and the
+// kernel image, or None to leave it unmapped
+fn kernel_page_attrs(addr: usize) -> Option<Attrs> {
+    let (text_start, text_end, rodata_start, rodata_end, stack_top) = unsafe {
+        (symbol_addr(&__text_start), symbol_addr(&__text_end),
+         symbol_addr(&__rodata_start), symbol_addr(&__rodata_end), symbol_addr(&__boot_stack_top))
+    };
+
+    if addr < KERNEL_BASE + PAGE_SIZE {
+        None // Guard page below the boot stack
+    } else if addr < stack_top {
+        Some(KERNEL_DATA)
+    } else if addr >= text_start && addr < text_end {
+        Some(KERNEL_TEXT)
+    } else if addr >= rodata_start && addr < rodata_end {
+        Some(KERNEL_RODATA)
+    } else {
+        Some(KERNEL_DATA)
+    }
+}
+
+// Builds the kernel address space and switches to it, dropping the boot identity mapping. Needs
+// the frame allocator.
+pub fn init() {
+    let (_, ram_end) = frame::ram_range();
+    let kernel_end = vm::align_up(unsafe { symbol_addr(&__end) }, SECTION_SIZE);
+    let ram_end    = phys_to_virt(ram_end & !(SECTION_SIZE - 1));
+    let local_end  = mmio::LOCAL_PERIPHERAL_BASE + mmio::LOCAL_PERIPHERAL_SIZE;
+
+    let mut kernel = vm::create_kernel_space();
+
+    let mut addr = KERNEL_BASE;
+    while addr < kernel_end {
+        if let Some(attrs) = kernel_page_attrs(addr) {
+            kernel.map(addr, virt_to_phys(addr), attrs).expect("mmu: cannot map the kernel image");
+        }
+        addr += PAGE_SIZE;
+    }
+
+    let linear: [(usize, usize, Attrs); 4] = [
+        (kernel_end,                  ram_end,                     KERNEL_DATA),
+        (ram_end,                     mmio::PERIPHERAL_BASE,       GPU_MEMORY),
+        (mmio::PERIPHERAL_BASE,       mmio::LOCAL_PERIPHERAL_BASE, DEVICE),
+        (mmio::LOCAL_PERIPHERAL_BASE, local_end,                   DEVICE),
+    ];
+    for &(start, end, attrs) in linear.iter() {
+        let mut addr = start;
+        while addr < end {
+            kernel.map_section(addr, virt_to_phys(addr), attrs).expect("mmu: cannot map the linear map");
+            addr += SECTION_SIZE;
+        }
+    }
+
+    unsafe {
+        mmu_set_ttbr1(kernel.table_phys());
+        mmu_set_ttbr0(0);
+    }
+
+    kinfo!("mmu: kernel address space at {:#010x}", kernel.table_phys());
+}
+
+// Switches the user half of the address space to the table at "table" (physical), or unmaps it
+pub fn set_user_table(table: Option<usize>) {
+    unsafe { mmu_set_ttbr0(table.unwrap_or(0)); }
+}
+
+pub fn tlb_invalidate_page(addr: usize) {
+    unsafe { mmu_tlb_invalidate_page(addr); }
+}
+
+pub fn tlb_invalidate_all() {
+    unsafe { mmu_tlb_invalidate_all(); }
+}
+
+pub fn is_enabled() -> bool {
+    unsafe { mmu_get_sctlr() & SCTLR_M != 0 }
+}
+
+// Whether the kernel could read (or write) "addr" right now without faulting
+pub fn is_readable(addr: usize) -> bool {
+    !is_enabled() || unsafe { mmu_probe_read(addr) } & PAR_FAULT == 0
+}
+
+pub fn is_writable(addr: usize) -> bool {
+    !is_enabled() || unsafe { mmu_probe_write(addr) } & PAR_FAULT == 0
+}
+
+// Translates "addr" through the current tables, as the hardware would for a kernel read
+pub fn translate(addr: usize) -> Option<usize> {
+    if !is_enabled() {
+        return Some(addr);
+    }
+    match unsafe { mmu_probe_read(addr) } {
+        par if par & PAR_FAULT != 0 => None,
+        par                         => Some((par as usize & !(PAGE_SIZE - 1)) | (addr & (PAGE_SIZE - 1))),
+    }
+}
+
+pub fn register_commands() {
+    shell::register("mmu",  "",       "Show the MMU state and kernel memory map", cmd_mmu);
+    shell::register("vtop", "<addr>", "Translate a virtual address",              cmd_vtop);
+}
+
+fn cmd_mmu(_args: &[&str]) -> i32 {
+    let sctlr = unsafe { mmu_get_sctlr() };
+    let onoff = |bit: u32| if sctlr & bit != 0 { "on" } else { "off" };
+
+    kprintln!("MMU {}, D-cache {}, I-cache {}, branch prediction {}",
+              onoff(SCTLR_M), onoff(SCTLR_C), onoff(SCTLR_I), onoff(SCTLR_Z));
+    if !is_enabled() {
+        return shell::STATUS_OK;
+    }
+    kprintln!("TTBR0: {:#010x}, TTBR1: {:#010x}", unsafe { mmu_get_ttbr0() }, unsafe { mmu_get_ttbr1() });
+    kprintln!("Kernel address space:");
+    vm::kernel().dump();
+    shell::STATUS_OK
+}
+
+fn cmd_vtop(args: &[&str]) -> i32 {
+    let addr = match args.get(1).and_then(|a| shell::parse_number(a)) {
+        Some(a) => a,
+        None    => return shell::usage(args[0]),
+    };
+
+    match translate(addr) {
+        Some(phys) => {
+            kprintln!("{:#010x} -> {:#010x} ({})", addr, phys, if is_writable(addr) { "rw" } else { "ro" });
+            shell::STATUS_OK
+        },
+        None => {
+            shell::error(format_args!("vtop: {:#010x} is not mapped", addr));
+            shell::STATUS_FAILED
+        },
+    }
+}
@@ -0,0 +1,185 @@
+use core::alloc::Layout;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console;
+use crate::cpu;
+use crate::framebuffer::{Pixel24, TextStyle};
+use crate::ipi;
+use crate::mmu;
+use crate::watchdog;
+
+/*
+ * Panic and out-of-memory handlers. Both report what went wrong on every console sink (painting a
+ * red panic screen if a framebuffer is attached), walk the frame-pointer chain and then halt or
+ * reboot, depending on the "panic-reboot" cargo feature.
+ */
+
+// Linker symbols (see linker.ld and tools/ksyms.sh)
+extern {
+    static __text_start:  u32;
+    static __text_end:    u32;
+    static __ksyms_start: u32;
+    static __ksyms_end:   u32;
+}
+
+// Maximum number of frames printed in a backtrace
+const MAX_FRAMES: usize = 32;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+#[panic_handler]
+#[no_mangle]
+pub fn panic(info: &PanicInfo) -> ! {
+    enter_panic();
+
+    kprint!("\n*** KERNEL PANIC");
+    if let Some(loc) = info.location() {
+        kprint!(" at {}:{}:{}", loc.file(), loc.line(), loc.column());
+    }
+    kprintln!(" ***");
+    if let Some(msg) = info.message() {
+        kprintln!("{}", msg);
+    }
+
+    backtrace(cpu::frame_pointer());
+    finish()
+}
+
+#[alloc_error_handler]
+#[no_mangle]
+pub fn oom(layout: Layout) -> ! {
+    enter_panic();
+
+    kprintln!("\n*** OUT OF MEMORY ***");
+    kprintln!("Allocation of {} bytes (align {}) failed", layout.size(), layout.align());
+
+    backtrace(cpu::frame_pointer());
+    finish()
+}
+
+// Reports a fault the kernel cannot recover from, such as a CPU exception (see exception.rs). The
+// backtrace starts at "fp", the frame of the code that faulted, rather than the caller's.
+pub fn fatal(what: fmt::Arguments, fp: usize) -> ! {
+    enter_panic();
+
+    kprintln!("\n*** KERNEL PANIC ***");
+    kprintln!("{}", what);
+
+    backtrace(fp);
+    finish()
+}
+
+// Guards against panicking while panicking (on any core) and switches the screen into panic mode
+fn enter_panic() {
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        cpu::halt();
+    }
+    ipi::stop_others();
+
+    // The panic may have interrupted the console's holder, possibly on this very core
+    unsafe { console::break_locks(); }
+
+    let red:   Pixel24 = Pixel24 {r: 160, g: 0,   b: 0};
+    let white: Pixel24 = Pixel24 {r: 255, g: 255, b: 255};
+
+    console::with_screen(|fb| {
+        fb.fill(&red);
+        fb.x = 0;
+        fb.y = 0;
+        fb.set_style(TextStyle { bg: Some(red), ..TextStyle::new(white) });
+    });
+}
+
+// Prints the return address of each frame, starting with the frame at "fp". Frames are laid out
+// as {saved fp, saved lr} with fp pointing at the saved fp, so the walk follows the saved fp
+// values up the stack until one is not mapped (the stacks are surrounded by unmapped pages).
+fn backtrace(mut fp: usize) {
+    kprintln!("Backtrace:");
+
+    for frame in 0..MAX_FRAMES {
+        if fp == 0 || fp & 0x3 != 0 || !mmu::is_readable(fp) || !mmu::is_readable(fp + 4) {
+            break;
+        }
+
+        let (next_fp, lr) = unsafe {
+            (*(fp as *const usize), *((fp + 4) as *const usize))
+        };
+
+        print_frame(frame, lr);
+
+        // The stack grows down, so each caller's frame must be above the current one
+        if next_fp <= fp {
+            break;
+        }
+        fp = next_fp;
+    }
+}
+
+fn print_frame(frame: usize, addr: usize) {
+    kprint!("  #{:<2} {:#010x}", frame, addr);
+    if let Some((name, offset)) = symbolicate(addr) {
+        kprint!(" {}+{:#x}", name, offset);
+    }
+    kprintln!();
+}
+
+// One entry of the symbol table generated by tools/ksyms.sh
+#[repr(C)]
+struct KSym {
+    addr: usize,
+    name: *const u8,
+}
+
+// Finds the symbol containing "addr" and returns its name and the offset of "addr" within it
+pub fn symbolicate(addr: usize) -> Option<(&'static str, usize)> {
+    let syms: &[KSym] = unsafe {
+        let start = &__ksyms_start as *const u32;
+        let end   = &__ksyms_end   as *const u32;
+        if (end as usize) < start as usize + 4 {
+            return None;
+        }
+        slice::from_raw_parts(start.offset(1) as *const KSym, *start as usize)
+    };
+
+    let text_start = unsafe { &__text_start as *const u32 as usize };
+    let text_end   = unsafe { &__text_end   as *const u32 as usize };
+    if addr < text_start || addr >= text_end {
+        return None;
+    }
+
+    // Entries are sorted by address: find the last one at or below addr
+    let idx = match syms.binary_search_by(|s| s.addr.cmp(&addr)) {
+        Ok(i)  => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+
+    let sym = &syms[idx];
+    let name = unsafe {
+        let mut len = 0;
+        while *sym.name.offset(len as isize) != 0 {
+            len += 1;
+        }
+        str::from_utf8(slice::from_raw_parts(sym.name, len)).unwrap_or("?")
+    };
+
+    Some((name, addr - sym.addr))
+}
+
+// Halts, or reboots through the watchdog when built with the "panic-reboot" feature. The other
+// cores were already stopped by enter_panic().
+fn finish() -> ! {
+    if cfg!(feature = "panic-reboot") {
+        kprintln!("Rebooting...");
+        watchdog::reboot();
+    } else {
+        kprintln!("System halted.");
+    }
+
+    cpu::halt()
+}
+
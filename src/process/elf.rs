@@ -0,0 +1,241 @@
+use alloc::vec::Vec;
+
+use crate::mmu::{self, PAGE_SIZE};
+use crate::process::{self, Pid, ProcError, Process};
+use crate::timer::Timer;
+use crate::vm;
+
+/*
+ * Loader for statically linked 32-bit ARM ELF executables. Each PT_LOAD segment is mapped with the
+ * permissions its flags ask for, at its own pages (a page cannot be both writable and executable,
+ * so segments must not share one), and its file contents are copied in; the rest (.bss) stays
+ * zeroed. The stack is set up as Linux sets it up, from the stack pointer up:
+ *   argc, argv[0..argc], NULL, envp[..], NULL, auxv pairs ending with AT_NULL, then the strings
+ * and the AT_RANDOM bytes at the top.
+ */
+
+// ELF header fields
+const EI_CLASS:    usize = 4;
+const EI_DATA:     usize = 5;
+const ELFCLASS32:  u8    = 1;
+const ELFDATA2LSB: u8    = 1;
+const ET_EXEC:     u16   = 2;
+const EM_ARM:      u16   = 40;
+const EHDR_SIZE:   usize = 52;
+const PHDR_SIZE:   usize = 32;
+
+// Program header types and flags
+const PT_LOAD:   u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR:   u32 = 6;
+const PF_X:      u32 = 1 << 0;
+const PF_W:      u32 = 1 << 1;
+
+// Auxiliary vector entries
+const AT_NULL:   usize = 0;
+const AT_PHDR:   usize = 3;
+const AT_PHENT:  usize = 4;
+const AT_PHNUM:  usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY:  usize = 9;
+const AT_RANDOM: usize = 25;
+
+// Most of the stack the arguments and environment may take
+const MAX_ARGS_SIZE: usize = process::STACK_PAGES * PAGE_SIZE / 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    NotElf,       // No ELF magic, or truncated headers
+    Unsupported,  // Not a little-endian 32-bit ARM executable, or dynamically linked
+    BadSegment,   // A segment is outside the file or the user address space, overlaps another, or
+                  // is both writable and executable
+    TooManyArgs,  // The arguments and environment take too much of the stack
+    Process(ProcError),
+}
+
+impl From<ProcError> for ElfError {
+    fn from(e: ProcError) -> ElfError {
+        match e {
+            ProcError::BadAddress => ElfError::BadSegment,
+            e                     => ElfError::Process(e),
+        }
+    }
+}
+
+fn u16_at(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+    match offset.checked_add(2).and_then(|end| image.get(offset..end)) {
+        Some(b) => Ok(b[0] as u16 | (b[1] as u16) << 8),
+        None    => Err(ElfError::NotElf),
+    }
+}
+
+fn u32_at(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+    match offset.checked_add(4).and_then(|end| image.get(offset..end)) {
+        Some(b) => Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24),
+        None    => Err(ElfError::NotElf),
+    }
+}
+
+// A program header
+struct Segment {
+    kind:   u32,
+    offset: usize,
+    vaddr:  usize,
+    filesz: usize,
+    memsz:  usize,
+    flags:  u32,
+}
+
+struct Header {
+    entry: usize,
+    phoff: usize,
+    phnum: usize,
+}
+
+fn parse_header(image: &[u8]) -> Result<Header, ElfError> {
+    if image.len() < EHDR_SIZE || &image[0..4] != b"\x7fELF" {
+        return Err(ElfError::NotElf);
+    }
+    if image[EI_CLASS] != ELFCLASS32 || image[EI_DATA] != ELFDATA2LSB
+        || u16_at(image, 16)? != ET_EXEC || u16_at(image, 18)? != EM_ARM {
+        return Err(ElfError::Unsupported);
+    }
+    if u16_at(image, 42)? as usize != PHDR_SIZE {
+        return Err(ElfError::NotElf);
+    }
+
+    Ok(Header {
+        entry: u32_at(image, 24)? as usize,
+        phoff: u32_at(image, 28)? as usize,
+        phnum: u16_at(image, 44)? as usize,
+    })
+}
+
+fn parse_segment(image: &[u8], offset: usize) -> Result<Segment, ElfError> {
+    Ok(Segment {
+        kind:   u32_at(image, offset)?,
+        offset: u32_at(image, offset + 4)? as usize,
+        vaddr:  u32_at(image, offset + 8)? as usize,
+        filesz: u32_at(image, offset + 16)? as usize,
+        memsz:  u32_at(image, offset + 20)? as usize,
+        flags:  u32_at(image, offset + 24)?,
+    })
+}
+
+// Maps a PT_LOAD segment and copies its contents in. The segment must lie between LOAD_BASE
+// (leaving the pages at 0 unmapped, so that null pointers fault) and the stack, outside the mmap()
+// window.
+fn load_segment(process: &mut Process, image: &[u8], seg: &Segment) -> Result<(), ElfError> {
+    let file_end = seg.offset.checked_add(seg.filesz).ok_or(ElfError::BadSegment)?;
+    let mem_end  = seg.vaddr.checked_add(seg.memsz).ok_or(ElfError::BadSegment)?;
+    let stack_bottom = process::STACK_TOP - process::STACK_PAGES * PAGE_SIZE;
+    let in_mmap = seg.vaddr < process::MMAP_END && mem_end > process::MMAP_BASE;
+    if seg.filesz > seg.memsz || file_end > image.len() {
+        return Err(ElfError::BadSegment);
+    }
+    if seg.vaddr < process::LOAD_BASE || mem_end > stack_bottom || in_mmap {
+        return Err(ElfError::BadSegment);
+    }
+    if seg.memsz == 0 {
+        return Ok(());
+    }
+
+    let attrs = match (seg.flags & PF_W != 0, seg.flags & PF_X != 0) {
+        (true,  true)  => return Err(ElfError::BadSegment),
+        (true,  false) => mmu::USER_DATA,
+        (false, true)  => mmu::USER_TEXT,
+        (false, false) => mmu::USER_RODATA,
+    };
+
+    let start = seg.vaddr & !(PAGE_SIZE - 1);
+    let pages = (vm::align_up(mem_end, PAGE_SIZE) - start) / PAGE_SIZE;
+    process.map(start, pages, attrs)?;
+    process.write(seg.vaddr, &image[seg.offset..file_end])?;
+    Ok(())
+}
+
+// Where the program headers are in the process's memory, for AT_PHDR: given by PT_PHDR, or found
+// in the segment that loads them
+fn phdr_address(segments: &[Segment], phoff: usize) -> usize {
+    if let Some(seg) = segments.iter().find(|s| s.kind == PT_PHDR) {
+        return seg.vaddr;
+    }
+    segments.iter()
+        .find(|s| s.kind == PT_LOAD && phoff >= s.offset && phoff - s.offset < s.filesz)
+        .map_or(0, |s| s.vaddr + (phoff - s.offset))
+}
+
+// Builds the initial stack, which ends at "top", and returns it with the stack pointer
+fn build_stack(top: usize, args: &[&str], env: &[&str], auxv: &[(usize, usize)]) -> Result<(Vec<u8>, usize), ElfError> {
+    // The strings and AT_RANDOM's bytes go at the top, the vectors below them
+    let strings: usize = args.iter().chain(env.iter()).map(|s| s.len() + 1).sum();
+    let vectors = 4 * (1 + args.len() + 1 + env.len() + 1 + 2 * (auxv.len() + 2));
+    let size = vm::align_up(strings + 16 + vectors, 8);
+    if size > MAX_ARGS_SIZE {
+        return Err(ElfError::TooManyArgs);
+    }
+
+    let sp = top - size;
+    let mut stack: Vec<u8> = Vec::with_capacity(size);
+    stack.resize(size, 0);
+    let mut words = 0;
+    let mut push = |stack: &mut Vec<u8>, value: usize| {
+        stack[words * 4..words * 4 + 4].copy_from_slice(&(value as u32).to_le_bytes());
+        words += 1;
+    };
+
+    let mut text = size - strings - 16;
+    let random = sp + text;
+    let seed = Timer::now_us();
+    for i in 0..16 {
+        stack[text + i] = (seed >> ((i % 8) * 8)) as u8 ^ (i as u8).wrapping_mul(0x9D);
+    }
+    text += 16;
+
+    push(&mut stack, args.len());
+    for list in [args, env].iter() {
+        for s in list.iter() {
+            push(&mut stack, sp + text);
+            stack[text..text + s.len()].copy_from_slice(s.as_bytes());
+            text += s.len() + 1;
+        }
+        push(&mut stack, 0);
+    }
+    for &(key, value) in auxv.iter().chain([(AT_RANDOM, random), (AT_NULL, 0)].iter()) {
+        push(&mut stack, key);
+        push(&mut stack, value);
+    }
+    Ok((stack, sp))
+}
+
+// Loads the executable "image" into a new process, called "name", and starts it with the
+// arguments "args" (args[0] being the program's name) and the environment "env"
+pub fn spawn_elf(name: &str, image: &[u8], args: &[&str], env: &[&str]) -> Result<Pid, ElfError> {
+    let header = parse_header(image)?;
+    let mut segments = Vec::with_capacity(header.phnum);
+    for i in 0..header.phnum {
+        let offset = i.checked_mul(PHDR_SIZE).and_then(|o| o.checked_add(header.phoff)).ok_or(ElfError::NotElf)?;
+        segments.push(parse_segment(image, offset)?);
+    }
+    if segments.iter().any(|s| s.kind == PT_INTERP) {
+        return Err(ElfError::Unsupported);
+    }
+
+    let mut process = Process::new(name)?;
+    for seg in segments.iter().filter(|s| s.kind == PT_LOAD) {
+        load_segment(&mut process, image, seg)?;
+    }
+
+    let top = process.map_stack()?;
+    let auxv = [
+        (AT_PHDR,   phdr_address(&segments, header.phoff)),
+        (AT_PHENT,  PHDR_SIZE),
+        (AT_PHNUM,  header.phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY,  header.entry),
+    ];
+    let (stack, sp) = build_stack(top, args, env, &auxv)?;
+    process.write(sp, &stack)?;
+
+    Ok(process.start(header.entry, sp, 0)?)
+}
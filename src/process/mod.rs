@@ -0,0 +1,446 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cache;
+use crate::frame::{self, Frame};
+use crate::fs::{self, FdTable, FileType, FsError};
+use crate::ipi;
+use crate::mmu::{self, Attrs, PAGE_SIZE};
+use crate::sched::{self, ThreadId};
+use crate::shell;
+use crate::sync::{Mutex, SpinLock};
+use crate::vm::{self, AddressSpace, VmError};
+
+/*
+ * User processes. A process is an address space of its own, covering the bottom half of the
+ * address space (see vm.rs), and a kernel thread that runs its code in user mode. The thread's
+ * kernel stack takes its system calls (see syscall.rs) and interrupts, and the scheduler switches
+ * the address space in and out with it.
+ *
+ * A process is built with new(), map() and write(), then start()ed. It ends by calling exit, or
+ * is killed if it faults (see exception.rs); its memory is freed at once, but it stays in the
+ * process table until wait() collects its exit status.
+ *
+ * The memory a process maps is its own: the frames are freed when it ends.
+ *
+ * Each process has a table of open file descriptors (see fs/file.rs), with the console open as its
+ * standard input, output and error (0, 1 and 2).
+ *
+ * Processes run either the built-in flat programs (see userdemo.S) or ELF executables (see
+ * elf.rs), which the shell's run command loads through the filesystem.
+ */
+
+mod elf;
+mod syscall;
+
+pub use self::elf::spawn_elf;
+
+extern "C" {
+    fn user_enter(entry: usize, sp: usize, arg: usize) -> !;
+
+    // Built-in user programs (see userdemo.S)
+    static user_demo_hello:      u8;
+    static user_demo_hello_end:  u8;
+    static user_demo_echo:       u8;
+    static user_demo_echo_end:   u8;
+    static user_demo_fault:      u8;
+    static user_demo_fault_end:  u8;
+    static user_demo_badptr:     u8;
+    static user_demo_badptr_end: u8;
+}
+
+pub type Pid = usize;
+
+// User address space layout
+pub const LOAD_BASE:   usize = 0x00010000; // Where flat images (the built-in programs) go
+pub const MMAP_BASE:   usize = 0x40000000; // mmap() hands out memory from here up
+pub const MMAP_END:    usize = 0x70000000;
+pub const STACK_TOP:   usize = 0x7FF00000;
+pub const STACK_PAGES: usize = 16;
+
+// Standard input, output and error
+const CONSOLE: &str = "/dev/console";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProcError {
+    OutOfMemory,
+    BadAddress,    // Outside the user address space, misaligned or already mapped
+    NoSuchProcess,
+}
+
+impl From<VmError> for ProcError {
+    fn from(e: VmError) -> ProcError {
+        match e {
+            VmError::OutOfMemory => ProcError::OutOfMemory,
+            _                    => ProcError::BadAddress,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExitStatus {
+    Exited(i32), // Called exit with this code
+    Killed,      // Faulted
+}
+
+// Pages mapped together, by map() or mmap()
+#[derive(Copy, Clone)]
+struct Region {
+    start: usize,
+    pages: usize,
+}
+
+struct Memory {
+    space:     AddressSpace,
+    regions:   Vec<Region>,
+    mmap_next: usize,
+}
+
+impl Memory {
+    fn map(&mut self, start: usize, pages: usize, attrs: Attrs) -> Result<(), ProcError> {
+        let end = start.checked_add(pages * PAGE_SIZE);
+        if start & (PAGE_SIZE - 1) != 0 || pages == 0 || end.map_or(true, |end| end > vm::USER_END) {
+            return Err(ProcError::BadAddress);
+        }
+
+        for i in 0..pages {
+            let addr = start + i * PAGE_SIZE;
+            let mapped = frame::alloc().ok_or(VmError::OutOfMemory).and_then(|page| {
+                unsafe { ptr::write_bytes(mmu::phys_to_virt(page.addr()) as *mut u8, 0, PAGE_SIZE); }
+                self.space.map(addr, page.addr(), attrs).map_err(|e| {
+                    frame::free(page);
+                    e
+                })
+            });
+            if let Err(e) = mapped {
+                self.unmap(Region { start: start, pages: i });
+                return Err(e.into());
+            }
+        }
+        self.regions.push(Region { start: start, pages: pages });
+        Ok(())
+    }
+
+    // Maps "pages" pages of zeroed memory wherever there is room in the mmap() area, leaving an
+    // unmapped page after them, and returns their address
+    fn map_anywhere(&mut self, pages: usize, attrs: Attrs) -> Result<usize, ProcError> {
+        let start = self.mmap_next;
+        if start >= MMAP_END || pages > (MMAP_END - start) / PAGE_SIZE {
+            return Err(ProcError::OutOfMemory);
+        }
+        self.map(start, pages, attrs)?;
+        self.mmap_next = start + (pages + 1) * PAGE_SIZE;
+        Ok(start)
+    }
+
+    fn unmap(&mut self, region: Region) {
+        for i in 0..region.pages {
+            if let Ok(phys) = self.space.unmap(region.start + i * PAGE_SIZE) {
+                frame::free(Frame::containing(phys));
+            }
+        }
+    }
+
+    // Unmaps and frees everything mapped
+    fn release(&mut self) {
+        while let Some(region) = self.regions.pop() {
+            self.unmap(region);
+        }
+    }
+
+    // Whether [addr, addr + len) is all mapped for user mode access (and writable, if "write")
+    fn check(&self, addr: usize, len: usize, write: bool) -> bool {
+        let end = match addr.checked_add(len) {
+            Some(end) if end <= vm::USER_END => end,
+            _                                => return false,
+        };
+
+        let mut page = addr & !(PAGE_SIZE - 1);
+        while page < end {
+            match self.space.translate(page) {
+                Some((_, attrs)) if attrs.user && (attrs.writable || !write) => {},
+                _ => return false,
+            }
+            page += PAGE_SIZE;
+        }
+        true
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+pub struct Process {
+    pid:     Pid,
+    name:    String,
+    memory:  Mutex<Memory>,
+    files:   SpinLock<FdTable>,
+    thread:  AtomicUsize,           // Set by start()
+    initial: (usize, usize, usize), // Entry point, stack pointer and r0, from start()
+    status:  SpinLock<Option<ExitStatus>>,
+}
+
+static NEXT_PID:  AtomicUsize = AtomicUsize::new(1);
+static PROCESSES: SpinLock<Vec<Arc<Process>>> = SpinLock::new(Vec::new());
+
+impl Process {
+    // A new process with an empty address space, and the console as its standard input, output
+    // and error if it can be opened
+    pub fn new(name: &str) -> Result<Process, ProcError> {
+        let space = AddressSpace::new_user()?;
+        let mut files = FdTable::new();
+        match fs::open(CONSOLE, fs::O_RDWR) {
+            Ok(console) => for _ in 0..3 {
+                let _ = files.insert(console.clone());
+            },
+            Err(e) => kwarn!("process: cannot open {}: {:?}", CONSOLE, e),
+        }
+
+        Ok(Process {
+            pid:     NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            name:    String::from(name),
+            memory:  Mutex::new(Memory { space: space, regions: Vec::new(), mmap_next: MMAP_BASE }),
+            files:   SpinLock::new(files),
+            thread:  AtomicUsize::new(0),
+            initial: (0, 0, 0),
+            status:  SpinLock::new(None),
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    // Maps "pages" pages of zeroed memory at "start"
+    pub fn map(&mut self, start: usize, pages: usize, attrs: Attrs) -> Result<(), ProcError> {
+        self.memory.lock().map(start, pages, attrs)
+    }
+
+    // Maps the stack, returning the initial stack pointer
+    pub fn map_stack(&mut self) -> Result<usize, ProcError> {
+        self.map(STACK_TOP - STACK_PAGES * PAGE_SIZE, STACK_PAGES, mmu::USER_DATA)?;
+        Ok(STACK_TOP)
+    }
+
+    // Copies "data" to "addr", which must be mapped, whatever its protection. The process need not
+    // be running: the copy goes through the kernel's linear map.
+    pub fn write(&mut self, addr: usize, data: &[u8]) -> Result<(), ProcError> {
+        let memory = self.memory.lock();
+        let mut done = 0;
+        while done < data.len() {
+            let at = addr + done;
+            let (phys, _) = memory.space.translate(at).ok_or(ProcError::BadAddress)?;
+            let len = (PAGE_SIZE - (at & (PAGE_SIZE - 1))).min(data.len() - done);
+            let dest = mmu::phys_to_virt(phys);
+
+            unsafe { ptr::copy_nonoverlapping(data[done..].as_ptr(), dest as *mut u8, len); }
+            cache::clean_range(dest, len);
+            done += len;
+        }
+        Ok(())
+    }
+
+    // Starts the process at "entry" in user mode, with the stack pointer "sp" and "arg" in r0
+    pub fn start(mut self, entry: usize, sp: usize, arg: usize) -> Result<Pid, ProcError> {
+        self.initial = (entry, sp, arg);
+        let pid = self.pid;
+        let process = Arc::new(self);
+
+        // Whatever write() put in memory may be code
+        ipi::icache_shootdown();
+
+        // The table is locked until the thread is recorded, so that it cannot make a system call
+        // before it can be found
+        let mut processes = PROCESSES.lock();
+        let raw = Arc::into_raw(process.clone()) as usize;
+        let thread = match sched::spawn("user", sched::PRIORITY_NORMAL, process_main, raw) {
+            Ok(thread) => thread,
+            Err(_)     => {
+                unsafe { drop(Arc::from_raw(raw as *const Process)); }
+                return Err(ProcError::OutOfMemory);
+            },
+        };
+        process.thread.store(thread, Ordering::Relaxed);
+        processes.push(process);
+        Ok(pid)
+    }
+}
+
+// The thread of each process: takes on its address space and drops to user mode
+fn process_main(arg: usize) {
+    let process = unsafe { Arc::from_raw(arg as *const Process) };
+    let (entry, sp, arg) = process.initial;
+    sched::set_user_table(Some(process.memory.lock().space.table_phys()));
+    drop(process);
+
+    unsafe { user_enter(entry, sp, arg); }
+}
+
+// The process the current thread runs, if any
+pub fn current() -> Option<Arc<Process>> {
+    let thread = sched::current();
+    PROCESSES.lock().iter().find(|p| p.thread.load(Ordering::Relaxed) == thread).cloned()
+}
+
+// Ends the current process, freeing its memory and closing its files. Its thread exits, leaving
+// the exit status for wait().
+pub fn exit_current(status: ExitStatus) -> ! {
+    let process = current().expect("process: not running a process");
+    *process.status.lock() = Some(status);
+    match status {
+        ExitStatus::Exited(code) => kdebug!("process: {} ({}) exited with {}", process.pid, process.name, code),
+        ExitStatus::Killed       => kwarn!("process: {} ({}) killed", process.pid, process.name),
+    }
+
+    sched::set_user_table(None);
+    process.memory.lock().release();
+    process.files.lock().clear();
+    drop(process);
+    sched::exit()
+}
+
+// Waits for process "pid" to end, removes it and returns its exit status
+pub fn wait(pid: Pid) -> Result<ExitStatus, ProcError> {
+    let thread: ThreadId = PROCESSES.lock().iter().find(|p| p.pid == pid)
+        .map(|p| p.thread.load(Ordering::Relaxed))
+        .ok_or(ProcError::NoSuchProcess)?;
+    sched::join(thread).map_err(|_| ProcError::NoSuchProcess)?;
+
+    let process = {
+        let mut processes = PROCESSES.lock();
+        let index = processes.iter().position(|p| p.pid == pid).ok_or(ProcError::NoSuchProcess)?;
+        processes.remove(index)
+    };
+    let status = *process.status.lock();
+    Ok(status.unwrap_or(ExitStatus::Killed))
+}
+
+/*
+ * Built-in programs and executables
+ */
+
+fn linked_image(start: &u8, end: &u8) -> &'static [u8] {
+    let start = start as *const u8;
+    unsafe { slice::from_raw_parts(start, end as *const u8 as usize - start as usize) }
+}
+
+fn demos() -> [(&'static str, &'static str, &'static [u8]); 4] {
+    unsafe {
+        [
+            ("hello",  "Write, mmap and sleep, then exit with the pid", linked_image(&user_demo_hello, &user_demo_hello_end)),
+            ("echo",   "Echo the serial input until q",                 linked_image(&user_demo_echo, &user_demo_echo_end)),
+            ("fault",  "Read kernel memory, and be killed",             linked_image(&user_demo_fault, &user_demo_fault_end)),
+            ("badptr", "Pass write() a kernel pointer",                 linked_image(&user_demo_badptr, &user_demo_badptr_end)),
+        ]
+    }
+}
+
+// Environment given to the executables the shell runs
+const RUN_ENV: [&str; 2] = ["HOME=/", "TERM=vt100"];
+
+// Starts a process running the flat binary "image", loaded at LOAD_BASE
+pub fn spawn_flat(name: &str, image: &[u8]) -> Result<Pid, ProcError> {
+    let mut process = Process::new(name)?;
+    process.map(LOAD_BASE, vm::align_up(image.len(), PAGE_SIZE) / PAGE_SIZE, mmu::USER_TEXT)?;
+    process.write(LOAD_BASE, image)?;
+    let sp = process.map_stack()?;
+    process.start(LOAD_BASE, sp, 0)
+}
+
+pub fn register_commands() {
+    shell::register("run",  "<file> [<args>...]", "Run an executable (from /bin unless a path), or list /bin", cmd_run);
+    shell::register("user", "[<program>]",        "Run a built-in user program, or list them",                cmd_user);
+}
+
+// Waits for process "pid" on behalf of shell command "cmd" and reports how it ended
+fn wait_and_report(cmd: &str, pid: Pid) -> i32 {
+    match wait(pid) {
+        Ok(ExitStatus::Exited(code)) => {
+            kprintln!("process {} exited with {}", pid, code);
+            shell::STATUS_OK
+        },
+        Ok(ExitStatus::Killed) => {
+            kprintln!("process {} was killed", pid);
+            shell::STATUS_FAILED
+        },
+        Err(e) => {
+            shell::error(format_args!("{}: cannot wait for process {}: {:?}", cmd, pid, e));
+            shell::STATUS_FAILED
+        },
+    }
+}
+
+// Where run looks for executables named without a "/"
+const RUN_PATH: &str = "/bin";
+
+fn cmd_run(args: &[&str]) -> i32 {
+    let name = match args.get(1) {
+        Some(name) => *name,
+        None       => {
+            let programs = fs::read_dir(RUN_PATH).unwrap_or_else(|_| Vec::new());
+            for e in programs.iter().filter(|e| e.file_type == FileType::File) {
+                let size = fs::stat(&format!("{}/{}", RUN_PATH, e.name)).map_or(0, |s| s.size);
+                kprintln!("{:<16} {} bytes", e.name, size);
+            }
+            return shell::STATUS_OK;
+        },
+    };
+
+    let path = if name.contains('/') { String::from(name) } else { format!("{}/{}", RUN_PATH, name) };
+    let image = match fs::read_all(&path) {
+        Ok(image) => image,
+        Err(FsError::NotFound) => {
+            shell::error(format_args!("run: no executable called {}", name));
+            return shell::STATUS_FAILED;
+        },
+        Err(e) => {
+            shell::error(format_args!("run: {}: {:?}", path, e));
+            return shell::STATUS_FAILED;
+        },
+    };
+
+    match spawn_elf(name, &image, &args[1..], &RUN_ENV) {
+        Ok(pid) => wait_and_report(args[0], pid),
+        Err(e)  => {
+            shell::error(format_args!("run: cannot load {}: {:?}", path, e));
+            shell::STATUS_FAILED
+        },
+    }
+}
+
+fn cmd_user(args: &[&str]) -> i32 {
+    let demos = demos();
+    let name = match args.get(1) {
+        Some(name) => *name,
+        None       => {
+            for &(name, help, image) in demos.iter() {
+                kprintln!("{:<8} {:<48} {} bytes", name, help, image.len());
+            }
+            return shell::STATUS_OK;
+        },
+    };
+
+    let image = match demos.iter().find(|d| d.0 == name) {
+        Some(&(_, _, image)) => image,
+        None                 => {
+            shell::error(format_args!("user: no program called {}", name));
+            return shell::STATUS_FAILED;
+        },
+    };
+
+    match spawn_flat(name, image) {
+        Ok(pid) => wait_and_report(args[0], pid),
+        Err(e)  => {
+            shell::error(format_args!("user: cannot start {}: {:?}", name, e));
+            shell::STATUS_FAILED
+        },
+    }
+}
@@ -0,0 +1,347 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use crate::cpu;
+use crate::exception::{self, ExceptionFrame};
+use crate::fs::{self, Fd, File, FileType, FsError, SeekFrom};
+use crate::mmu::{self, PAGE_SIZE};
+use crate::process::{self, ExitStatus, ProcError};
+use crate::sched;
+use crate::vm;
+
+/*
+ * System calls. User code makes one with "svc #0", the call number in r7 and its arguments in
+ * r0-r3; the result comes back in r0, with errors as negated error numbers. vectors.S saves the
+ * caller's registers on the thread's kernel stack as a TrapFrame and calls syscall_dispatch(),
+ * which runs with IRQs enabled so that calls may block or be preempted.
+ *
+ * Every pointer passed in is checked against the caller's mappings before the kernel touches it,
+ * so a bad one fails the call with EFAULT instead of faulting in the kernel. Only the process's own
+ * thread changes its mappings, so they cannot change between the check and the access.
+ *
+ *   r7  call                        returns
+ *   0   exit(code)                  does not return
+ *   1   write(fd, buf, len)         bytes written
+ *   2   read(fd, buf, len)          bytes read: 0 at the end of a file; the console waits for one
+ *   3   sleep(ms)                   0
+ *   4   mmap(len, prot)             address of "len" bytes (whole pages) of zeroed memory
+ *   5   getpid()                    the caller's process id
+ *   6   open(path, flags)           the lowest free file descriptor; "path" ends with a NUL
+ *   7   close(fd)                   0
+ *   8   seek(fd, offset, whence)    the new offset
+ *   9   readdir(fd, dirent)         1, with the next entry in "dirent"; 0 after the last entry
+ *   10  fstat(fd, stat)             0, with the file's type, mode and size in "stat"
+ */
+
+pub const SYS_EXIT:    usize = 0;
+pub const SYS_WRITE:   usize = 1;
+pub const SYS_READ:    usize = 2;
+pub const SYS_SLEEP:   usize = 3;
+pub const SYS_MMAP:    usize = 4;
+pub const SYS_GETPID:  usize = 5;
+pub const SYS_OPEN:    usize = 6;
+pub const SYS_CLOSE:   usize = 7;
+pub const SYS_SEEK:    usize = 8;
+pub const SYS_READDIR: usize = 9;
+pub const SYS_FSTAT:   usize = 10;
+
+// Error numbers (Linux's)
+pub const ENOENT:       usize = 2;
+pub const EBADF:        usize = 9;
+pub const ENOMEM:       usize = 12;
+pub const EFAULT:       usize = 14;
+pub const EBUSY:        usize = 16;
+pub const ENODEV:       usize = 19;
+pub const ENOTDIR:      usize = 20;
+pub const EISDIR:       usize = 21;
+pub const EINVAL:       usize = 22;
+pub const EMFILE:       usize = 24;
+pub const EROFS:        usize = 30;
+pub const ENAMETOOLONG: usize = 36;
+pub const ENOSYS:       usize = 38;
+pub const ELOOP:        usize = 40;
+
+// mmap() protection bits. Memory may be writable or executable, not both.
+pub const PROT_READ:  usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC:  usize = 1 << 2;
+
+// seek() origins
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+// Longest path open() takes, without the NUL
+const PATH_MAX: usize = 255;
+
+// Longest name readdir() gives, without the NUL
+const NAME_MAX: usize = 255;
+
+// File types, as readdir() and fstat() give them
+pub const TYPE_OTHER:     u32 = 0;
+pub const TYPE_FILE:      u32 = 1;
+pub const TYPE_DIRECTORY: u32 = 2;
+pub const TYPE_SYMLINK:   u32 = 3;
+pub const TYPE_DEVICE:    u32 = 4;
+
+// Bytes read() and write() pass to the file at a time
+const IO_CHUNK: usize = 256;
+
+const MODE_MASK: usize = 0x1F;
+const MODE_USR:  usize = 0x10;
+
+// The caller's registers, as saved by exc_svc (see vectors.S)
+#[repr(C)]
+pub struct TrapFrame {
+    pub sp:   usize, // User mode sp and lr
+    pub lr:   usize,
+    pub r:    [usize; 13],
+    pub pc:   usize, // Return address: the instruction after the SVC
+    pub spsr: usize,
+}
+
+type SysResult = Result<usize, usize>; // Error numbers on failure
+
+// What readdir() and fstat() fill in (struct dirent and struct stat in user/lib/user.h)
+#[repr(C)]
+struct UserDirent {
+    file_type: u32,
+    name:      [u8; NAME_MAX + 1], // NUL-terminated
+}
+
+#[repr(C)]
+struct UserStat {
+    file_type: u32,
+    mode:      u32,
+    size:      u32,
+}
+
+// Called from vectors.S, with IRQs masked, for every supervisor call
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(frame: &mut TrapFrame) {
+    if frame.spsr & MODE_MASK != MODE_USR {
+        let saved = ExceptionFrame { r: frame.r, pc: frame.pc - 4 };
+        exception::exception_fatal(exception::EXC_SVC, &saved, frame.spsr as u32);
+    }
+
+    cpu::enable_interrupts();
+    let (a0, a1, a2) = (frame.r[0], frame.r[1], frame.r[2]);
+    let result = match frame.r[7] {
+        SYS_EXIT    => process::exit_current(ExitStatus::Exited(a0 as i32)),
+        SYS_WRITE   => sys_write(a0, a1, a2),
+        SYS_READ    => sys_read(a0, a1, a2),
+        SYS_SLEEP   => sys_sleep(a0),
+        SYS_MMAP    => sys_mmap(a0, a1),
+        SYS_GETPID  => sys_getpid(),
+        SYS_OPEN    => sys_open(a0, a1),
+        SYS_CLOSE   => sys_close(a0),
+        SYS_SEEK    => sys_seek(a0, a1 as isize, a2),
+        SYS_READDIR => sys_readdir(a0, a1),
+        SYS_FSTAT   => sys_fstat(a0, a1),
+        _           => Err(ENOSYS),
+    };
+    frame.r[0] = match result {
+        Ok(value) => value,
+        Err(e)    => e.wrapping_neg(),
+    };
+    cpu::disable_interrupts();
+}
+
+// Whether the caller may access [addr, addr + len) (and write it, if "write")
+fn check_user(addr: usize, len: usize, write: bool) -> Result<(), usize> {
+    let process = process::current().ok_or(EFAULT)?;
+    let ok = process.memory.lock().check(addr, len, write);
+    if ok { Ok(()) } else { Err(EFAULT) }
+}
+
+fn errno(e: FsError) -> usize {
+    match e {
+        FsError::NotFound        => ENOENT,
+        FsError::NotADirectory   => ENOTDIR,
+        FsError::IsADirectory    => EISDIR,
+        FsError::NotSupported    => EINVAL,
+        FsError::ReadOnly        => EROFS,
+        FsError::BadDescriptor   => EBADF,
+        FsError::InvalidArgument => EINVAL,
+        FsError::TooManyOpen     => EMFILE,
+        FsError::TooManyLinks    => ELOOP,
+        FsError::Busy            => EBUSY,
+        FsError::NoDevice        => ENODEV,
+        FsError::NameTooLong     => ENAMETOOLONG,
+    }
+}
+
+fn user_type(file_type: FileType) -> u32 {
+    match file_type {
+        FileType::File      => TYPE_FILE,
+        FileType::Directory => TYPE_DIRECTORY,
+        FileType::Symlink   => TYPE_SYMLINK,
+        FileType::Device    => TYPE_DEVICE,
+        FileType::Other     => TYPE_OTHER,
+    }
+}
+
+// Copies "value" out to "addr", which need not be aligned
+fn copy_out<T>(addr: usize, value: T) -> Result<(), usize> {
+    check_user(addr, mem::size_of::<T>(), true)?;
+    unsafe { ptr::write_unaligned(addr as *mut T, value); }
+    Ok(())
+}
+
+// The caller's open file "fd"
+fn file(fd: Fd) -> Result<Arc<File>, usize> {
+    let process = process::current().ok_or(EBADF)?;
+    let file = process.files.lock().get(fd);
+    file.map_err(errno)
+}
+
+// Copies in the NUL-terminated string at "addr", checking each page as it reaches it
+fn user_path(addr: usize) -> Result<String, usize> {
+    let mut path = Vec::new();
+    loop {
+        let at = addr.checked_add(path.len()).ok_or(EFAULT)?;
+        if path.is_empty() || at & (PAGE_SIZE - 1) == 0 {
+            check_user(at, 1, false)?;
+        }
+        match unsafe { *(at as *const u8) } {
+            0                           => break,
+            _ if path.len() == PATH_MAX => return Err(ENAMETOOLONG),
+            ch                          => path.push(ch),
+        }
+    }
+    String::from_utf8(path).map_err(|_| EINVAL)
+}
+
+/*
+ * read() and write() go to the file a chunk at a time, so that a long transfer to or from a device
+ * holds the device's lock (the console's and the framebuffer's mask IRQs) for one chunk at most,
+ * and other threads get a turn in between. They stop at the first short chunk; a read also stops
+ * after one chunk from a device, as the next could wait for input. An error after some bytes have
+ * been moved ends the call with the count so far.
+ */
+
+fn sys_write(fd: usize, buf: usize, len: usize) -> SysResult {
+    check_user(buf, len, false)?;
+    let file = file(fd)?;
+    let data = unsafe { slice::from_raw_parts(buf as *const u8, len) };
+
+    let mut written = 0;
+    for part in data.chunks(IO_CHUNK) {
+        match file.write(part) {
+            Ok(count) => {
+                written += count;
+                if count < part.len() {
+                    break;
+                }
+            },
+            Err(e) if written == 0 => return Err(errno(e)),
+            Err(_) => break,
+        }
+    }
+    Ok(written)
+}
+
+fn sys_read(fd: usize, buf: usize, len: usize) -> SysResult {
+    check_user(buf, len, true)?;
+    let file = file(fd)?;
+    let data = unsafe { slice::from_raw_parts_mut(buf as *mut u8, len) };
+    let device = file.stat().file_type == FileType::Device;
+
+    let mut read = 0;
+    for part in data.chunks_mut(IO_CHUNK) {
+        match file.read(part) {
+            Ok(count) => {
+                read += count;
+                if count < part.len() || device {
+                    break;
+                }
+            },
+            Err(e) if read == 0 => return Err(errno(e)),
+            Err(_) => break,
+        }
+    }
+    Ok(read)
+}
+
+fn sys_sleep(ms: usize) -> SysResult {
+    sched::sleep_ms(ms as u64);
+    Ok(0)
+}
+
+fn sys_mmap(len: usize, prot: usize) -> SysResult {
+    let attrs = match prot {
+        PROT_READ                           => mmu::USER_RODATA,
+        p if p == PROT_READ | PROT_WRITE    => mmu::USER_DATA,
+        p if p == PROT_READ | PROT_EXEC     => mmu::USER_TEXT,
+        _                                   => return Err(EINVAL),
+    };
+    if len == 0 || len > vm::USER_END {
+        return Err(EINVAL);
+    }
+
+    let process = process::current().ok_or(EFAULT)?;
+    let mapped = process.memory.lock().map_anywhere(vm::align_up(len, PAGE_SIZE) / PAGE_SIZE, attrs);
+    match mapped {
+        Ok(addr)                     => Ok(addr),
+        Err(ProcError::OutOfMemory)  => Err(ENOMEM),
+        Err(_)                       => Err(EINVAL),
+    }
+}
+
+fn sys_getpid() -> SysResult {
+    process::current().map(|p| p.pid()).ok_or(EFAULT)
+}
+
+fn sys_open(path: usize, flags: usize) -> SysResult {
+    let path = user_path(path)?;
+    let file = fs::open(&path, flags).map_err(errno)?;
+    let process = process::current().ok_or(EFAULT)?;
+    let fd = process.files.lock().insert(file);
+    fd.map_err(errno)
+}
+
+fn sys_close(fd: usize) -> SysResult {
+    let process = process::current().ok_or(EBADF)?;
+    let file = process.files.lock().remove(fd);
+    file.map(|_| 0).map_err(errno)
+}
+
+fn sys_seek(fd: usize, offset: isize, whence: usize) -> SysResult {
+    let pos = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as usize),
+        SEEK_CUR                => SeekFrom::Current(offset),
+        SEEK_END                => SeekFrom::End(offset),
+        _                       => return Err(EINVAL),
+    };
+    file(fd)?.seek(pos).map_err(errno)
+}
+
+// "dirent" is checked first, so that once an entry is taken from the directory, copying it out
+// cannot fail; one whose name is too long stays put, failing every call with ENAMETOOLONG
+fn sys_readdir(fd: usize, dirent: usize) -> SysResult {
+    check_user(dirent, mem::size_of::<UserDirent>(), true)?;
+    let delivered = file(fd)?.read_dir(|entry| {
+        if entry.name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        let mut out = UserDirent { file_type: user_type(entry.file_type), name: [0; NAME_MAX + 1] };
+        out.name[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+        unsafe { ptr::write_unaligned(dirent as *mut UserDirent, out); }
+        Ok(())
+    });
+    match delivered.map_err(errno)? {
+        Some(()) => Ok(1),
+        None     => Ok(0),
+    }
+}
+
+fn sys_fstat(fd: usize, stat: usize) -> SysResult {
+    let st  = file(fd)?.stat();
+    let out = UserStat { file_type: user_type(st.file_type), mode: st.mode, size: st.size as u32 };
+    copy_out(stat, out)?;
+    Ok(0)
+}
@@ -0,0 +1,509 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::cpu;
+use crate::irq;
+use crate::mmu;
+use crate::shell;
+use crate::smp;
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard, Once};
+use crate::timer::Timer;
+use crate::vm::{self, KernelStack};
+
+/*
+ * Kernel threads and a preemptive priority scheduler. Each thread has its own kernel stack, on
+ * which context_switch (context.S) keeps its registers while it is not running. The highest
+ * priority ready thread runs, and threads of equal priority take turns, switched at every tick of
+ * system timer channel 1. The context that booted the kernel becomes the "main" thread (running
+ * the shell), and an idle thread at the lowest priority sleeps the core when nothing else is ready.
+ *
+ * Threads run on core 0, which takes the tick; the other cores are given work through
+ * smp::start_core() and ipi. Preemption happens on the way out of irq_dispatch, and is held off
+ * while the core holds a SpinLock or RwLock (see preempt_disable()); an IrqSpinLock holds it off
+ * by masking IRQs. Blocking calls must not be made with a lock held.
+ *
+ * A thread that returns, or calls exit(), stays as a zombie until join() reaps it and frees its
+ * stack.
+ *
+ * A thread running a user process (see process/mod.rs) has that process's address space in the
+ * bottom half (TTBR0), switched with the thread; other threads run with the bottom half unmapped.
+ */
+
+extern "C" {
+    fn context_switch(prev_sp: *mut usize, next_sp: usize);
+    fn thread_trampoline();
+}
+
+pub type ThreadId = usize;
+
+// Higher priorities run first
+pub const PRIORITY_IDLE:   u8 = 0;
+pub const PRIORITY_NORMAL: u8 = 10;
+
+// Pages of each thread's stack
+const STACK_PAGES: usize = 4;
+
+// The tick: system timer compare channel 1, which raises BCM2835 interrupt 1
+const TICK_CHANNEL: usize = 1;
+const TICK_IRQ:     usize = 1;
+const TICK_US:      u32   = 10_000;
+
+// Words in the frame context_switch() pops (see context.S), and where a new thread's entry point,
+// argument and first return address go in it
+const FRAME_WORDS: usize = 30;
+const FRAME_R4:    usize = 21;
+const FRAME_R5:    usize = 22;
+const FRAME_PC:    usize = 29;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SchedError {
+    OutOfMemory,
+    NoSuchThread,
+    Deadlock,     // A thread tried to join itself
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    Ready,
+    Running,
+    Sleeping(u64),     // Until this time (see Timer::now_us())
+    Joining(ThreadId), // Until this thread exits
+    Blocked,           // Until wake()
+    Exited,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match *self {
+            State::Ready       => "ready",
+            State::Running     => "running",
+            State::Sleeping(_) => "sleeping",
+            State::Joining(_)  => "joining",
+            State::Blocked     => "blocked",
+            State::Exited      => "exited",
+        }
+    }
+}
+
+struct Thread {
+    id:       ThreadId,
+    name:     &'static str,
+    priority: u8,
+    state:    State,
+    sp:       usize,               // Saved stack pointer, while not running
+    stack:    Option<KernelStack>, // None for main, which keeps the boot stack
+    woken:    bool,                // wake() was called while it was not blocked
+    switches: usize,               // Times it has been switched to
+    user:     Option<usize>,       // User address space (L1 table) of the process it runs, if any
+}
+
+struct Scheduler {
+    threads: Vec<Box<Thread>>, // Boxed so that a thread's "sp" stays put while it is switched out
+    next_id: ThreadId,
+}
+
+impl Scheduler {
+    fn index_of(&self, id: ThreadId) -> Option<usize> {
+        self.threads.iter().position(|t| t.id == id)
+    }
+
+    // The thread to run after the one at "current": the highest priority thread that is ready, or
+    // still running. The search starts after "current" and ends with it, so that threads of equal
+    // priority take turns.
+    fn pick_next(&self, current: usize) -> usize {
+        let count = self.threads.len();
+        let mut best: Option<usize> = None;
+
+        for i in (1..count + 1).map(|i| (current + i) % count) {
+            let t = &self.threads[i];
+            let runnable = t.state == State::Ready || (i == current && t.state == State::Running);
+            if runnable && best.map_or(true, |b| t.priority > self.threads[b].priority) {
+                best = Some(i);
+            }
+        }
+
+        best.expect("sched: nothing to run")
+    }
+
+    fn current(&self) -> usize {
+        self.index_of(current()).expect("sched: current thread missing")
+    }
+}
+
+static SCHED:        Once<IrqSpinLock<Scheduler>> = Once::new();
+static STARTED:      AtomicBool  = AtomicBool::new(false);
+static NEED_RESCHED: AtomicBool  = AtomicBool::new(false);
+static TICKS:        AtomicUsize = AtomicUsize::new(0);
+static NEXT_TICK:    AtomicUsize = AtomicUsize::new(0); // Low 32 bits of the counter
+
+fn scheduler() -> IrqSpinLockGuard<'static, Scheduler> {
+    SCHED.get().expect("sched: not initialised").lock()
+}
+
+// Turns the boot context into the "main" thread, creates the idle thread and starts the tick.
+// Needs the heap, kernel stacks and the interrupt controller.
+pub fn init() {
+    let main = Box::new(Thread {
+        id:       1,
+        name:     "main",
+        priority: PRIORITY_NORMAL,
+        state:    State::Running,
+        sp:       0,
+        stack:    None,
+        woken:    false,
+        switches: 1,
+        user:     None,
+    });
+    SCHED.call_once(move || {
+        let mut threads = Vec::new();
+        threads.push(main);
+        IrqSpinLock::new(Scheduler { threads: threads, next_id: 2 })
+    });
+    smp::this_cpu().current_task.set(1);
+
+    spawn("idle", PRIORITY_IDLE, idle, 0).expect("sched: cannot create the idle thread");
+
+    irq::register(TICK_IRQ, tick_irq);
+    arm_tick();
+    irq::enable(TICK_IRQ);
+    STARTED.store(true, Ordering::Release);
+}
+
+// Creates a thread running entry(arg), ready to run straight away
+pub fn spawn(name: &'static str, priority: u8, entry: fn(usize), arg: usize) -> Result<ThreadId, SchedError> {
+    let stack = vm::alloc_kernel_stack(STACK_PAGES).map_err(|_| SchedError::OutOfMemory)?;
+
+    // The frame context_switch() will pop, "returning" to thread_trampoline. Zeroing it also ends
+    // the chain of frame pointers for backtraces.
+    let sp = stack.top() - FRAME_WORDS * 4;
+    unsafe {
+        let frame = sp as *mut usize;
+        ptr::write_bytes(frame, 0, FRAME_WORDS);
+        *frame.offset(FRAME_R4 as isize) = entry as usize;
+        *frame.offset(FRAME_R5 as isize) = arg;
+        *frame.offset(FRAME_PC as isize) = thread_trampoline as usize;
+    }
+
+    let mut thread = Box::new(Thread {
+        id:       0,
+        name:     name,
+        priority: priority,
+        state:    State::Ready,
+        sp:       sp,
+        stack:    Some(stack),
+        woken:    false,
+        switches: 0,
+        user:     None,
+    });
+
+    let mut sched = scheduler();
+    let id = sched.next_id;
+    sched.next_id += 1;
+    thread.id = id;
+    sched.threads.push(thread);
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+    Ok(id)
+}
+
+// Where each new thread starts, from thread_trampoline (context.S), with IRQs still masked by
+// schedule()
+#[no_mangle]
+pub extern "C" fn sched_thread_start(entry: usize, arg: usize) -> ! {
+    cpu::enable_interrupts();
+
+    let entry: fn(usize) = unsafe { mem::transmute(entry) };
+    entry(arg);
+    exit();
+}
+
+// Runs when nothing else is ready, sleeping the core until the next interrupt
+fn idle(_arg: usize) {
+    loop {
+        cpu::wait_for_interrupt();
+    }
+}
+
+// The running thread (0 if the scheduler is not running on this core)
+pub fn current() -> ThreadId {
+    smp::this_cpu().current_task.get()
+}
+
+// Whether the caller may block: a thread on core 0, once the scheduler has started, with IRQs
+// enabled and no lock held
+pub fn can_block() -> bool {
+    STARTED.load(Ordering::Acquire) && cpu::core_id() == 0 && cpu::interrupts_enabled()
+        && smp::this_cpu().preempt_count.get() == 0
+}
+
+pub fn thread_count() -> usize {
+    scheduler().threads.len()
+}
+
+// Gives the current thread the user address space whose L1 table is at "table" (physical), or
+// takes it away, and switches to it. The scheduler switches it in and out with the thread.
+pub fn set_user_table(table: Option<usize>) {
+    let mut sched = scheduler();
+    let current = sched.current();
+    sched.threads[current].user = table;
+    mmu::set_user_table(table);
+}
+
+// Switches to the next thread to run, if that is not the current one. Callers set the current
+// thread's state first; one still Running stays runnable.
+fn schedule() {
+    if !STARTED.load(Ordering::Acquire) || cpu::core_id() != 0 {
+        return;
+    }
+
+    let saved = cpu::irq_save();
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+
+    let switch = {
+        let mut sched = scheduler();
+        let current = sched.current();
+        let next = sched.pick_next(current);
+
+        if next == current {
+            sched.threads[current].state = State::Running;
+            None
+        } else {
+            if sched.threads[current].state == State::Running {
+                sched.threads[current].state = State::Ready;
+            }
+            sched.threads[next].state = State::Running;
+            sched.threads[next].switches += 1;
+            smp::this_cpu().current_task.set(sched.threads[next].id);
+
+            if sched.threads[next].user != sched.threads[current].user {
+                mmu::set_user_table(sched.threads[next].user);
+            }
+
+            let prev_sp = &mut sched.threads[current].sp as *mut usize;
+            let next_sp = sched.threads[next].sp;
+            Some((prev_sp, next_sp))
+        }
+    };
+
+    // The lock is released, but IRQs stay masked until the switch is over
+    if let Some((prev_sp, next_sp)) = switch {
+        unsafe { context_switch(prev_sp, next_sp); }
+    }
+    cpu::irq_restore(saved);
+}
+
+// Called on the way out of irq_dispatch: switches threads if the tick or a wake-up asked for it
+// and the interrupted code allows it
+pub fn preempt() {
+    if NEED_RESCHED.load(Ordering::Relaxed) && smp::this_cpu().preempt_count.get() == 0 {
+        schedule();
+    }
+}
+
+/*
+ * Preemption control. Each core counts the reasons it must not be preempted (the spin locks it
+ * holds); the tick only switches threads while the count is zero, and the last preempt_enable()
+ * switches if the tick asked for it meanwhile. Pairs may nest.
+ */
+
+pub fn preempt_disable() {
+    let count = &smp::this_cpu().preempt_count;
+    count.set(count.get() + 1);
+}
+
+pub fn preempt_enable() {
+    let count = &smp::this_cpu().preempt_count;
+    count.set(count.get() - 1);
+
+    if count.get() == 0 && NEED_RESCHED.load(Ordering::Relaxed) && cpu::interrupts_enabled() {
+        schedule();
+    }
+}
+
+// Lets other ready threads of the same or higher priority run
+pub fn yield_now() {
+    if can_block() {
+        schedule();
+    }
+}
+
+// Sleeps for at least "ms" milliseconds, to the next tick. Busy-waits if the caller cannot block.
+pub fn sleep_ms(ms: u64) {
+    if !can_block() {
+        Timer::delay_us(ms * 1000);
+        return;
+    }
+
+    let saved = cpu::irq_save();
+    {
+        let mut sched = scheduler();
+        let current = sched.current();
+        sched.threads[current].state = State::Sleeping(Timer::now_us() + ms * 1000);
+    }
+    schedule();
+    cpu::irq_restore(saved);
+}
+
+// Blocks the current thread until wake() is called for it. If wake() has been called since the
+// thread last blocked, returns at once, so that a wake-up sent between checking a condition and
+// blocking is not lost. Wake-ups may be spurious: callers check their condition in a loop.
+pub fn block() {
+    assert!(can_block(), "sched: cannot block here");
+
+    let saved = cpu::irq_save();
+    let woken = {
+        let mut sched = scheduler();
+        let current = sched.current();
+        let thread = &mut sched.threads[current];
+        if thread.woken {
+            thread.woken = false;
+            true
+        } else {
+            thread.state = State::Blocked;
+            false
+        }
+    };
+    if !woken {
+        schedule();
+    }
+    cpu::irq_restore(saved);
+}
+
+// Makes thread "id" ready to run if it is blocked, or makes its next block() return at once if
+// not. Usable from interrupt handlers and on any core. Returns false if there is no such thread.
+pub fn wake(id: ThreadId) -> bool {
+    let mut sched = match SCHED.get() {
+        Some(sched) => sched.lock(),
+        None        => return false,
+    };
+    let index = match sched.index_of(id) {
+        Some(index) => index,
+        None        => return false,
+    };
+
+    let thread = &mut sched.threads[index];
+    if thread.state == State::Blocked {
+        thread.state = State::Ready;
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    } else {
+        thread.woken = true;
+    }
+    true
+}
+
+// Ends the current thread, waking any threads joining it
+pub fn exit() -> ! {
+    assert!(can_block(), "sched: cannot exit here");
+
+    cpu::disable_interrupts();
+    {
+        let mut sched = scheduler();
+        let id = current();
+        for thread in sched.threads.iter_mut() {
+            if thread.state == State::Joining(id) {
+                thread.state = State::Ready;
+            }
+        }
+        let current = sched.current();
+        sched.threads[current].state = State::Exited;
+    }
+    schedule();
+    unreachable!("sched: exited thread resumed");
+}
+
+// Waits for thread "id" to exit, then frees it
+pub fn join(id: ThreadId) -> Result<(), SchedError> {
+    assert!(can_block(), "sched: cannot block here");
+    if id == current() {
+        return Err(SchedError::Deadlock);
+    }
+
+    loop {
+        let saved = cpu::irq_save();
+        let found = {
+            let mut sched = scheduler();
+            let index = sched.index_of(id);
+            let found = match index {
+                None => Err(SchedError::NoSuchThread),
+                Some(index) if sched.threads[index].state == State::Exited => Ok(Some(sched.threads.remove(index))),
+                Some(_) => {
+                    let current = sched.current();
+                    sched.threads[current].state = State::Joining(id);
+                    Ok(None)
+                },
+            };
+            found
+        };
+
+        let zombie = match found {
+            Ok(Some(zombie)) => zombie,
+            Ok(None)         => {
+                schedule();
+                cpu::irq_restore(saved);
+                continue;
+            },
+            Err(e)           => {
+                cpu::irq_restore(saved);
+                return Err(e);
+            },
+        };
+
+        // Freeing the stack unmaps it, which takes locks and cross-calls the other cores
+        cpu::irq_restore(saved);
+        drop(zombie);
+        return Ok(());
+    }
+}
+
+// Tick interrupt: sets up the next tick, wakes the sleepers whose time has come and asks for the
+// current thread to be switched out on the way out of irq_dispatch
+fn tick_irq(_irq: usize) {
+    Timer::clear_match(TICK_CHANNEL);
+    arm_tick();
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    let now = Timer::now_us();
+    let mut sched = scheduler();
+    for thread in sched.threads.iter_mut() {
+        if let State::Sleeping(until) = thread.state {
+            if until <= now {
+                thread.state = State::Ready;
+            }
+        }
+    }
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+}
+
+// Sets the next tick a period after the last one, or after now if that has already gone by
+fn arm_tick() {
+    let now  = Timer::now_us() as u32;
+    let next = (NEXT_TICK.load(Ordering::Relaxed) as u32).wrapping_add(TICK_US);
+    let next = if (next.wrapping_sub(now) as i32) <= 0 { now.wrapping_add(TICK_US) } else { next };
+
+    NEXT_TICK.store(next as usize, Ordering::Relaxed);
+    Timer::set_match(TICK_CHANNEL, next);
+}
+
+pub fn register_commands() {
+    shell::register("ps", "", "Show the kernel threads", cmd_ps);
+}
+
+fn cmd_ps(_args: &[&str]) -> i32 {
+    // Copied out first: printing with the lock held would keep IRQs masked throughout
+    let threads: Vec<(ThreadId, &'static str, u8, State, usize, usize)> = scheduler().threads.iter()
+        .map(|t| (t.id, t.name, t.priority, t.state, t.switches, t.stack.as_ref().map_or(0, |s| s.bottom())))
+        .collect();
+
+    kprintln!("ID   NAME         PRIO STATE     SWITCHES   STACK");
+    for &(id, name, priority, state, switches, stack) in threads.iter() {
+        if stack == 0 {
+            kprintln!("{:<4} {:<12} {:<4} {:<9} {:<10} boot", id, name, priority, state.name(), switches);
+        } else {
+            kprintln!("{:<4} {:<12} {:<4} {:<9} {:<10} {:#010x}", id, name, priority, state.name(), switches, stack);
+        }
+    }
+    kprintln!("{} ticks of {} us", TICKS.load(Ordering::Relaxed), TICK_US);
+    shell::STATUS_OK
+}
@@ -0,0 +1,320 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::console;
+use crate::cpu;
+use crate::framebuffer::{Pixel24, TextStyle};
+use crate::fs;
+use crate::heap;
+use crate::lineedit::LineEditor;
+use crate::sync::RwLock;
+use crate::timer::Timer;
+use crate::uart::Uart;
+
+/*
+ * Command shell. Modules register named commands with register(); run() then reads lines from the
+ * console, splits them into arguments and dispatches to the matching command. A command receives
+ * its arguments with the command name as args[0] and returns a status (0 on success).
+ */
+
+pub type CommandFn = fn(args: &[&str]) -> i32;
+
+// Command exit statuses
+pub const STATUS_OK:     i32 = 0;
+pub const STATUS_FAILED: i32 = 1;
+pub const STATUS_USAGE:  i32 = 2;
+
+#[derive(Copy, Clone)]
+pub struct Command {
+    pub name:  &'static str,
+    pub usage: &'static str, // Argument synopsis, e.g. "<addr> <value>"
+    pub help:  &'static str, // One-line description
+    pub func:  CommandFn,
+}
+
+// Commands are mostly registered during boot, but may be added at any time. Lookups copy the
+// command out, so that it runs without the lock held.
+static COMMANDS: RwLock<Option<Vec<Command>>> = RwLock::new(None);
+
+// Number of lines remembered by the line editor
+const HISTORY_LEN: usize = 32;
+
+const COL_PROMPT: Pixel24 = Pixel24 {r: 100, g: 250, b: 128};
+const COL_INPUT:  Pixel24 = Pixel24 {r: 255, g: 255, b: 255};
+const COL_OUTPUT: Pixel24 = Pixel24 {r: 200, g: 200, b: 200};
+const COL_ERROR:  Pixel24 = Pixel24 {r: 250, g: 100, b: 100};
+
+// Adds a command. A command registered under an existing name replaces it.
+pub fn register(name: &'static str, usage: &'static str, help: &'static str, func: CommandFn) {
+    let mut commands = COMMANDS.write();
+    let commands = commands.get_or_insert_with(Vec::new);
+    let cmd = Command { name: name, usage: usage, help: help, func: func };
+
+    match commands.iter().position(|c| c.name == name) {
+        Some(i) => commands[i] = cmd,
+        None    => commands.push(cmd),
+    }
+}
+
+// Registered commands
+pub fn commands() -> Vec<Command> {
+    match *COMMANDS.read() {
+        Some(ref v) => v.clone(),
+        None        => Vec::new(),
+    }
+}
+
+pub fn find(name: &str) -> Option<Command> {
+    match *COMMANDS.read() {
+        Some(ref v) => v.iter().find(|c| c.name == name).cloned(),
+        None        => None,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TokenizeError {
+    UnterminatedQuote,
+    TrailingEscape,
+}
+
+// Splits a command line into arguments. Arguments are separated by whitespace; single quotes
+// preserve everything literally, double quotes allow backslash escapes, and a backslash outside
+// quotes escapes the next character.
+pub fn tokenize(line: &str) -> Result<Vec<String>, TokenizeError> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut chars = line.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            ' ' | '\t' => {
+                if in_token {
+                    tokens.push(current.clone());
+                    current.clear();
+                    in_token = false;
+                }
+            },
+            '\'' => {
+                in_token = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c)    => current.push(c),
+                        None       => return Err(TokenizeError::UnterminatedQuote),
+                    }
+                }
+            },
+            '"' => {
+                in_token = true;
+                loop {
+                    match chars.next() {
+                        Some('"')  => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => current.push(c),
+                            None    => return Err(TokenizeError::UnterminatedQuote),
+                        },
+                        Some(c)    => current.push(c),
+                        None       => return Err(TokenizeError::UnterminatedQuote),
+                    }
+                }
+            },
+            '\\' => {
+                in_token = true;
+                match chars.next() {
+                    Some(c) => current.push(c),
+                    None    => return Err(TokenizeError::TrailingEscape),
+                }
+            },
+            c => {
+                in_token = true;
+                current.push(c);
+            },
+        }
+    }
+
+    if in_token {
+        tokens.push(current);
+    }
+
+    Ok(tokens)
+}
+
+// Parses a decimal or 0x-prefixed hexadecimal number
+pub fn parse_number(s: &str) -> Option<usize> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        usize::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse::<usize>().ok()
+    }
+}
+
+// Runs a command line and returns its status
+pub fn execute(line: &str) -> i32 {
+    let tokens = match tokenize(line) {
+        Ok(t) => t,
+        Err(e) => {
+            error(format_args!("syntax error: {:?}", e));
+            return STATUS_USAGE;
+        },
+    };
+
+    if tokens.is_empty() {
+        return STATUS_OK;
+    }
+
+    let args: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+
+    match find(args[0]) {
+        Some(cmd) => {
+            console::set_screen_style(TextStyle::new(COL_OUTPUT));
+            (cmd.func)(&args)
+        },
+        None => {
+            error(format_args!("{}: command not found (try \"help\")", args[0]));
+            STATUS_FAILED
+        },
+    }
+}
+
+// Prints an error message in the error style
+pub fn error(args: ::core::fmt::Arguments) {
+    console::set_screen_style(TextStyle::new(COL_ERROR));
+    kprintln!("{}", args);
+}
+
+// Prints the usage line of a command, for commands given bad arguments
+pub fn usage(name: &str) -> i32 {
+    if let Some(cmd) = find(name) {
+        error(format_args!("usage: {} {}", cmd.name, cmd.usage));
+    }
+    STATUS_USAGE
+}
+
+// Command names starting with "prefix", for tab completion
+pub fn complete(prefix: &str) -> Vec<&'static str> {
+    commands().iter().filter(|c| c.name.starts_with(prefix)).map(|c| c.name).collect()
+}
+
+// The working directory, then "> "
+fn prompt() {
+    console::set_screen_style(TextStyle::new(COL_PROMPT));
+    kprint!("{}> ", fs::cwd());
+    console::set_screen_style(TextStyle::new(COL_INPUT));
+}
+
+// Reads and runs commands forever
+pub fn run() -> ! {
+    let mut editor = LineEditor::new(HISTORY_LEN);
+
+    loop {
+        let line = match editor.read_line(&prompt, &complete) {
+            Some(line) => line,
+            None       => continue,
+        };
+
+        let status = execute(&line);
+        if status != STATUS_OK {
+            error(format_args!("[status {}]", status));
+        }
+    }
+}
+
+/*
+ * Built-in commands
+ */
+
+// Linker symbols (see linker.ld)
+extern {
+    static __text_start:   u32;
+    static __text_end:     u32;
+    static __rodata_start: u32;
+    static __rodata_end:   u32;
+    static __data_start:   u32;
+    static __data_end:     u32;
+    static __bss_start:    u32;
+    static __bss_end:      u32;
+}
+
+pub fn register_builtins() {
+    register("help",   "[command]",       "List commands or describe one",       cmd_help);
+    register("clear",  "",                "Clear the screen",                    cmd_clear);
+    register("echo",   "[args...]",       "Print arguments",                     cmd_echo);
+    register("info",   "",                "Show system information",             cmd_info);
+    register("mem",    "",                "Show the kernel memory layout",       cmd_mem);
+    register("uptime", "",                "Show time since boot",                cmd_uptime);
+}
+
+fn cmd_help(args: &[&str]) -> i32 {
+    if args.len() > 1 {
+        return match find(args[1]) {
+            Some(cmd) => {
+                kprintln!("{} {}", cmd.name, cmd.usage);
+                kprintln!("  {}", cmd.help);
+                STATUS_OK
+            },
+            None => {
+                error(format_args!("help: no such command: {}", args[1]));
+                STATUS_FAILED
+            },
+        };
+    }
+
+    for cmd in commands() {
+        kprintln!("  {:<10} {}", cmd.name, cmd.help);
+    }
+    STATUS_OK
+}
+
+fn cmd_clear(_args: &[&str]) -> i32 {
+    console::with_screen(|fb| fb.clear());
+    if console::sinks() & console::SINK_SERIAL > 0 {
+        // ANSI: erase display and move the cursor home
+        Uart::puts("\x1b[2J\x1b[H");
+    }
+    STATUS_OK
+}
+
+fn cmd_echo(args: &[&str]) -> i32 {
+    for (i, arg) in args[1..].iter().enumerate() {
+        if i > 0 {
+            kprint!(" ");
+        }
+        kprint!("{}", arg);
+    }
+    kprintln!();
+    STATUS_OK
+}
+
+fn cmd_info(_args: &[&str]) -> i32 {
+    kprintln!("Kernel:      {} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    kprintln!("Core:        {}", cpu::core_id());
+    match console::with_screen(|fb| (fb.width, fb.height, fb.bpp, fb.size, fb.buf)) {
+        Some((width, height, bpp, size, buf)) => kprintln!("Framebuffer: {}x{}, {} bpp, {} bytes at {:p}", width, height, bpp, size, buf),
+        None                                  => kprintln!("Framebuffer: none"),
+    }
+    cmd_uptime(&[])
+}
+
+fn cmd_mem(_args: &[&str]) -> i32 {
+    let sections: [(&str, *const u32, *const u32); 4] = unsafe { [
+        (".text",   &__text_start   as *const u32, &__text_end   as *const u32),
+        (".rodata", &__rodata_start as *const u32, &__rodata_end as *const u32),
+        (".data",   &__data_start   as *const u32, &__data_end   as *const u32),
+        (".bss",    &__bss_start    as *const u32, &__bss_end    as *const u32),
+    ] };
+
+    for &(name, start, end) in sections.iter() {
+        kprintln!("  {:<8} {:#010x} - {:#010x} ({} bytes)", name, start as usize, end as usize, end as usize - start as usize);
+    }
+    let (heap_start, heap_end) = heap::region();
+    kprintln!("  {:<8} {:#010x} - {:#010x} ({} bytes)", "heap", heap_start, heap_end, heap_end - heap_start);
+    STATUS_OK
+}
+
+fn cmd_uptime(_args: &[&str]) -> i32 {
+    let us = Timer::now_us();
+    let secs = us / 1_000_000;
+    kprintln!("Uptime:      {}:{:02}:{:02}.{:03}", secs / 3600, (secs / 60) % 60, secs % 60, (us / 1000) % 1000);
+    STATUS_OK
+}
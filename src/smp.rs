@@ -0,0 +1,229 @@
+use core::cell::Cell;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::cpu;
+use crate::exception;
+use crate::ipi;
+use crate::mmio::{self, Mmio};
+use crate::mmu;
+use crate::shell;
+use crate::sync::SpinLock;
+use crate::timer::Timer;
+use crate::vm::{self, KernelStack};
+
+/*
+ * Multicore support. Core 0 boots the kernel while the firmware holds cores 1 - 3 in a loop
+ * watching their local mailbox 3 for an entry address. start_core() gives a core a stack and sends
+ * it to _start_secondary (main.S), which turns its MMU on and calls smp_secondary_main() below.
+ * The core then runs the function it was given, and waits for another once that returns.
+ *
+ * Each core reaches its own PerCpu through TPIDRPRW, a CP15 register private to the core, so it
+ * needs no lock to do so.
+ */
+
+extern "C" {
+    fn _start_secondary();
+    static mut secondary_boot: SecondaryBoot;
+}
+
+// Start-up parameters read by _start_secondary (see main.S)
+#[repr(C)]
+struct SecondaryBoot {
+    ttbr1:     usize, // Physical address of the kernel page table
+    stack_top: usize,
+    core:      usize,
+}
+
+// Pages of each secondary core's stack
+const STACK_PAGES: usize = 4;
+
+// How long start_core() waits for a core to come up
+const START_TIMEOUT_US: u64 = 1_000_000;
+
+// The firmware's spin loop watches mailbox 3
+const START_MAILBOX: usize = 3;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SmpError {
+    NoSuchCore,
+    Busy,        // The core has not taken the previous function yet
+    OutOfMemory,
+    Timeout,     // The core did not come up
+}
+
+// Data private to one core
+pub struct PerCpu {
+    pub id:            usize,
+    pub current_task:  Cell<usize>, // The running thread, for the scheduler (0: none)
+    pub preempt_count: Cell<usize>, // Reasons not to preempt it (see sched::preempt_disable())
+}
+
+static mut PER_CPU: [PerCpu; cpu::CORES] = [
+    PerCpu { id: 0, current_task: Cell::new(0), preempt_count: Cell::new(0) },
+    PerCpu { id: 1, current_task: Cell::new(0), preempt_count: Cell::new(0) },
+    PerCpu { id: 2, current_task: Cell::new(0), preempt_count: Cell::new(0) },
+    PerCpu { id: 3, current_task: Cell::new(0), preempt_count: Cell::new(0) },
+];
+
+// Shared between cores: whether each one has come up, is running a function, and the function
+// it is to run next (0: none)
+static ONLINE: [AtomicBool; cpu::CORES] = [
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+];
+static RUNNING: [AtomicBool; cpu::CORES] = [
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+];
+static ENTRY: [AtomicUsize; cpu::CORES] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+// Stacks of the secondary cores, kept for as long as the cores run. start_core() holds the lock
+// until the core is up, as there is only one secondary_boot block.
+static STACKS: SpinLock<[Option<KernelStack>; cpu::CORES]> = SpinLock::new([None, None, None, None]);
+
+// Sets up core 0's per-CPU data. Comes first, as taking a SpinLock needs it.
+pub fn init() {
+    unsafe { cpu::set_per_cpu(&PER_CPU[0] as *const PerCpu as usize); }
+    ONLINE[0].store(true, Ordering::Release);
+}
+
+// The current core's per-CPU data
+pub fn this_cpu() -> &'static PerCpu {
+    unsafe { &*(cpu::per_cpu() as *const PerCpu) }
+}
+
+pub fn is_online(core: usize) -> bool {
+    core < cpu::CORES && ONLINE[core].load(Ordering::Acquire)
+}
+
+pub fn online_cores() -> usize {
+    (0..cpu::CORES).filter(|&c| is_online(c)).count()
+}
+
+// Runs "entry" on core "core" (1 - 3), bringing the core up first if needed. Returns once the
+// core is up, without waiting for "entry".
+pub fn start_core(core: usize, entry: fn()) -> Result<(), SmpError> {
+    if core == 0 || core >= cpu::CORES {
+        return Err(SmpError::NoSuchCore);
+    }
+    if ENTRY[core].compare_exchange(0, entry as usize, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return Err(SmpError::Busy);
+    }
+
+    // Already up: it is waiting for an event in smp_secondary_main()
+    if ONLINE[core].load(Ordering::Acquire) {
+        cpu::send_event();
+        return Ok(());
+    }
+
+    let stack = match vm::alloc_kernel_stack(STACK_PAGES) {
+        Ok(stack) => stack,
+        Err(_)    => {
+            ENTRY[core].store(0, Ordering::Release);
+            return Err(SmpError::OutOfMemory);
+        },
+    };
+
+    let mut stacks = STACKS.lock();
+    unsafe {
+        secondary_boot = SecondaryBoot {
+            ttbr1:     vm::kernel().table_phys(),
+            stack_top: stack.top(),
+            core:      core,
+        };
+    }
+    stacks[core] = Some(stack);
+
+    // The core starts with its MMU off, so it needs the physical address
+    let entry_phys = mmu::virt_to_phys(_start_secondary as usize);
+    {
+        let _access = Mmio::enter();
+        Mmio::write(mmio::LOCAL_MAILBOX_SET0 + core * mmio::LOCAL_MAILBOX_CORE_STRIDE + START_MAILBOX * 4, entry_phys as u32);
+    }
+    cpu::send_event();
+
+    let start = Timer::now_us();
+    while !ONLINE[core].load(Ordering::Acquire) {
+        if Timer::now_us() - start > START_TIMEOUT_US {
+            // Frees the core for another try; should it come up late, it finds nothing to run.
+            // Its stack stays in STACKS, as it may yet be using it.
+            ENTRY[core].store(0, Ordering::Release);
+            return Err(SmpError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+// Called from main.S on each secondary core, on the stack start_core() gave it
+#[no_mangle]
+pub extern "C" fn smp_secondary_main(core: usize) -> ! {
+    unsafe { cpu::set_per_cpu(&PER_CPU[core] as *const PerCpu as usize); }
+    exception::init();
+    ipi::init_core();
+    cpu::enable_interrupts();
+
+    ONLINE[core].store(true, Ordering::Release);
+    kinfo!("smp: core {} online", core);
+
+    loop {
+        let entry = ENTRY[core].load(Ordering::Acquire);
+        if entry == 0 {
+            cpu::wait_for_event();
+            continue;
+        }
+
+        RUNNING[core].store(true, Ordering::Release);
+        ENTRY[core].store(0, Ordering::Release);
+
+        let entry: fn() = unsafe { mem::transmute(entry) };
+        entry();
+
+        RUNNING[core].store(false, Ordering::Release);
+    }
+}
+
+pub fn register_commands() {
+    shell::register("cpus", "[start <core>]", "Show the cores or say hello from one", cmd_cpus);
+}
+
+fn hello() {
+    kprintln!("Hello from core {}", this_cpu().id);
+}
+
+fn cmd_cpus(args: &[&str]) -> i32 {
+    match args.get(1) {
+        Some(&"start") => {
+            let core = match args.get(2).and_then(|c| shell::parse_number(c)) {
+                Some(core) => core,
+                None       => return shell::usage(args[0]),
+            };
+            if let Err(e) = start_core(core, hello) {
+                shell::error(format_args!("cannot start core {}: {:?}", core, e));
+                return shell::STATUS_FAILED;
+            }
+            return shell::STATUS_OK;
+        },
+        Some(_) => return shell::usage(args[0]),
+        None    => {},
+    }
+
+    kprintln!("CORE  STATE    STACK");
+    for core in 0..cpu::CORES {
+        let state = if !is_online(core) {
+            "offline"
+        } else if core == 0 || RUNNING[core].load(Ordering::Acquire) {
+            "running"
+        } else {
+            "idle"
+        };
+        let stack = STACKS.lock()[core].as_ref().map(|s| (s.bottom(), s.top()));
+        kprint!("{:<5} {:<8} ", core, state);
+        match stack {
+            Some((bottom, top)) => kprintln!("{:#010x} - {:#010x}", bottom, top),
+            None if core == 0   => kprintln!("boot stack"),
+            None                => kprintln!("-"),
+        }
+    }
+    shell::STATUS_OK
+}
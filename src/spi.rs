@@ -0,0 +1,97 @@
+use crate::dma::{self, ChannelKind, ControlBlock, DmaError, Dreq};
+use crate::dmabuf::{Coherency, DmaBuffer};
+use crate::gpio::{self, Function};
+use crate::mmio::{self, Mmio};
+use crate::sync::Mutex;
+
+/*
+ * SPI0 master, on GPIO 7 - 11 (CE1, CE0, MISO, MOSI, SCLK), driven by the DMA engine: one channel
+ * feeds the TX FIFO and another empties the RX FIFO, each paced by the controller's DREQ, while
+ * the calling thread sleeps in Channel::wait(). In DMA mode the FIFO is read and written a 32-bit
+ * word (4 bytes) at a time, so each transfer is padded with zeros to whole words and the bytes
+ * clocked in for the padding are dropped.
+ */
+
+// Bytes moved per pair of DMA transfers by transfer_dma() (DLEN holds at most 0xFFFF)
+const DMA_CHUNK: usize = 1024;
+
+// CLK: core clock (250 MHz) / 256, just under 1 MHz
+const CLOCK_DIVIDER: u32 = 256;
+
+// CS bits
+const CS_CLEAR: u32 = 3 << 4;  // Clear both FIFOs
+const CS_TA:    u32 = 1 << 7;  // Transfer active
+const CS_DMAEN: u32 = 1 << 8;
+const CS_ADCS:  u32 = 1 << 11; // Deassert chip select at the end of a DMA transfer
+const CS_DONE:  u32 = 1 << 16;
+
+// The GPIO pins SPI0 takes over (Alt0 function)
+const PINS: [usize; 5] = [7, 8, 9, 10, 11];
+
+// Held for the whole of a transfer, which may sleep
+static BUS: Mutex<()> = Mutex::new(());
+
+pub fn init() {
+    for &pin in PINS.iter() {
+        gpio::set_function(pin, Function::Alt0);
+    }
+
+    let _access = Mmio::enter();
+    Mmio::write(mmio::SPI0_CS,  CS_CLEAR);
+    Mmio::write(mmio::SPI0_CLK, CLOCK_DIVIDER);
+}
+
+// Sends "data" on CE0 (mode 0) and fills "received" with what came back, byte for byte
+pub fn transfer_dma(data: &[u8], received: &mut [u8]) -> Result<(), DmaError> {
+    assert_eq!(data.len(), received.len());
+
+    let _bus     = BUS.lock();
+    let mut tx   = dma::alloc_channel(ChannelKind::Any)?;
+    let mut rx   = dma::alloc_channel(ChannelKind::Any)?;
+    let mut tbuf = DmaBuffer::new([0u8; DMA_CHUNK], Coherency::Cached)?;
+    let rbuf     = DmaBuffer::new([0u8; DMA_CHUNK], Coherency::Cached)?;
+    let fifo = mmio::bus_addr(mmio::SPI0_FIFO);
+
+    for (out, back) in data.chunks(DMA_CHUNK).zip(received.chunks_mut(DMA_CHUNK)) {
+        let len = (out.len() + 3) & !3;
+        tbuf[..out.len()].copy_from_slice(out);
+        for b in tbuf[out.len()..len].iter_mut() {
+            *b = 0;
+        }
+        tbuf.sync_for_device();
+        rbuf.sync_for_device();
+
+        tx.chain().clear();
+        tx.chain().push(ControlBlock::to_peripheral(fifo, tbuf.bus_addr(), len, Dreq::SpiTx))?;
+        rx.chain().clear();
+        rx.chain().push(ControlBlock::from_peripheral(rbuf.bus_addr(), fifo, len, Dreq::SpiRx))?;
+
+        {
+            let _access = Mmio::enter();
+            Mmio::write(mmio::SPI0_CS,   CS_CLEAR);
+            Mmio::write(mmio::SPI0_DLEN, len as u32);
+            Mmio::write(mmio::SPI0_CS,   CS_TA | CS_DMAEN | CS_ADCS);
+        }
+
+        // The receiver is started first so that no byte clocked in is missed. On an error the
+        // channels are stopped as they are dropped.
+        let result = rx.start().and_then(|_| tx.start()).and_then(|_| tx.wait()).and_then(|_| rx.wait());
+        finish();
+        result?;
+
+        rbuf.sync_for_cpu();
+        back.copy_from_slice(&rbuf[..back.len()]);
+    }
+    Ok(())
+}
+
+// Waits for the last byte to leave the shift register, then ends the transfer
+fn finish() {
+    let _access = Mmio::enter();
+    for _ in 0..100000 {
+        if Mmio::read(mmio::SPI0_CS) & CS_DONE != 0 {
+            break;
+        }
+    }
+    Mmio::write(mmio::SPI0_CS, CS_CLEAR);
+}
@@ -0,0 +1,84 @@
+use alloc::collections::VecDeque;
+
+use crate::sync::{IrqSpinLock, WaitQueue};
+
+/*
+ * Bounded channel carrying values between threads, in order, with any number of senders and
+ * receivers. send() sleeps while the channel is full and recv() while it is empty. try_send() and
+ * try_recv() never wait and are safe from interrupt handlers, which is how drivers hand received
+ * data to threads. The slots are allocated up front, so sending never allocates.
+ */
+
+pub struct Channel<T> {
+    queue:    IrqSpinLock<VecDeque<T>>,
+    capacity: usize,
+    readers:  WaitQueue, // Waiting for a value
+    writers:  WaitQueue, // Waiting for room
+}
+
+impl<T> Channel<T> {
+    pub fn new(capacity: usize) -> Channel<T> {
+        let capacity = if capacity < 1 { 1 } else { capacity };
+        Channel {
+            queue:    IrqSpinLock::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity,
+            readers:  WaitQueue::new(),
+            writers:  WaitQueue::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Sends "value", waiting for room if the channel is full
+    pub fn send(&self, value: T) {
+        let mut value = Some(value);
+        self.writers.wait_until(|| match self.try_send(value.take().unwrap()) {
+            Ok(())    => true,
+            Err(back) => {
+                value = Some(back);
+                false
+            },
+        });
+    }
+
+    // Sends "value" if there is room, or hands it back
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        {
+            let mut queue = self.queue.lock();
+            if queue.len() >= self.capacity {
+                return Err(value);
+            }
+            queue.push_back(value);
+        }
+        self.readers.wake_one();
+        Ok(())
+    }
+
+    // Receives the oldest value, waiting for one if the channel is empty
+    pub fn recv(&self) -> T {
+        let mut received = None;
+        self.readers.wait_until(|| {
+            received = self.try_recv();
+            received.is_some()
+        });
+        received.unwrap()
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        let value = self.queue.lock().pop_front();
+        if value.is_some() {
+            self.writers.wake_one();
+        }
+        value
+    }
+}
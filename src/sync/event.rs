@@ -0,0 +1,43 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::sync::WaitQueue;
+
+/*
+ * Event flags: 32 bits that stay set until cleared. Threads wait for any or all of a set of
+ * them; set() wakes every waiter to check, and is safe from interrupt handlers.
+ */
+
+pub struct EventFlags {
+    bits:    AtomicU32,
+    waiters: WaitQueue,
+}
+
+impl EventFlags {
+    pub const fn new() -> EventFlags {
+        EventFlags { bits: AtomicU32::new(0), waiters: WaitQueue::new() }
+    }
+
+    pub fn set(&self, bits: u32) {
+        self.bits.fetch_or(bits, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    pub fn clear(&self, bits: u32) {
+        self.bits.fetch_and(!bits, Ordering::Release);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.bits.load(Ordering::Acquire)
+    }
+
+    // Waits for any of "mask" to be set, returning those that are
+    pub fn wait_any(&self, mask: u32) -> u32 {
+        self.waiters.wait_until(|| self.get() & mask != 0);
+        self.get() & mask
+    }
+
+    // Waits for all of "mask" to be set
+    pub fn wait_all(&self, mask: u32) {
+        self.waiters.wait_until(|| self.get() & mask == mask);
+    }
+}
@@ -0,0 +1,121 @@
+use alloc::vec::Vec;
+
+use crate::sched;
+use crate::shell;
+
+/*
+ * Synchronisation primitives for state shared between cores and with interrupt handlers:
+ *  - SpinLock:    mutual exclusion between cores (LDREX/STREX, see spinlock.S)
+ *  - IrqSpinLock: the same, with IRQs masked while held, for data interrupt handlers also use
+ *  - RwLock:      many readers or one writer, for read-mostly data
+ *  - Once:        one-time initialisation of statics such as driver singletons
+ * None of them allocate, so they are usable from the console, the log and the heap itself.
+ *
+ * The lock-free MpscQueue carries messages between cores; it allocates its slots up front, when
+ * created.
+ *
+ * The blocking primitives put a waiting thread to sleep (see sched.rs) instead of spinning. They
+ * are for threads; interrupt handlers may only use the calls that never wait (release(), set(),
+ * try_send() and the like).
+ *  - WaitQueue:  threads waiting for a condition, woken by whoever changes it
+ *  - Mutex:      mutual exclusion for longer critical sections
+ *  - Semaphore:  a count of resources
+ *  - EventFlags: bits to wait for any or all of
+ *  - Channel:    a bounded queue of values, from any number of senders to any number of receivers
+ */
+
+mod channel;
+mod event;
+mod mutex;
+mod once;
+mod queue;
+mod rwlock;
+mod semaphore;
+mod spinlock;
+mod waitqueue;
+
+pub use self::channel::Channel;
+pub use self::event::EventFlags;
+pub use self::mutex::Mutex;
+pub use self::once::Once;
+pub use self::queue::MpscQueue;
+pub use self::rwlock::RwLock;
+pub use self::semaphore::Semaphore;
+pub use self::spinlock::{IrqSpinLock, IrqSpinLockGuard, SpinLock, SpinLockGuard};
+pub use self::waitqueue::WaitQueue;
+
+pub fn register_commands() {
+    shell::register("synctest", "[threads]", "Test the blocking primitives with worker threads", cmd_synctest);
+}
+
+// Worker threads (one event flag each), turns each takes, and how many may be inside at once
+const TEST_MAX_THREADS: usize = 8;
+const TEST_ROUNDS:      usize = 100;
+const TEST_SLOTS:       usize = 2;
+
+struct TestState {
+    count:      usize, // Turns taken
+    inside:     usize, // Workers holding a slot
+    max_inside: usize,
+}
+
+static TEST_STATE: Mutex<TestState> = Mutex::new(TestState { count: 0, inside: 0, max_inside: 0 });
+static TEST_SEM:   Semaphore        = Semaphore::new(TEST_SLOTS);
+static TEST_DONE:  EventFlags       = EventFlags::new();
+
+// Takes TEST_ROUNDS turns in a slot, yielding in each so that others contend for the slots and
+// the lock, then sets its flag
+fn test_worker(index: usize) {
+    for _ in 0..TEST_ROUNDS {
+        TEST_SEM.acquire();
+        {
+            let mut state = TEST_STATE.lock();
+            state.count  += 1;
+            state.inside += 1;
+            state.max_inside = state.max_inside.max(state.inside);
+        }
+        sched::yield_now();
+        TEST_STATE.lock().inside -= 1;
+        TEST_SEM.release();
+    }
+    TEST_DONE.set(1 << index);
+}
+
+fn cmd_synctest(args: &[&str]) -> i32 {
+    let threads = match args.get(1).map(|n| shell::parse_number(n)) {
+        Some(Some(n)) if n > 0 && n <= TEST_MAX_THREADS => n,
+        Some(_) => return shell::usage(args[0]),
+        None    => 4,
+    };
+
+    {
+        let mut state = TEST_STATE.lock();
+        *state = TestState { count: 0, inside: 0, max_inside: 0 };
+    }
+    TEST_DONE.clear(!0);
+
+    let mut ids = Vec::new();
+    for i in 0..threads {
+        match sched::spawn("synctest", sched::PRIORITY_NORMAL, test_worker, i) {
+            Ok(id) => ids.push(id),
+            Err(e) => {
+                shell::error(format_args!("cannot spawn: {:?}", e));
+                break;
+            },
+        }
+    }
+
+    let mask = (1 << ids.len()) - 1;
+    TEST_DONE.wait_all(mask);
+    for &id in ids.iter() {
+        let _ = sched::join(id);
+    }
+
+    let state = TEST_STATE.lock();
+    kprintln!("{} threads took {} turns, at most {} at once ({} slots)",
+              ids.len(), state.count, state.max_inside, TEST_SLOTS);
+    if ids.len() < threads || state.count != ids.len() * TEST_ROUNDS || state.max_inside > TEST_SLOTS {
+        return shell::STATUS_FAILED;
+    }
+    shell::STATUS_OK
+}
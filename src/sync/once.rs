@@ -0,0 +1,57 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cpu;
+use crate::sched;
+
+/*
+ * One-time initialisation, for driver singletons and other statics that cannot be built at
+ * compile time. Whoever calls call_once() first runs the initialiser; cores calling it meanwhile
+ * wait for it to finish. Calling it again from inside the initialiser (or from an interrupt
+ * handler that interrupted it) deadlocks. The initialiser is not preempted, so that no thread on
+ * the same core waits for it through a whole time slice.
+ */
+
+const INCOMPLETE: usize = 0;
+const RUNNING:    usize = 1;
+const COMPLETE:   usize = 2;
+
+pub struct Once<T> {
+    state: AtomicUsize,
+    data:  UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Once<T> {
+        Once { state: AtomicUsize::new(INCOMPLETE), data: UnsafeCell::new(None) }
+    }
+
+    // Returns the value, running "init" to create it if no one has yet
+    pub fn call_once<F: FnOnce() -> T>(&self, init: F) -> &T {
+        sched::preempt_disable();
+        if self.state.compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire).is_ok() {
+            unsafe { *self.data.get() = Some(init()); }
+            self.state.store(COMPLETE, Ordering::Release);
+            cpu::send_event();
+        } else {
+            while self.state.load(Ordering::Acquire) != COMPLETE {
+                cpu::wait_for_event();
+            }
+        }
+        sched::preempt_enable();
+
+        unsafe { (*self.data.get()).as_ref().unwrap() }
+    }
+
+    // The value, if it has been created
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            unsafe { (*self.data.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+}
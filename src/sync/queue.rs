@@ -0,0 +1,91 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ * Bounded lock-free queue for passing work between cores (and to and from interrupt handlers),
+ * from any number of producers to one consumer at a time. It never blocks: push() hands the value
+ * back when the queue is full and pop() returns None when it is empty, so the caller decides
+ * whether to retry, wait or drop. Keeping to one consumer is up to the users; breaking that
+ * corrupts the queue. Capacities are rounded up to a power of two. Positions are free-running
+ * counters, so that full and empty can be told apart without a spare slot.
+ */
+
+fn slots_for(capacity: usize) -> usize {
+    if capacity < 2 { 2 } else { capacity.next_power_of_two() }
+}
+
+// A slot of an MpscQueue. "seq" says whose turn it is: equal to the slot's position when a
+// producer may fill it, one past when the consumer may empty it.
+struct Slot<T> {
+    seq:   AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+pub struct MpscQueue<T> {
+    slots: Box<[Slot<T>]>,
+    mask:  usize,
+    head:  AtomicUsize, // Next position to pop
+    tail:  AtomicUsize, // Next position to claim for a push
+}
+
+unsafe impl<T: Send> Sync for MpscQueue<T> {}
+unsafe impl<T: Send> Send for MpscQueue<T> {}
+
+impl<T> MpscQueue<T> {
+    pub fn new(capacity: usize) -> MpscQueue<T> {
+        let slots = slots_for(capacity);
+        MpscQueue {
+            slots: (0..slots).map(|i| Slot { seq: AtomicUsize::new(i), value: UnsafeCell::new(None) })
+                             .collect::<Vec<_>>().into_boxed_slice(),
+            mask:  slots - 1,
+            head:  AtomicUsize::new(0),
+            tail:  AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        self.slots[head & self.mask].seq.load(Ordering::Acquire) != head.wrapping_add(1)
+    }
+
+    // Producer side; safe from any number of cores at once. Returns the value if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        loop {
+            let pos  = self.tail.load(Ordering::Relaxed);
+            let slot = &self.slots[pos & self.mask];
+            let seq  = slot.seq.load(Ordering::Acquire);
+
+            if seq == pos {
+                // The slot is free: claim the position, unless another producer got there first
+                if self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                    unsafe { *slot.value.get() = Some(value); }
+                    slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                    return Ok(());
+                }
+            } else if (seq.wrapping_sub(pos) as isize) < 0 {
+                // Still holds the value pushed a lap ago
+                return Err(value);
+            }
+        }
+    }
+
+    // Consumer side
+    pub fn pop(&self) -> Option<T> {
+        let pos  = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[pos & self.mask];
+        if slot.seq.load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return None;
+        }
+
+        let value = unsafe { (*slot.value.get()).take() };
+        slot.seq.store(pos.wrapping_add(self.slots.len()), Ordering::Release);
+        self.head.store(pos.wrapping_add(1), Ordering::Relaxed);
+        value
+    }
+}
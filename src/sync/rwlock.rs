@@ -0,0 +1,98 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::usize;
+
+use crate::cpu;
+use crate::sched;
+
+/*
+ * Reader-writer spinlock for read-mostly data: any number of readers, or one writer. Readers
+ * are let in whenever no writer holds the lock, so a steady stream of them can starve writers.
+ * Like SpinLock, it must not be used from interrupt handlers, and holders are not preempted.
+ */
+
+// Lock state: the number of readers, or WRITER
+const WRITER: usize = !(usize::MAX >> 1);
+
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    data:  UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Send> Send for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> RwLock<T> {
+        RwLock { state: AtomicUsize::new(0), data: UnsafeCell::new(data) }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        sched::preempt_disable();
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER != 0 {
+                cpu::wait_for_event();
+            } else if self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return RwLockReadGuard { lock: self };
+            }
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        sched::preempt_disable();
+        while self.state.compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            cpu::wait_for_event();
+        }
+        RwLockWriteGuard { lock: self }
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        // The last reader out wakes any writer waiting
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            cpu::send_event();
+        }
+        sched::preempt_enable();
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        cpu::send_event();
+        sched::preempt_enable();
+    }
+}
@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::WaitQueue;
+
+/*
+ * Counting semaphore. acquire() takes one from the count, sleeping while it is zero; release()
+ * gives one back and is safe from interrupt handlers, as is try_acquire().
+ */
+
+pub struct Semaphore {
+    count:   AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore { count: AtomicUsize::new(count), waiters: WaitQueue::new() }
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        loop {
+            let count = self.count.load(Ordering::Relaxed);
+            if count == 0 {
+                return false;
+            }
+            if self.count.compare_exchange(count, count - 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return true;
+            }
+        }
+    }
+
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
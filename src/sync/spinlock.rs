@@ -0,0 +1,181 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::cpu;
+use crate::sched;
+
+extern "C" {
+    fn spin_lock(lock: *mut u32);
+    fn spin_try_lock(lock: *mut u32) -> u32;
+    fn spin_unlock(lock: *mut u32);
+}
+
+// The lock word shared by SpinLock and IrqSpinLock (see spinlock.S)
+struct RawSpinLock {
+    word: UnsafeCell<u32>,
+}
+
+impl RawSpinLock {
+    const fn new() -> RawSpinLock {
+        RawSpinLock { word: UnsafeCell::new(0) }
+    }
+
+    fn lock(&self) {
+        unsafe { spin_lock(self.word.get()); }
+    }
+
+    fn try_lock(&self) -> bool {
+        unsafe { spin_try_lock(self.word.get()) != 0 }
+    }
+
+    fn unlock(&self) {
+        unsafe { spin_unlock(self.word.get()); }
+    }
+
+    fn is_locked(&self) -> bool {
+        unsafe { ptr::read_volatile(self.word.get()) != 0 }
+    }
+}
+
+/*
+ * SpinLock: for data shared between cores but never touched from interrupt handlers. Taking it
+ * from a handler that interrupted the holder on the same core would deadlock; use IrqSpinLock
+ * for that. The holder is not preempted, so that threads waiting for it do not spin through the
+ * holder's time slice.
+ */
+
+pub struct SpinLock<T> {
+    raw:  RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T: 'a> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock { raw: RawSpinLock::new(), data: UnsafeCell::new(data) }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        sched::preempt_disable();
+        self.raw.lock();
+        SpinLockGuard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        sched::preempt_disable();
+        if self.raw.try_lock() {
+            Some(SpinLockGuard { lock: self })
+        } else {
+            sched::preempt_enable();
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    // Releases the lock whoever holds it. Only for the panic path, which must get its message out
+    // even if it interrupted the holder.
+    pub unsafe fn force_unlock(&self) {
+        self.raw.unlock();
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+        sched::preempt_enable();
+    }
+}
+
+/*
+ * IrqSpinLock: masks IRQs on the current core for as long as it is held, so the data can also be
+ * used from interrupt handlers. Keep the critical sections short.
+ */
+
+pub struct IrqSpinLock<T> {
+    raw:  RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqSpinLock<T> {}
+unsafe impl<T: Send> Send for IrqSpinLock<T> {}
+
+pub struct IrqSpinLockGuard<'a, T: 'a> {
+    lock:  &'a IrqSpinLock<T>,
+    saved: u32, // IRQ state to restore on release (see cpu::irq_save())
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> IrqSpinLock<T> {
+        IrqSpinLock { raw: RawSpinLock::new(), data: UnsafeCell::new(data) }
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let saved = cpu::irq_save();
+        self.raw.lock();
+        IrqSpinLockGuard { lock: self, saved: saved }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let saved = cpu::irq_save();
+        if self.raw.try_lock() {
+            Some(IrqSpinLockGuard { lock: self, saved: saved })
+        } else {
+            cpu::irq_restore(saved);
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    // See SpinLock::force_unlock()
+    pub unsafe fn force_unlock(&self) {
+        self.raw.unlock();
+    }
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+        cpu::irq_restore(self.saved);
+    }
+}
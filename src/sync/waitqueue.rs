@@ -0,0 +1,83 @@
+use alloc::vec::Vec;
+
+use crate::sched::{self, ThreadId};
+use crate::sync::IrqSpinLock;
+
+/*
+ * Threads waiting for a condition, such as a semaphore having a count, to become true. Whoever
+ * makes it true calls wake_one() or wake_all(), which is safe from interrupt handlers. Waiters
+ * check the condition after joining the queue and again each time they are woken, so a wake-up
+ * that comes before a waiter blocks is not lost (see sched::block()), and one meant for another
+ * waiter only costs a spurious check.
+ *
+ * Callers that cannot block (before the scheduler starts, other cores, IRQs masked) spin on the
+ * condition instead.
+ */
+
+pub struct WaitQueue {
+    waiters: IrqSpinLock<Vec<ThreadId>>, // Oldest first
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue { waiters: IrqSpinLock::new(Vec::new()) }
+    }
+
+    // Returns once "condition" returns true, blocking the thread in between. "condition" is
+    // called without the queue's lock held.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        if condition() {
+            return;
+        }
+        if !sched::can_block() {
+            while !condition() {}
+            return;
+        }
+
+        let me = sched::current();
+        loop {
+            self.add(me);
+            if condition() {
+                self.remove(me);
+                return;
+            }
+            sched::block();
+        }
+    }
+
+    fn add(&self, id: ThreadId) {
+        let mut waiters = self.waiters.lock();
+        if !waiters.contains(&id) {
+            waiters.push(id);
+        }
+    }
+
+    fn remove(&self, id: ThreadId) {
+        self.waiters.lock().retain(|&w| w != id);
+    }
+
+    // Wakes the longest waiting thread. Returns false if there was none.
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        if waiters.is_empty() {
+            return false;
+        }
+        sched::wake(waiters.remove(0));
+        true
+    }
+
+    // Wakes every waiting thread, returning how many there were
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        for &id in waiters.iter() {
+            sched::wake(id);
+        }
+        let count = waiters.len();
+        waiters.clear();
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+}
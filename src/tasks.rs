@@ -0,0 +1,55 @@
+use alloc::string::String;
+use core::fmt::Write;
+
+use crate::console;
+use crate::framebuffer::{Pixel24, TextStyle};
+use crate::gpio::{self, Function};
+use crate::sched;
+use crate::timer::Timer;
+
+/*
+ * Background threads started at boot, running alongside the shell:
+ *  - blink:  flashes the activity LED as a sign of life
+ *  - status: keeps the uptime and the number of threads in the top right-hand corner of the screen
+ */
+
+const BLINK_MS:  u64 = 500;
+const STATUS_MS: u64 = 1000;
+
+// Width of the status line, in characters of 8 pixels
+const STATUS_CHARS: u32 = 20;
+
+pub fn start() {
+    let tasks: [(&'static str, fn(usize)); 2] = [("blink", blink), ("status", status)];
+    for &(name, entry) in tasks.iter() {
+        if let Err(e) = sched::spawn(name, sched::PRIORITY_NORMAL, entry, 0) {
+            kwarn!("cannot start {}: {:?}", name, e);
+        }
+    }
+}
+
+fn blink(_arg: usize) {
+    gpio::set_function(gpio::ACT_LED, Function::Output);
+    loop {
+        gpio::set(gpio::ACT_LED, !gpio::get(gpio::ACT_LED));
+        sched::sleep_ms(BLINK_MS);
+    }
+}
+
+fn status(_arg: usize) {
+    let style = TextStyle::new(Pixel24 {r: 250, g: 200, b: 100});
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let _ = write!(line, "{:>8}s {:>2} threads", Timer::now_ms() / 1000, sched::thread_count());
+
+        console::with_screen(|fb| {
+            let x = fb.width.saturating_sub(STATUS_CHARS * 8);
+            for (i, ch) in line.chars().enumerate() {
+                fb.putchar(ch, x + i as u32 * 8, 0, &style);
+            }
+        });
+        sched::sleep_ms(STATUS_MS);
+    }
+}
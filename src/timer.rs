@@ -0,0 +1,44 @@
+use crate::mmio::{self, Mmio};
+
+// The BCM2835 system timer counts microseconds since power-on in a 64-bit free-running counter.
+// Its four compare channels raise interrupts 0 - 3 when the low 32 bits of the counter match them;
+// the GPU firmware uses channels 0 and 2.
+pub struct Timer { }
+
+impl Timer {
+    // Microseconds since boot
+    pub fn now_us() -> u64 {
+        // CHI may tick over between the two reads of CLO and CHI, so read CHI either side of CLO
+        // and retry until it is stable
+        let _access = Mmio::enter();
+        loop {
+            let hi: u32 = Mmio::read(mmio::SYSTIMER_CHI);
+            let lo: u32 = Mmio::read(mmio::SYSTIMER_CLO);
+            if Mmio::read(mmio::SYSTIMER_CHI) == hi {
+                return ((hi as u64) << 32) | lo as u64;
+            }
+        }
+    }
+
+    pub fn now_ms() -> u64 {
+        Timer::now_us() / 1000
+    }
+
+    // Busy-waits for at least the given number of microseconds
+    pub fn delay_us(us: u64) {
+        let start = Timer::now_us();
+        while Timer::now_us() - start < us { }
+    }
+
+    // Arms compare channel "channel" to match when the low 32 bits of the counter reach "at"
+    pub fn set_match(channel: usize, at: u32) {
+        let _access = Mmio::enter();
+        Mmio::write(mmio::SYSTIMER_C0 + channel * 4, at);
+    }
+
+    // Clears a match on "channel", acknowledging its interrupt
+    pub fn clear_match(channel: usize) {
+        let _access = Mmio::enter();
+        Mmio::write(mmio::SYSTIMER_CS, 1 << channel);
+    }
+}
@@ -1,24 +1,74 @@
-use mmio::{self, Mmio};
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::dma::{self, ChannelKind, ControlBlock, DmaError, Dreq};
+use crate::dmabuf::{Coherency, DmaBuffer};
+use crate::executor::WakerSlot;
+use crate::irq;
+use crate::mmio::{self, Mmio};
+use crate::sched;
+use crate::sync::{Channel, IrqSpinLock, Once};
 
 extern "C" {
     fn delay(count: u32);
 }
 
+// Bytes sent per DMA transfer by write_dma()
+const DMA_CHUNK: usize = 1024;
+
+// DMACR: raise the TX DREQ for the DMA engine
+const DMACR_TXDMAE: u32 = 1 << 1;
+
+// IFLS: TX and RX FIFO levels of 1/2 (the TX DREQ is raised at or below the TX level)
+const IFLS_HALF: u32 = (2 << 3) | 2;
+
+// FR: receive FIFO empty, transmit FIFO full
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+// IMSC/MIS/ICR: receive (FIFO at its level) and receive timeout (data left below the level)
+// interrupts, and transmit (FIFO down to its level)
+const INT_RX: u32 = (1 << 4) | (1 << 6);
+const INT_TX: u32 = 1 << 5;
+
+// BCM2835 interrupt number of UART0
+const UART_IRQ: usize = 57;
+
+// Bytes received that no one has read yet
+const RX_QUEUE_LEN: usize = 256;
+
+// Received bytes, sent by the interrupt handler once enable_interrupts() has been called
+static RX: Once<Channel<u8>> = Once::new();
+
+// Futures waiting for received data and for room to transmit
+static RX_WAKER: WakerSlot = WakerSlot::new();
+static TX_WAKER: WakerSlot = WakerSlot::new();
+
+// The interrupts unmasked in IMSC, changed both by threads and by the handler
+static IMSC: IrqSpinLock<u32> = IrqSpinLock::new(0);
+
 pub struct Uart { }
 
 impl Uart {
     pub fn init() {
+        let _access = Mmio::enter();
+
         // Disables all aspects of UART using CR
         Mmio::write(mmio::UART0_CR, 0x0);
 
         // Disable GPIO pins: writing 0 to GPPUD marks that pins should be disabled,
         // and GPPUDCLK0 marks which pins. Finally, writing 0 to GPPUDCLK0 fialises
         // the changes.
-        Mmio::write(mmio::GPPUD, 0x0);
-        unsafe { delay(150); }
-        Mmio::write(mmio::GPPUDCLK0, (1 << 14) | (1 << 15));
-        unsafe { delay(150); }
-        Mmio::write(mmio::GPPUDCLK0, 0x0);
+        {
+            let _gpio = Mmio::enter();
+            Mmio::write(mmio::GPPUD, 0x0);
+            unsafe { delay(150); }
+            Mmio::write(mmio::GPPUDCLK0, (1 << 14) | (1 << 15));
+            unsafe { delay(150); }
+            Mmio::write(mmio::GPPUDCLK0, 0x0);
+        }
 
         // Set all flags in the Interrupt Clear Register (clear all pending
         // interrupts)
@@ -47,6 +97,8 @@ impl Uart {
     }
 
     pub fn putc(ch: u8) {
+        let _access = Mmio::enter();
+
         // Loop until flag register bit 5 (TXFF: transmit FIFO is full) is unset
         loop {
             let reg: u32 = Mmio::read(mmio::UART0_FR);
@@ -59,17 +111,99 @@ impl Uart {
         Mmio::write(mmio::UART0_DR, ch as u32);
     }
 
+    // Takes received data through the RX interrupt from now on, so that getc() can block the
+    // calling thread instead of polling, and lets the async calls wait for interrupts. Needs the
+    // heap and the interrupt controller.
+    pub fn enable_interrupts() {
+        RX.call_once(|| Channel::new(RX_QUEUE_LEN));
+        irq::register(UART_IRQ, Uart::handle_irq);
+        {
+            let _access = Mmio::enter();
+            Mmio::write(mmio::UART0_ICR, 0x7ff);
+        }
+        Uart::set_interrupts(INT_RX, true);
+        irq::enable(UART_IRQ);
+    }
+
+    fn set_interrupts(bits: u32, on: bool) {
+        let mut imsc = IMSC.lock();
+        *imsc = if on { *imsc | bits } else { *imsc & !bits };
+
+        let _access = Mmio::enter();
+        Mmio::write(mmio::UART0_IMSC, *imsc);
+    }
+
+    // RX: moves the FIFO into the channel (dropping what does not fit). TX:
+    // masks the interrupt again, as the FIFO stays below its level until written, and wakes the
+    // writer.
+    fn handle_irq(_irq: usize) {
+        let status = {
+            let _access = Mmio::enter();
+            Mmio::read(mmio::UART0_MIS)
+        };
+
+        if status & INT_RX != 0 {
+            if let Some(rx) = RX.get() {
+                while let Some(ch) = Uart::read_fifo() {
+                    let _ = rx.try_send(ch);
+                }
+            }
+            {
+                let _access = Mmio::enter();
+                Mmio::write(mmio::UART0_ICR, INT_RX);
+            }
+            RX_WAKER.wake();
+        }
+
+        if status & INT_TX != 0 {
+            Uart::set_interrupts(INT_TX, false);
+            TX_WAKER.wake();
+        }
+    }
+
+    fn tx_full() -> bool {
+        let _access = Mmio::enter();
+        Mmio::read(mmio::UART0_FR) & FR_TXFF != 0
+    }
+
+    // A byte from the receive FIFO, if there is one
+    fn read_fifo() -> Option<u8> {
+        let _access = Mmio::enter();
+        if Mmio::read(mmio::UART0_FR) & FR_RXFE == 0 {
+            Some(Mmio::read(mmio::UART0_DR) as u8)
+        } else {
+            None
+        }
+    }
+
+    // Waits for a byte. A thread sleeps on the RX channel until the interrupt brings one; anything
+    // else polls.
     pub fn getc() -> u8 {
-        // Loop until flag register bit 4 (RXFE: receive FIFO is empty) is unset
-        loop {
-            let reg: u32 = Mmio::read(mmio::UART0_FR);
-            if reg & (1 << 4) == 0 {
-                break;
+        let rx = match RX.get() {
+            Some(rx) => rx,
+            None     => loop {
+                if let Some(ch) = Uart::read_fifo() {
+                    return ch;
+                }
+            },
+        };
+
+        if !sched::can_block() {
+            // The handler may not get to run (IRQs masked, or another core), so take from the
+            // FIFO too, after anything already queued
+            loop {
+                if let Some(ch) = rx.try_recv().or_else(Uart::read_fifo) {
+                    return ch;
+                }
             }
         }
 
-        // Read character from data register and return
-        Mmio::read(mmio::UART0_DR) as u8
+        rx.recv()
+    }
+
+    // A byte that has already been received, if there is one
+    pub fn try_getc() -> Option<u8> {
+        RX.get().and_then(|rx| rx.try_recv()).or_else(Uart::read_fifo)
     }
 
     pub fn puts(s: &str) {
@@ -77,4 +211,122 @@ impl Uart {
             Uart::putc(*ch);
         }
     }
+
+    // Waits for a received byte without blocking the thread
+    pub async fn read_async() -> u8 {
+        RxByte.await
+    }
+
+    // Sends "data", waiting for room in the FIFO without blocking the thread
+    pub async fn write_async(data: &[u8]) {
+        for &ch in data {
+            TxSpace.await;
+            Uart::putc(ch);
+        }
+    }
+
+    // Sends "data" with the DMA engine, paced by the UART's DREQ: the CPU sleeps until each chunk
+    // has gone rather than polling the FIFO for every byte. The engine only writes whole words, so
+    // each byte goes through a bounce buffer as a word of its own.
+    pub fn write_dma(data: &[u8]) -> Result<(), DmaError> {
+        let mut channel = dma::alloc_channel(ChannelKind::Any)?;
+        let mut words   = DmaBuffer::new([0u32; DMA_CHUNK], Coherency::Cached)?;
+        let dr = mmio::bus_addr(mmio::UART0_DR);
+
+        {
+            let _access = Mmio::enter();
+            Mmio::write(mmio::UART0_IFLS,  IFLS_HALF);
+            Mmio::write(mmio::UART0_DMACR, DMACR_TXDMAE);
+        }
+
+        let mut result = Ok(());
+        for chunk in data.chunks(DMA_CHUNK) {
+            for (word, byte) in words.iter_mut().zip(chunk) {
+                *word = *byte as u32;
+            }
+            words.sync_for_device();
+
+            let block = ControlBlock::to_peripheral(dr, words.bus_addr(), chunk.len() * 4, Dreq::UartTx);
+            {
+                let chain = channel.chain();
+                chain.clear();
+                result = chain.push(block);
+            }
+            if result.is_ok() {
+                result = channel.run();
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+
+        let _access = Mmio::enter();
+        Mmio::write(mmio::UART0_DMACR, 0);
+        result
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Uart::puts(s);
+        Ok(())
+    }
+}
+
+// Resolves to the next received byte
+struct RxByte;
+
+impl Future for RxByte {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u8> {
+        let rx = match RX.get() {
+            Some(rx) => rx,
+            None     => {
+                // No interrupts to wait for: poll the FIFO whenever the executor gets round to it
+                return match Uart::read_fifo() {
+                    Some(ch) => Poll::Ready(ch),
+                    None     => {
+                        cx.waker().wake_by_ref();
+                        Poll::Pending
+                    },
+                };
+            },
+        };
+
+        if let Some(ch) = rx.try_recv() {
+            return Poll::Ready(ch);
+        }
+        RX_WAKER.register(cx.waker());
+        match rx.try_recv() {
+            Some(ch) => Poll::Ready(ch),
+            None     => Poll::Pending,
+        }
+    }
+}
+
+// Resolves once the transmit FIFO has room for a byte
+struct TxSpace;
+
+impl Future for TxSpace {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if !Uart::tx_full() {
+            return Poll::Ready(());
+        }
+        if RX.get().is_none() {
+            // No interrupts yet, as for RxByte
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        TX_WAKER.register(cx.waker());
+        Uart::set_interrupts(INT_TX, true);
+        if Uart::tx_full() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
 }
@@ -0,0 +1,450 @@
+use core::ptr;
+
+use crate::cache;
+use crate::frame::{self, Frame};
+use crate::ipi;
+use crate::mmu::{self, Attrs, L1_FAULT, L1_PAGE_TABLE, L1_SECTION, L2_FAULT, PAGE_SHIFT, PAGE_SIZE, SECTION_SHIFT, SECTION_SIZE};
+use crate::sync::{Once, SpinLock, SpinLockGuard};
+
+/*
+ * Virtual address spaces. An AddressSpace owns an L1 page table and the L2 tables hanging off it,
+ * and maps, unmaps and changes the protection of 4 KiB pages (or whole 1 MiB sections). There is
+ * one kernel address space, covering the top half of the address space through TTBR1 (see mmu.rs),
+ * and any number of user address spaces covering the bottom half, activated through TTBR0.
+ *
+ * Page tables are reached through the kernel's linear map, and the frames backing mapped pages
+ * belong to whoever mapped them: unmap() hands the physical address back rather than freeing it.
+ *
+ * The cores do not cache faulting translations, so new mappings only need a local TLB
+ * invalidation; removing or changing one is broadcast to every online core (see ipi.rs).
+ */
+
+// User address spaces cover 0 - USER_END; the kernel everything above
+pub const USER_END: usize = mmu::KERNEL_BASE;
+
+// Area holding kernel stacks. Each stack gets a KSTACK_SLOT-sized slot whose lowest page is never
+// mapped, so running off the bottom of a stack faults instead of corrupting its neighbour.
+pub const KSTACK_BASE:      usize = 0xD0000000;
+pub const KSTACK_END:       usize = 0xE0000000;
+pub const KSTACK_SLOT:      usize = 64 * 1024;
+pub const KSTACK_MAX_PAGES: usize = KSTACK_SLOT / PAGE_SIZE - 1;
+const KSTACK_SLOTS:         usize = (KSTACK_END - KSTACK_BASE) / KSTACK_SLOT;
+
+// Area holding uncached mappings of RAM (see dmabuf.rs)
+pub const UNCACHED_BASE:    usize = 0xE0000000;
+pub const UNCACHED_END:     usize = 0xE1000000;
+const UNCACHED_PAGES:       usize = (UNCACHED_END - UNCACHED_BASE) / PAGE_SIZE;
+
+const L1_ENTRIES:  usize = 4096;
+const L2_ENTRIES:  usize = 256;
+const USER_FRAMES: usize = 2; // User L1 tables: 2048 entries (8 KiB, 8 KiB aligned)
+const KERN_FRAMES: usize = 4; // Kernel L1 table: 4096 entries (16 KiB, 16 KiB aligned)
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VmError {
+    OutOfRange,    // The address does not belong to this address space
+    Misaligned,    // Addresses must be page (or section) aligned
+    AlreadyMapped,
+    NotMapped,
+    OutOfMemory,   // No frame for a page table
+}
+
+pub fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+pub struct AddressSpace {
+    table:  Frame, // First frame of the L1 table
+    frames: usize, // Size of the L1 table in frames
+    first:  usize, // Range of L1 entries translated by this address space
+    end:    usize,
+}
+
+impl AddressSpace {
+    fn new(frames: usize, first: usize, end: usize) -> Result<AddressSpace, VmError> {
+        let table = frame::alloc_contiguous(frames, frames).ok_or(VmError::OutOfMemory)?;
+        let space = AddressSpace { table: table, frames: frames, first: first, end: end };
+        for idx in 0..(frames * frame::FRAME_SIZE / 4) {
+            unsafe { ptr::write_volatile(space.l1().offset(idx as isize), L1_FAULT); }
+        }
+        Ok(space)
+    }
+
+    // A new, empty user address space
+    pub fn new_user() -> Result<AddressSpace, VmError> {
+        AddressSpace::new(USER_FRAMES, 0, USER_END >> SECTION_SHIFT)
+    }
+
+    // Physical address of the L1 table, for TTBR0/TTBR1
+    pub fn table_phys(&self) -> usize {
+        self.table.addr()
+    }
+
+    fn l1(&self) -> *mut u32 {
+        mmu::phys_to_virt(self.table.addr()) as *mut u32
+    }
+
+    fn l1_entry(&self, idx: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.l1().offset(idx as isize)) }
+    }
+
+    fn set_l1_entry(&mut self, idx: usize, desc: u32) {
+        unsafe { ptr::write_volatile(self.l1().offset(idx as isize), desc); }
+    }
+
+    // L1 index of "addr", if this address space translates it
+    fn index(&self, addr: usize) -> Result<usize, VmError> {
+        let idx = addr >> SECTION_SHIFT;
+        if idx >= self.first && idx < self.end { Ok(idx) } else { Err(VmError::OutOfRange) }
+    }
+
+    // The L2 table (by virtual address) an L1 page table descriptor points at
+    fn l2(desc: u32) -> *mut u32 {
+        mmu::phys_to_virt((desc & !0x3FF) as usize) as *mut u32
+    }
+
+    fn l2_slot(addr: usize) -> isize {
+        ((addr >> PAGE_SHIFT) & (L2_ENTRIES - 1)) as isize
+    }
+
+    // Allocates an empty L2 table, returning its physical address. L2 tables are 1 KiB; each gets a
+    // frame of its own, which wastes the rest of it but keeps freeing them simple.
+    fn new_l2() -> Result<usize, VmError> {
+        let table = frame::alloc().ok_or(VmError::OutOfMemory)?;
+        let l2 = mmu::phys_to_virt(table.addr()) as *mut u32;
+        for i in 0..L2_ENTRIES {
+            unsafe { ptr::write_volatile(l2.offset(i as isize), L2_FAULT); }
+        }
+        Ok(table.addr())
+    }
+
+    // Replaces the section at L1 index "idx" with an L2 table mapping the same memory with pages
+    fn split_section(&mut self, idx: usize) -> Result<*mut u32, VmError> {
+        let desc  = self.l1_entry(idx);
+        let table = AddressSpace::new_l2()?;
+        let l2    = mmu::phys_to_virt(table) as *mut u32;
+        let bits  = Attrs::from_section(desc).page_bits();
+        let base  = desc as usize & !(SECTION_SIZE - 1);
+
+        for i in 0..L2_ENTRIES {
+            unsafe { ptr::write_volatile(l2.offset(i as isize), (base + i * PAGE_SIZE) as u32 | bits); }
+        }
+        self.set_l1_entry(idx, table as u32 | L1_PAGE_TABLE);
+        ipi::tlb_shootdown_all();
+        Ok(l2)
+    }
+
+    // The L2 table covering "addr", splitting a section or creating an empty table as needed
+    fn l2_table(&mut self, addr: usize, create: bool) -> Result<*mut u32, VmError> {
+        let idx  = self.index(addr)?;
+        let desc = self.l1_entry(idx);
+
+        match desc & 0b11 {
+            L1_PAGE_TABLE => Ok(AddressSpace::l2(desc)),
+            L1_SECTION    => self.split_section(idx),
+            _ if create   => {
+                let table = AddressSpace::new_l2()?;
+                self.set_l1_entry(idx, table as u32 | L1_PAGE_TABLE);
+                Ok(mmu::phys_to_virt(table) as *mut u32)
+            },
+            _ => Err(VmError::NotMapped),
+        }
+    }
+
+    // Maps the page at "addr" to the physical page at "phys"
+    pub fn map(&mut self, addr: usize, phys: usize, attrs: Attrs) -> Result<(), VmError> {
+        if addr & (PAGE_SIZE - 1) != 0 || phys & (PAGE_SIZE - 1) != 0 {
+            return Err(VmError::Misaligned);
+        }
+        let idx = self.index(addr)?;
+        if self.l1_entry(idx) & 0b11 == L1_SECTION {
+            return Err(VmError::AlreadyMapped);
+        }
+
+        let pte = unsafe { self.l2_table(addr, true)?.offset(AddressSpace::l2_slot(addr)) };
+        unsafe {
+            if ptr::read_volatile(pte) != L2_FAULT {
+                return Err(VmError::AlreadyMapped);
+            }
+            ptr::write_volatile(pte, phys as u32 | attrs.page_bits());
+        }
+        mmu::tlb_invalidate_page(addr);
+        Ok(())
+    }
+
+    // Maps the 1 MiB section at "addr" to the physical section at "phys"
+    pub fn map_section(&mut self, addr: usize, phys: usize, attrs: Attrs) -> Result<(), VmError> {
+        if addr & (SECTION_SIZE - 1) != 0 || phys & (SECTION_SIZE - 1) != 0 {
+            return Err(VmError::Misaligned);
+        }
+        let idx = self.index(addr)?;
+        if self.l1_entry(idx) != L1_FAULT {
+            return Err(VmError::AlreadyMapped);
+        }
+
+        self.set_l1_entry(idx, phys as u32 | attrs.section_bits());
+        mmu::tlb_invalidate_page(addr);
+        Ok(())
+    }
+
+    // Unmaps the page at "addr", returning the physical page it was mapped to
+    pub fn unmap(&mut self, addr: usize) -> Result<usize, VmError> {
+        let pte = unsafe { self.l2_table(addr, false)?.offset(AddressSpace::l2_slot(addr)) };
+        let desc = unsafe { ptr::read_volatile(pte) };
+        if desc & 0b10 == 0 {
+            return Err(VmError::NotMapped);
+        }
+
+        unsafe { ptr::write_volatile(pte, L2_FAULT); }
+        ipi::tlb_shootdown_page(addr);
+        Ok(desc as usize & !(PAGE_SIZE - 1))
+    }
+
+    // Changes the attributes of the page at "addr"
+    pub fn protect(&mut self, addr: usize, attrs: Attrs) -> Result<(), VmError> {
+        let pte = unsafe { self.l2_table(addr, false)?.offset(AddressSpace::l2_slot(addr)) };
+        let desc = unsafe { ptr::read_volatile(pte) };
+        if desc & 0b10 == 0 {
+            return Err(VmError::NotMapped);
+        }
+
+        unsafe { ptr::write_volatile(pte, (desc & !(PAGE_SIZE as u32 - 1)) | attrs.page_bits()); }
+        ipi::tlb_shootdown_page(addr);
+        Ok(())
+    }
+
+    // Looks "addr" up in this address space's tables (which need not be the active ones)
+    pub fn translate(&self, addr: usize) -> Option<(usize, Attrs)> {
+        let idx  = self.index(addr).ok()?;
+        let desc = self.l1_entry(idx);
+
+        match desc & 0b11 {
+            L1_SECTION => {
+                let base = desc as usize & !(SECTION_SIZE - 1);
+                Some((base | (addr & (SECTION_SIZE - 1)), Attrs::from_section(desc)))
+            },
+            L1_PAGE_TABLE => {
+                let pte = unsafe { ptr::read_volatile(AddressSpace::l2(desc).offset(AddressSpace::l2_slot(addr))) };
+                if pte & 0b10 == 0 {
+                    return None;
+                }
+                let base = pte as usize & !(PAGE_SIZE - 1);
+                Some((base | (addr & (PAGE_SIZE - 1)), Attrs::from_page(pte)))
+            },
+            _ => None,
+        }
+    }
+
+    // Makes this (user) address space the one translating the bottom half of the address space
+    pub fn activate(&self) {
+        debug_assert!(self.frames == USER_FRAMES, "only user address spaces can be activated");
+        mmu::set_user_table(Some(self.table.addr()));
+    }
+
+    // Prints the mappings, merging runs that are contiguous and share their attributes
+    pub fn dump(&self) {
+        // (virtual start, physical start, length, attributes) of the run being built
+        let mut run: Option<(usize, usize, usize, Attrs)> = None;
+
+        let mut emit = |next: Option<(usize, usize, usize, Attrs)>| {
+            if let (Some((va, pa, len, a)), Some((nva, npa, nlen, na))) = (run, next) {
+                if va + len == nva && pa + len == npa && a == na {
+                    run = Some((va, pa, len + nlen, a));
+                    return;
+                }
+            }
+            if let Some((va, pa, len, a)) = run {
+                kprintln!("  {:#010x} - {:#010x} -> {:#010x} {:<8} {}{}{}", va, va + (len - 1), pa,
+                          match a.mem { mmu::MemType::Normal => "normal", mmu::MemType::Uncached => "uncached",
+                                        mmu::MemType::Device => "device" },
+                          if a.writable { "rw" } else { "ro" }, if a.executable { "x" } else { "-" },
+                          if a.user { " user" } else { "" });
+            }
+            run = next;
+        };
+
+        for idx in self.first..self.end {
+            let desc = self.l1_entry(idx);
+            let base = idx << SECTION_SHIFT;
+            match desc & 0b11 {
+                L1_SECTION => emit(Some((base, desc as usize & !(SECTION_SIZE - 1), SECTION_SIZE,
+                                         Attrs::from_section(desc)))),
+                L1_PAGE_TABLE => {
+                    for i in 0..L2_ENTRIES {
+                        let pte = unsafe { ptr::read_volatile(AddressSpace::l2(desc).offset(i as isize)) };
+                        if pte & 0b10 == 0 {
+                            emit(None);
+                        } else {
+                            emit(Some((base + i * PAGE_SIZE, pte as usize & !(PAGE_SIZE - 1), PAGE_SIZE,
+                                       Attrs::from_page(pte))));
+                        }
+                    }
+                },
+                _ => emit(None),
+            }
+        }
+        emit(None);
+    }
+}
+
+impl Drop for AddressSpace {
+    // Frees the page tables (but not the memory they map)
+    fn drop(&mut self) {
+        for idx in self.first..self.end {
+            let desc = self.l1_entry(idx);
+            if desc & 0b11 == L1_PAGE_TABLE {
+                frame::free(Frame::containing(desc as usize & !0x3FF));
+            }
+        }
+        frame::free_contiguous(self.table, self.frames);
+    }
+}
+
+/*
+ * The kernel address space, created by mmu::init() and shared by all cores. Locks are taken in the
+ * order: kernel stack or uncached area bitmap, kernel address space, frame allocator.
+ */
+
+static KERNEL: Once<SpinLock<AddressSpace>> = Once::new();
+
+pub fn create_kernel_space() -> SpinLockGuard<'static, AddressSpace> {
+    KERNEL.call_once(|| {
+        let space = AddressSpace::new(KERN_FRAMES, USER_END >> SECTION_SHIFT, L1_ENTRIES)
+            .expect("vm: out of memory for the kernel page table");
+        SpinLock::new(space)
+    });
+    kernel()
+}
+
+pub fn kernel() -> SpinLockGuard<'static, AddressSpace> {
+    KERNEL.get().expect("vm: no kernel address space yet").lock()
+}
+
+/*
+ * Kernel stacks
+ */
+
+// Slots of the kernel stack area in use, one bit each
+static KSTACK_USED: SpinLock<[u32; KSTACK_SLOTS / 32]> = SpinLock::new([0; KSTACK_SLOTS / 32]);
+
+// A kernel stack in the kernel stack area, unmapped and freed when dropped
+pub struct KernelStack {
+    slot:  usize,
+    pages: usize, // Pages mapped, counting down from the top of the slot
+}
+
+impl KernelStack {
+    // Initial stack pointer (the stack grows down from here)
+    pub fn top(&self) -> usize {
+        KSTACK_BASE + (self.slot + 1) * KSTACK_SLOT
+    }
+
+    // Lowest usable address; the page below it is the guard page
+    pub fn bottom(&self) -> usize {
+        self.top() - self.pages * PAGE_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for i in 0..self.pages {
+            if let Ok(phys) = kernel().unmap(self.top() - (i + 1) * PAGE_SIZE) {
+                frame::free(Frame::containing(phys));
+            }
+        }
+        KSTACK_USED.lock()[self.slot / 32] &= !(1 << (self.slot % 32));
+    }
+}
+
+// Allocates and maps a kernel stack of "pages" pages (at most KSTACK_MAX_PAGES)
+pub fn alloc_kernel_stack(pages: usize) -> Result<KernelStack, VmError> {
+    if pages == 0 || pages > KSTACK_MAX_PAGES {
+        return Err(VmError::OutOfRange);
+    }
+
+    let slot = {
+        let mut used = KSTACK_USED.lock();
+        let free = (0..KSTACK_SLOTS).find(|&s| used[s / 32] & (1 << (s % 32)) == 0);
+        match free {
+            Some(s) => { used[s / 32] |= 1 << (s % 32); s },
+            None    => return Err(VmError::OutOfMemory),
+        }
+    };
+
+    // Dropping a partly built stack undoes whatever was mapped so far
+    let mut stack = KernelStack { slot: slot, pages: 0 };
+    while stack.pages < pages {
+        let page = frame::alloc().ok_or(VmError::OutOfMemory)?;
+        let mapped = kernel().map(stack.bottom() - PAGE_SIZE, page.addr(), mmu::KERNEL_DATA);
+        if let Err(e) = mapped {
+            frame::free(page);
+            return Err(e);
+        }
+        stack.pages += 1;
+    }
+    Ok(stack)
+}
+
+/*
+ * Uncached mappings
+ */
+
+// Pages of the uncached area in use, one bit each. Held while the pages are (un)mapped.
+static UNCACHED_USED: SpinLock<[u32; UNCACHED_PAGES / 32]> = SpinLock::new([0; UNCACHED_PAGES / 32]);
+
+fn uncached_used(used: &[u32], page: usize) -> bool {
+    used[page / 32] & (1 << (page % 32)) != 0
+}
+
+fn set_uncached_used(used: &mut [u32], page: usize, on: bool) {
+    if on {
+        used[page / 32] |= 1 << (page % 32);
+    } else {
+        used[page / 32] &= !(1 << (page % 32));
+    }
+}
+
+// Maps "pages" physically contiguous pages from "phys" uncached, returning the virtual address.
+// The cacheable linear map alias is cleaned and invalidated first, so that no dirty line from it
+// can later be written back over data written through the uncached mapping.
+pub fn map_uncached(phys: usize, pages: usize) -> Result<usize, VmError> {
+    if phys & (PAGE_SIZE - 1) != 0 {
+        return Err(VmError::Misaligned);
+    }
+    if pages == 0 || pages > UNCACHED_PAGES {
+        return Err(VmError::OutOfRange);
+    }
+
+    let mut used = UNCACHED_USED.lock();
+    let first = (0..(UNCACHED_PAGES - pages + 1))
+        .find(|&p| (p..(p + pages)).all(|q| !uncached_used(&*used, q)))
+        .ok_or(VmError::OutOfMemory)?;
+
+    cache::clean_invalidate_range(mmu::phys_to_virt(phys), pages * PAGE_SIZE);
+
+    let addr = UNCACHED_BASE + first * PAGE_SIZE;
+    let mut kernel = kernel();
+    for i in 0..pages {
+        if let Err(e) = kernel.map(addr + i * PAGE_SIZE, phys + i * PAGE_SIZE, mmu::UNCACHED) {
+            for j in 0..i {
+                let _ = kernel.unmap(addr + j * PAGE_SIZE);
+            }
+            return Err(e);
+        }
+    }
+    for p in first..(first + pages) {
+        set_uncached_used(&mut *used, p, true);
+    }
+    Ok(addr)
+}
+
+// Removes a mapping made by map_uncached()
+pub fn unmap_uncached(addr: usize, pages: usize) {
+    let mut used = UNCACHED_USED.lock();
+    let mut kernel = kernel();
+    let first = (addr - UNCACHED_BASE) / PAGE_SIZE;
+    for p in first..(first + pages) {
+        let _ = kernel.unmap(UNCACHED_BASE + p * PAGE_SIZE);
+        set_uncached_used(&mut *used, p, false);
+    }
+}
@@ -0,0 +1,129 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cpu;
+use crate::mmio::{self, Mmio};
+use crate::shell;
+
+/*
+ * BCM2835 power management watchdog. Once armed, the board is fully reset when the counter runs
+ * out unless it is petted first. The same mechanism provides software reboot and halt.
+ */
+
+// The watchdog counts down in ticks of 1/65536 of a second
+const TICKS_PER_SEC: u64 = 1 << 16;
+
+// The firmware treats a reset into boot partition 63 as a request to halt. The partition number
+// is spread over the even bits of PM_RSTS.
+const RSTS_PARTITION_HALT: u32 = 0x555;
+
+// Timeout given to arm(), in ticks (0: disarmed)
+static TIMEOUT_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Watchdog { }
+
+impl Watchdog {
+    // Largest timeout that fits in the counter (just under 16 seconds)
+    pub fn max_timeout_ms() -> u32 {
+        (mmio::PM_WDOG_TIME_SET as u64 * 1000 / TICKS_PER_SEC) as u32
+    }
+
+    // Starts the watchdog: the board resets unless pet() is called within timeout_ms. The timeout
+    // is clamped to max_timeout_ms(), and to at least one tick, so that the watchdog always counts
+    // as armed once started.
+    pub fn arm(timeout_ms: u32) {
+        let ticks = timeout_ms as u64 * TICKS_PER_SEC / 1000;
+        let ticks = if ticks > mmio::PM_WDOG_TIME_SET as u64 { mmio::PM_WDOG_TIME_SET } else { ticks.max(1) as u32 };
+
+        TIMEOUT_TICKS.store(ticks as usize, Ordering::Relaxed);
+        Watchdog::start(ticks);
+    }
+
+    // Restarts the countdown with the timeout given to arm()
+    pub fn pet() {
+        let ticks = TIMEOUT_TICKS.load(Ordering::Relaxed) as u32;
+        if ticks > 0 {
+            Watchdog::start(ticks);
+        }
+    }
+
+    // Cancels a pending reset
+    pub fn disarm() {
+        TIMEOUT_TICKS.store(0, Ordering::Relaxed);
+        let _access = Mmio::enter();
+        Mmio::write(mmio::PM_RSTC, mmio::PM_PASSWORD | mmio::PM_RSTC_RESET);
+    }
+
+    pub fn is_armed() -> bool {
+        TIMEOUT_TICKS.load(Ordering::Relaxed) > 0
+    }
+
+    // Milliseconds left before the watchdog fires
+    pub fn remaining_ms() -> u32 {
+        let _access = Mmio::enter();
+        let ticks = Mmio::read(mmio::PM_WDOG) & mmio::PM_WDOG_TIME_SET;
+        (ticks as u64 * 1000 / TICKS_PER_SEC) as u32
+    }
+
+    fn start(ticks: u32) {
+        let _access = Mmio::enter();
+        let rstc = Mmio::read(mmio::PM_RSTC);
+        Mmio::write(mmio::PM_WDOG, mmio::PM_PASSWORD | (ticks & mmio::PM_WDOG_TIME_SET));
+        Mmio::write(mmio::PM_RSTC, mmio::PM_PASSWORD | (rstc & mmio::PM_RSTC_WRCFG_CLR) | mmio::PM_RSTC_WRCFG_FULL_RESET);
+    }
+}
+
+// Resets the board
+pub fn reboot() -> ! {
+    Watchdog::start(10);
+    cpu::halt()
+}
+
+// Resets the board into the halt state: the firmware stops instead of loading the kernel again
+pub fn halt() -> ! {
+    {
+        let _access = Mmio::enter();
+        let rsts = Mmio::read(mmio::PM_RSTS);
+        Mmio::write(mmio::PM_RSTS, mmio::PM_PASSWORD | rsts | RSTS_PARTITION_HALT);
+    }
+    reboot()
+}
+
+pub fn register_commands() {
+    shell::register("reboot", "",                          "Reset the board",                  cmd_reboot);
+    shell::register("halt",   "",                          "Halt the board",                   cmd_halt);
+    shell::register("wdog",   "[arm <ms> | pet | disarm]", "Control or show the watchdog",     cmd_wdog);
+}
+
+fn cmd_reboot(_args: &[&str]) -> i32 {
+    kprintln!("Rebooting...");
+    reboot()
+}
+
+fn cmd_halt(_args: &[&str]) -> i32 {
+    kprintln!("Halting...");
+    halt()
+}
+
+fn cmd_wdog(args: &[&str]) -> i32 {
+    match args.get(1) {
+        Some(&"arm") => match args.get(2).and_then(|ms| shell::parse_number(ms)) {
+            Some(0)  => {
+                shell::error(format_args!("wdog: the timeout must be at least 1 ms"));
+                return shell::STATUS_FAILED;
+            },
+            Some(ms) => Watchdog::arm(ms as u32),
+            None     => return shell::usage(args[0]),
+        },
+        Some(&"pet")    => Watchdog::pet(),
+        Some(&"disarm") => Watchdog::disarm(),
+        Some(_)         => return shell::usage(args[0]),
+        None            => {},
+    }
+
+    if Watchdog::is_armed() {
+        kprintln!("armed, {} ms remaining (max {} ms)", Watchdog::remaining_ms(), Watchdog::max_timeout_ms());
+    } else {
+        kprintln!("disarmed (max {} ms)", Watchdog::max_timeout_ms());
+    }
+    shell::STATUS_OK
+}
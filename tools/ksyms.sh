@@ -0,0 +1,40 @@
+#!/bin/sh
+#
+# Generates the kernel symbol table used by the panic handler to symbolicate
+# return addresses.
+#
+# Usage: ksyms.sh <nm> [<elf>] > ksyms.S
+#
+# With no ELF an empty table is generated (used for the first link pass).
+# Output is an assembly file placing the table in the .ksyms section: a word
+# holding the number of entries, followed by (address, name pointer) pairs
+# sorted by address, followed by the NUL-terminated names.
+
+NM=$1
+ELF=$2
+
+echo '.section ".ksyms", "a"'
+echo '.balign 4'
+
+if [ -z "$ELF" ]; then
+  echo '.word 0'
+  exit 0
+fi
+
+# Only code symbols (t/T) are useful for symbolicating return addresses
+$NM -n -C --defined-only "$ELF" | awk '
+  $2 == "t" || $2 == "T" {
+    addr[n] = $1
+    $1 = ""; $2 = ""
+    sub(/^  */, "")
+    gsub(/\\/, "\\\\")
+    gsub(/"/, "\\\"")
+    name[n] = $0
+    n++
+  }
+  END {
+    printf ".word %d\n", n
+    for (i = 0; i < n; i++) printf ".word 0x%s, .Lksym%d\n", addr[i], i
+    for (i = 0; i < n; i++) printf ".Lksym%d: .asciz \"%s\"\n", i, name[i]
+  }
+'
@@ -0,0 +1,34 @@
+#!/bin/sh
+#
+# Packs the initial RAM filesystem linked into the kernel (see
+# src/fs/initramfs.rs).
+#
+# Usage: mkinitramfs.sh <output> <dir> [<program>...]
+#
+# The archive holds the contents of <dir> and each user program, under /bin
+# without its .elf extension. Output is a cpio archive in the "newc" format,
+# with every file owned by root and paths relative to the root (no leading
+# "./"), sorted so that the archive only changes when its contents do.
+
+set -e
+
+OUTPUT=$1
+DIR=$2
+shift 2
+
+STAGING=$(mktemp -d)
+trap 'rm -rf "$STAGING"' EXIT
+
+if [ -d "$DIR" ]; then
+  cp -R "$DIR"/. "$STAGING"
+fi
+
+if [ $# -gt 0 ]; then
+  mkdir -p "$STAGING/bin"
+  for program in "$@"; do
+    cp "$program" "$STAGING/bin/$(basename "$program" .elf)"
+  done
+fi
+
+OUTPUT_ABS=$(cd "$(dirname "$OUTPUT")" && pwd)/$(basename "$OUTPUT")
+(cd "$STAGING" && find . -mindepth 1 | LC_ALL=C sort | sed 's|^\./||' | cpio -o -H newc -R 0:0 --quiet) > "$OUTPUT_ABS"
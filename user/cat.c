@@ -0,0 +1,45 @@
+#include "lib/user.h"
+
+/*
+ * Copies each file named to standard output, then says how big it was, by
+ * seeking to its end. Exits with 0, or with the error number of the first
+ * file it could not read.
+ */
+
+static char buffer[256];
+
+static int cat(const char *path)
+{
+  int fd = open(path, O_RDONLY);
+  if (fd < 0)
+    return fd;
+
+  int len;
+  while ((len = read(fd, buffer, sizeof(buffer))) > 0)
+    write(STDOUT, buffer, len);
+
+  long size = seek(fd, 0, SEEK_END);
+  close(fd);
+  if (len < 0)
+    return len;
+
+  print("[");
+  print_number(size);
+  print(" bytes]\n");
+  return 0;
+}
+
+int main(int argc, char **argv)
+{
+  for (int i = 1; i < argc; i++) {
+    int err = cat(argv[i]);
+    if (err < 0) {
+      print(argv[i]);
+      print(": error ");
+      print_number(-err);
+      print("\n");
+      return -err;
+    }
+  }
+  return 0;
+}
@@ -0,0 +1,48 @@
+#include "lib/user.h"
+
+/*
+ * Prints its arguments, environment and pid, then fills some memory from
+ * mmap() and exits with the number of arguments
+ */
+
+static const char greeting[] = "Hello from an ELF executable\n";
+static int counter = 3;
+static char buffer[64];
+
+int main(int argc, char **argv, char **envp)
+{
+  print(greeting);
+
+  for (int i = 0; i < argc; i++) {
+    print("argv[");
+    print_number(i);
+    print("] = ");
+    print(argv[i]);
+    print("\n");
+  }
+  for (char **env = envp; *env; env++) {
+    print("env: ");
+    print(*env);
+    print("\n");
+  }
+
+  print("pid ");
+  print_number(getpid());
+  print("\n");
+
+  char *page = mmap(4096, PROT_READ | PROT_WRITE);
+  if ((long)page < 0) {
+    print("mmap failed\n");
+    return -1;
+  }
+  memset(page, 'x', 4096);
+  memcpy(buffer, page, 8);
+  buffer[8] = '\n';
+  write(STDOUT, buffer, 9);
+
+  while (counter--) {
+    print("tick\n");
+    sleep(250);
+  }
+  return argc;
+}
@@ -0,0 +1,48 @@
+#include "user.h"
+
+/* The compiler may call these for copies and initialisations */
+
+size_t strlen(const char *s)
+{
+  size_t len = 0;
+  while (s[len])
+    len++;
+  return len;
+}
+
+void *memcpy(void *dest, const void *src, size_t len)
+{
+  uint8_t *d = dest;
+  const uint8_t *s = src;
+  while (len--)
+    *d++ = *s++;
+  return dest;
+}
+
+void *memset(void *dest, int c, size_t len)
+{
+  uint8_t *d = dest;
+  while (len--)
+    *d++ = (uint8_t)c;
+  return dest;
+}
+
+void print(const char *s)
+{
+  write(STDOUT, s, strlen(s));
+}
+
+void print_number(long n)
+{
+  char buf[12];
+  int i = sizeof(buf);
+  unsigned long u = n < 0 ? -(unsigned long)n : (unsigned long)n;
+
+  do {
+    buf[--i] = '0' + u % 10;
+    u /= 10;
+  } while (u);
+  if (n < 0)
+    buf[--i] = '-';
+  write(STDOUT, buf + i, sizeof(buf) - i);
+}
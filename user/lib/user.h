@@ -0,0 +1,83 @@
+#ifndef USER_H
+#define USER_H
+
+/*
+ * The user program runtime: system calls (see src/process/syscall.rs) and a
+ * few string and output helpers (see libuser.c)
+ */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define STDIN  0
+#define STDOUT 1
+#define STDERR 2
+
+#define PROT_READ  (1 << 0)
+#define PROT_WRITE (1 << 1)
+#define PROT_EXEC  (1 << 2)
+
+#define O_RDONLY 0
+#define O_WRONLY 1
+#define O_RDWR   2
+
+#define SEEK_SET 0
+#define SEEK_CUR 1
+#define SEEK_END 2
+
+/* File types, as readdir() and fstat() give them */
+#define TYPE_OTHER     0
+#define TYPE_FILE      1
+#define TYPE_DIRECTORY 2
+#define TYPE_SYMLINK   3
+#define TYPE_DEVICE    4
+
+#define NAME_MAX 255
+
+/* Errors come back as negated error numbers */
+#define ENOENT       2
+#define EBADF        9
+#define ENOMEM       12
+#define EFAULT       14
+#define EBUSY        16
+#define ENODEV       19
+#define ENOTDIR      20
+#define EISDIR       21
+#define EINVAL       22
+#define EMFILE       24
+#define EROFS        30
+#define ENAMETOOLONG 36
+#define ENOSYS       38
+#define ELOOP        40
+
+struct dirent {
+  uint32_t type;
+  char name[NAME_MAX + 1];
+};
+
+struct stat {
+  uint32_t type;
+  uint32_t mode;
+  uint32_t size;
+};
+
+void exit(int code) __attribute__((noreturn));
+int write(int fd, const void *buf, size_t len);
+int read(int fd, void *buf, size_t len);
+int sleep(unsigned int ms);
+void *mmap(size_t len, int prot);
+int getpid(void);
+int open(const char *path, int flags);
+int close(int fd);
+long seek(int fd, long offset, int whence);
+int readdir(int fd, struct dirent *entry);
+int fstat(int fd, struct stat *st);
+
+size_t strlen(const char *s);
+void *memcpy(void *dest, const void *src, size_t len);
+void *memset(void *dest, int c, size_t len);
+
+void print(const char *s);
+void print_number(long n);
+
+#endif
@@ -0,0 +1,76 @@
+#include "lib/user.h"
+
+/*
+ * Lists each directory named (or "/"), one entry a line with its type and,
+ * from fstat(), its size. Exits with 0, or with the error number of the first
+ * directory it could not list.
+ */
+
+static const char type_chars[] = "?-dlc";
+
+static char path[NAME_MAX + 1];
+
+/* Gives the size of "name" in "dir", or -1 if it cannot be opened */
+static long size_of(const char *dir, const char *name)
+{
+  size_t dir_len = strlen(dir), name_len = strlen(name);
+  if (dir_len + 1 + name_len > NAME_MAX)
+    return -1;
+
+  memcpy(path, dir, dir_len);
+  path[dir_len] = '/';
+  memcpy(path + dir_len + 1, name, name_len + 1);
+
+  int fd = open(path, O_RDONLY);
+  if (fd < 0)
+    return -1;
+  struct stat st;
+  int err = fstat(fd, &st);
+  close(fd);
+  return err < 0 ? -1 : (long)st.size;
+}
+
+static int ls(const char *dir)
+{
+  int fd = open(dir, O_RDONLY);
+  if (fd < 0)
+    return fd;
+
+  struct dirent entry;
+  int found;
+  while ((found = readdir(fd, &entry)) > 0) {
+    char type[2] = { entry.type <= TYPE_DEVICE ? type_chars[entry.type] : '?', 0 };
+    print(type);
+    print(" ");
+    long size = entry.type == TYPE_DIRECTORY ? 0 : size_of(dir, entry.name);
+    if (size >= 0)
+      print_number(size);
+    else
+      print("-");
+    print("\t");
+    print(entry.name);
+    print("\n");
+  }
+  close(fd);
+  return found;
+}
+
+int main(int argc, char **argv)
+{
+  if (argc < 2) {
+    int err = ls("/");
+    return err < 0 ? -err : 0;
+  }
+
+  for (int i = 1; i < argc; i++) {
+    int err = ls(argv[i]);
+    if (err < 0) {
+      print(argv[i]);
+      print(": error ");
+      print_number(-err);
+      print("\n");
+      return -err;
+    }
+  }
+  return 0;
+}